@@ -14,6 +14,7 @@
 #include <sys/types.h>
 #include <unistd.h>
 #include <stdbool.h>
+#include <stdarg.h>
 
 // Macro to create the publicly exposed symbol:
 #ifdef __APPLE__
@@ -40,6 +41,10 @@ static int (*underlying_real_pthread_create)(pthread_t *thread,
                                              void *(*start_routine)(void *),
                                              void *arg) = 0;
 static pid_t (*underlying_real_fork)(void) = 0;
+#ifdef __linux__
+static void *(*underlying_real_mremap)(void *old_address, size_t old_size,
+                                       size_t new_size, int flags, ...) = 0;
+#endif
 
 // Used on Linux to implement these APIs:
 extern void *_rjem_malloc(size_t length);
@@ -121,23 +126,29 @@ struct FunctionLocation {
 };
 
 // Implemented in the Rust library:
-extern uint64_t pymemprofile_add_function_location(const char* filename, size_t filename_length, const char* function_name,
-                                                   size_t function_length);
-extern void pymemprofile_start_call(uint16_t parent_line_number,
+extern uint64_t pymemprofile_add_function_location(const char* filename, size_t filename_length, const char* qualname,
+                                                   size_t qualname_length, uint32_t first_line);
+extern void pymemprofile_start_call(uint32_t parent_line_number,
                                     uint64_t function_id,
-                                    uint16_t line_number);
+                                    uint32_t line_number);
 extern void pymemprofile_finish_call();
-extern void pymemprofile_new_line_number(uint16_t line_number);
+extern void pymemprofile_new_line_number(uint32_t line_number);
 extern void pymemprofile_reset(const char *path);
 extern void pymemprofile_start_tracking();
 extern void pymemprofile_stop_tracking();
 extern void pymemprofile_dump_peak_to_flamegraph(const char *path);
+extern int pymemprofile_dump_signal();
 extern void pymemprofile_add_allocation(size_t address, size_t length,
-                                        uint16_t line_number);
+                                        uint32_t line_number);
 extern void pymemprofile_free_allocation(size_t address);
 extern void pymemprofile_add_anon_mmap(size_t address, size_t length,
-                                       uint16_t line_number);
+                                       uint32_t line_number);
 extern void pymemprofile_free_anon_mmap(size_t address, size_t length);
+extern void pymemprofile_remap_mmap(size_t old_address, size_t old_size,
+                                    size_t new_address, size_t new_size);
+extern void pymemprofile_add_categorized_mmap(size_t address, size_t length,
+                                              uint32_t line_number,
+                                              uint8_t category);
 extern void *pymemprofile_get_current_callstack();
 extern void pymemprofile_set_current_callstack(void *callstack);
 extern void pymemprofile_clear_current_callstack();
@@ -164,6 +175,13 @@ static void __attribute__((constructor)) constructor() {
     fprintf(stderr, "Couldn't load mmap(): %s\n", dlerror());
     exit(1);
   }
+#ifdef __linux__
+  underlying_real_mremap = dlsym(RTLD_NEXT, "mremap");
+  if (!underlying_real_mremap) {
+    fprintf(stderr, "Couldn't load mremap(): %s\n", dlerror());
+    exit(1);
+  }
+#endif
   underlying_real_pthread_create = dlsym(RTLD_NEXT, "pthread_create");
   if (!underlying_real_pthread_create) {
     fprintf(stderr, "Couldn't load pthread_create(): %s\n", dlerror());
@@ -189,10 +207,92 @@ static void __attribute__((constructor)) constructor() {
   initialized = 1;
 }
 
-static void start_call(uint64_t function_id, uint16_t line_number) {
+#if PY_VERSION_HEX < 0x030B0000
+/// Find the class that defines the method being called, based on its first
+/// argument (self or cls). Returns a borrowed reference, or NULL if this
+/// doesn't look like a method.
+static PyTypeObject *find_defining_class(PyFrameObject *frame) {
+  PyCodeObject *code = frame->f_code;
+  if (code->co_argcount == 0 || frame->f_localsplus[0] == NULL) {
+    return NULL;
+  }
+  PyObject *first_name = PyTuple_GET_ITEM(code->co_varnames, 0);
+  PyObject *first = frame->f_localsplus[0];
+  PyTypeObject *type;
+  if (PyUnicode_CompareWithASCIIString(first_name, "self") == 0) {
+    type = Py_TYPE(first);
+  } else if (PyUnicode_CompareWithASCIIString(first_name, "cls") == 0 &&
+             PyType_Check(first)) {
+    type = (PyTypeObject *)first;
+  } else {
+    return NULL;
+  }
+  // The method may be inherited, so look for the class whose attribute has
+  // this exact code object:
+  PyObject *mro = type->tp_mro;
+  if (mro == NULL) {
+    return NULL;
+  }
+  for (Py_ssize_t i = 0; i < PyTuple_GET_SIZE(mro); i++) {
+    PyTypeObject *klass = (PyTypeObject *)PyTuple_GET_ITEM(mro, i);
+    if (!(klass->tp_flags & Py_TPFLAGS_HEAPTYPE) || klass->tp_dict == NULL) {
+      continue;
+    }
+    PyObject *value = PyDict_GetItem(klass->tp_dict, code->co_name);
+    if (value == NULL) {
+      continue;
+    }
+    PyObject *function = value;
+    Py_INCREF(function);
+    if (Py_TYPE(value) == &PyClassMethod_Type ||
+        Py_TYPE(value) == &PyStaticMethod_Type) {
+      Py_DECREF(function);
+      function = PyObject_GetAttrString(value, "__func__");
+      if (function == NULL) {
+        PyErr_Clear();
+        continue;
+      }
+    }
+    int matches = PyFunction_Check(function) &&
+                  PyFunction_GET_CODE(function) == (PyObject *)code;
+    Py_DECREF(function);
+    if (matches) {
+      return klass;
+    }
+  }
+  return NULL;
+}
+#endif
+
+/// Get the qualified name (e.g. "Class.method") of the function being called.
+/// Python 3.11 and later store it in the code object; on older versions we
+/// reconstruct the class path for methods, and otherwise use the plain name.
+/// Returns a new reference.
+static PyObject *get_qualname(PyFrameObject *frame) {
+#if PY_VERSION_HEX >= 0x030B0000
+  PyObject *qualname = frame->f_code->co_qualname;
+  Py_INCREF(qualname);
+  return qualname;
+#else
+  PyObject *name = frame->f_code->co_name;
+  PyTypeObject *klass = find_defining_class(frame);
+  if (klass != NULL) {
+    PyObject *qualname = PyUnicode_FromFormat(
+        "%U.%U", ((PyHeapTypeObject *)klass)->ht_qualname, name);
+    if (qualname != NULL) {
+      return qualname;
+    }
+    PyErr_Clear();
+  }
+  Py_INCREF(name);
+  return name;
+#endif
+}
+
+static void start_call(uint64_t function_id, uint32_t line_number) {
   if (should_track_memory()) {
     increment_reentrancy();
-    uint16_t parent_line_number = 0;
+    uint32_t parent_line_number = 0;
     if (current_frame != NULL && current_frame->f_back != NULL) {
       PyFrameObject *f = current_frame->f_back;
       parent_line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
@@ -229,14 +329,16 @@ fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
     _PyCode_GetExtra((PyObject *)frame->f_code, extra_code_index,
                      (void **)&function_id);
     if (function_id == 0) {
-      Py_ssize_t filename_length, function_length;
+      Py_ssize_t filename_length, qualname_length;
       const char* filename = PyUnicode_AsUTF8AndSize(frame->f_code->co_filename,
                                                      &filename_length);
-      const char* function_name = PyUnicode_AsUTF8AndSize(frame->f_code->co_name,
-                                                          &function_length);
+      PyObject *qualname_object = get_qualname(frame);
+      const char* qualname = PyUnicode_AsUTF8AndSize(qualname_object,
+                                                     &qualname_length);
       increment_reentrancy();
-      function_id = pymemprofile_add_function_location(filename, (uint64_t)filename_length, function_name, (uint64_t)function_length);
+      function_id = pymemprofile_add_function_location(filename, (uint64_t)filename_length, qualname, (uint64_t)qualname_length, (uint32_t)frame->f_code->co_firstlineno);
       decrement_reentrancy();
+      Py_DECREF(qualname_object);
       _PyCode_SetExtra((PyObject *)frame->f_code, extra_code_index,
                        (void *)function_id + 1);
     } else {
@@ -282,6 +384,12 @@ __attribute__((visibility("default"))) void fil_stop_tracking() {
   atomic_store_explicit(&tracking_allocations, 0, memory_order_release);
 }
 
+/// The signal that dumps memory usage, or 0 if there isn't one; see
+/// FIL_DUMP_SIGNAL.
+__attribute__((visibility("default"))) int fil_dump_signal() {
+  return pymemprofile_dump_signal();
+}
+
 /// Register the C level Python tracer for the current thread.
 __attribute__((visibility("default"))) void register_fil_tracer() {
   // C threads inherit their callstack from the creating Python thread. That's
@@ -304,7 +412,7 @@ fil_dump_peak_to_flamegraph(const char *path) {
 
 // *** End APIs called by Python ***
 static void add_allocation(size_t address, size_t size) {
-  uint16_t line_number = 0;
+  uint32_t line_number = 0;
   PyFrameObject *f = current_frame;
   if (f != NULL) {
     line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
@@ -313,7 +421,7 @@ static void add_allocation(size_t address, size_t size) {
 }
 
 static void add_anon_mmap(size_t address, size_t size) {
-  uint16_t line_number = 0;
+  uint32_t line_number = 0;
   PyFrameObject *f = current_frame;
   if (f != NULL) {
     line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
@@ -321,6 +429,20 @@ static void add_anon_mmap(size_t address, size_t size) {
   pymemprofile_add_anon_mmap(address, size, line_number);
 }
 
+// Must match MmapCategory in memapi/src/memorytracking.rs:
+#define MMAP_CATEGORY_FILE_BACKED 0
+#define MMAP_CATEGORY_SHARED_ANONYMOUS 1
+
+static void add_categorized_mmap(size_t address, size_t size,
+                                 uint8_t category) {
+  uint32_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
+  }
+  pymemprofile_add_categorized_mmap(address, size, line_number, category);
+}
+
 // Disable memory tracking after fork() in the child.
 __attribute__((visibility("default"))) pid_t SYMBOL_PREFIX(fork)(void) {
   static int already_printed = 0;
@@ -439,16 +561,53 @@ fil_mmap_impl(void *addr, size_t length, int prot, int flags, int fd,
 
   void *result = underlying_real_mmap(addr, length, prot, flags, fd, offset);
 
-  // For now we only track anonymous mmap()s:
-  if (result != MAP_FAILED && (flags & MAP_ANONYMOUS) &&
-      should_track_memory()) {
+  // Private anonymous mmap()s are heap-like memory, other kinds get tracked
+  // separately:
+  if (result != MAP_FAILED && should_track_memory()) {
     increment_reentrancy();
-    add_anon_mmap((size_t)result, length);
+    if (!(flags & MAP_ANONYMOUS)) {
+      add_categorized_mmap((size_t)result, length, MMAP_CATEGORY_FILE_BACKED);
+    } else if (flags & MAP_SHARED) {
+      add_categorized_mmap((size_t)result, length,
+                           MMAP_CATEGORY_SHARED_ANONYMOUS);
+    } else {
+      add_anon_mmap((size_t)result, length);
+    }
     decrement_reentrancy();
   }
   return result;
 }
 
+#ifdef __linux__
+// mremap() moves and/or resizes an existing mapping, which keeps its
+// callstack. Linux-only, macOS doesn't have it.
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(mremap)(void *old_address, size_t old_size, size_t new_size,
+                      int flags, ...) {
+  void *new_address = NULL;
+  if (flags & MREMAP_FIXED) {
+    va_list args;
+    va_start(args, flags);
+    new_address = va_arg(args, void *);
+    va_end(args);
+  }
+  if (unlikely(!initialized)) {
+    return (void *)syscall(SYS_mremap, old_address, old_size, new_size, flags,
+                           new_address);
+  }
+
+  void *result = underlying_real_mremap(old_address, old_size, new_size, flags,
+                                        new_address);
+  if (result != MAP_FAILED && should_track_memory()) {
+    increment_reentrancy();
+    pymemprofile_remap_mmap((size_t)old_address, old_size, (size_t)result,
+                            new_size);
+    decrement_reentrancy();
+  }
+  return result;
+}
+#endif
+
 #ifdef __APPLE__
 __attribute__((visibility("default"))) void *
 SYMBOL_PREFIX(mmap)(void *addr, size_t length, int prot, int flags, int fd,
@@ -557,6 +716,15 @@ void call_if_tracking(void (*f)(void *), void *user_data) {
   }
 }
 
+// Call a function with tracking disabled for the current thread, regardless of
+// whether tracking is currently enabled. For use from Rust code, e.g. for
+// background threads that need the tracker state.
+void call_untracked(void (*f)(void *), void *user_data) {
+  increment_reentrancy();
+  f(user_data);
+  decrement_reentrancy();
+}
+
 // Expose initialized to Rust()
 int is_initialized() {
   return initialized;
@@ -1,9 +1,26 @@
-use parking_lot::Mutex;
-use pymemprofile_api::memorytracking::{AllocationTracker, CallSiteId, Callstack, FunctionId};
+use parking_lot::{Mutex, MutexGuard};
+use pymemprofile_api::alerts::{AlertPolicy, AllocationAlerts};
+use pymemprofile_api::diagnostics::capture_native_backtrace;
+use pymemprofile_api::eventbuffer::{apply_events, Event, EventBuffers, ThreadBuffer};
+use pymemprofile_api::memorytracking::{
+    AllocationTracker, CallSiteId, Callstack, CallstackId, CallstackInterner, FunctionId,
+    MmapCategory,
+};
 use pymemprofile_api::oom::{InfiniteMemory, OutOfMemoryEstimator, RealMemoryInfo};
+use pymemprofile_api::prometheus;
+use pymemprofile_api::query::{self, error_response, Command};
+use pymemprofile_api::snapshots::{SnapshotPolicy, SnapshotSchedule};
+use pymemprofile_api::util::DEBUG_MODE;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::CStr;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
 use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
 
 #[macro_use]
 extern crate lazy_static;
@@ -17,14 +34,38 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 thread_local!(static THREAD_CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new()));
 
+/// How many allocation events a thread buffers before applying them to the
+/// tracker.
+const EVENT_BUFFER_CAPACITY: usize = 256;
+
+/// Allocations at least this big skip the buffer, so out-of-memory detection
+/// doesn't lag behind.
+const UNBUFFERED_ALLOCATION_SIZE: usize = 64 * 1024;
+
+/// Maximum size of the per-thread callstack ID cache.
+const MAX_CACHED_CALLSTACK_IDS: usize = 10_000;
+
+// Buffered allocation events, see memapi/src/eventbuffer.rs:
+thread_local!(static THREAD_BUFFER: Arc<ThreadBuffer> = EVENT_BUFFERS.new_thread_buffer());
+
+// Callstack IDs this thread has already looked up, keyed by parent callstack ID
+// and call, so most allocations don't need the tracker lock at all. Only valid
+// for the given generation, see compact_callstacks().
+type CallstackIdCache = (u32, HashMap<(CallstackId, CallSiteId), CallstackId>);
+thread_local!(static CALLSTACK_IDS: RefCell<CallstackIdCache> = RefCell::new((0, HashMap::new())));
+
 struct TrackerState {
     oom: OutOfMemoryEstimator,
     allocations: AllocationTracker,
+    // Automatic snapshots, if enabled:
+    snapshots: Option<SnapshotSchedule>,
+    // Large allocation alerts, if enabled:
+    alerts: Option<AllocationAlerts>,
 }
 
 lazy_static! {
     static ref TRACKER_STATE: Mutex<TrackerState> = Mutex::new(TrackerState {
-        allocations: AllocationTracker::new("/tmp".to_string()),
+        allocations: new_allocation_tracker(),
         oom: OutOfMemoryEstimator::new(
             if std::env::var("__FIL_DISABLE_OOM_DETECTION") == Ok("1".to_string()) {
                 Box::new(InfiniteMemory {})
@@ -32,24 +73,513 @@ lazy_static! {
                 Box::new(RealMemoryInfo::new())
             }
         ),
+        snapshots: SnapshotPolicy::from_env().map(SnapshotSchedule::new),
+        alerts: ALERT_POLICY.clone().map(AllocationAlerts::new),
+    });
+    static ref EVENT_BUFFERS: EventBuffers = EventBuffers::new(EVENT_BUFFER_CAPACITY);
+    static ref ALERT_POLICY: Option<AlertPolicy> = AlertPolicy::from_env();
+    // Allocations at least this big take the slow path in add_allocation();
+    // alerts need the tracker lock, so that includes large allocations:
+    static ref UNBUFFERED_SIZE: usize = match &*ALERT_POLICY {
+        Some(policy) => std::cmp::min(UNBUFFERED_ALLOCATION_SIZE, policy.threshold_bytes),
+        None => UNBUFFERED_ALLOCATION_SIZE,
+    };
+}
+
+/// Lock the tracker state, first applying any buffered allocation events so
+/// it's up to date. Anything that reads or changes the tracker should use this.
+fn lock_tracker() -> MutexGuard<'static, TrackerState> {
+    let mut tracker_state = TRACKER_STATE.lock();
+    flush_events(&mut tracker_state);
+    tracker_state
+}
+
+/// Apply all threads' buffered allocation events to the tracker, in the order
+/// they happened.
+fn flush_events(tracker_state: &mut TrackerState) {
+    let events = EVENT_BUFFERS.drain();
+    if events.is_empty() {
+        return;
+    }
+    let allocated_bytes = apply_events(&mut tracker_state.allocations, events);
+    let current_allocated_bytes = tracker_state.allocations.get_current_allocated_bytes();
+    if tracker_state
+        .oom
+        .too_big_allocation(allocated_bytes, current_allocated_bytes)
+    {
+        // Uh-oh, we're out of memory.
+        tracker_state.allocations.oom_break_glass();
+        eprintln!("=fil-profile= WARNING: Detected out-of-memory condition, exiting soon.");
+        tracker_state.oom.print_info();
+        tracker_state.allocations.oom_dump();
+    }
+    note_snapshot_growth(tracker_state);
+}
+
+/// Alert about an allocation if it's large enough, see memapi/src/alerts.rs.
+fn alert_if_large(
+    tracker_state: &mut TrackerState,
+    callstack_id: CallstackId,
+    size: usize,
+    kind: &str,
+) {
+    if let Some(alerts) = tracker_state.alerts.as_mut() {
+        if alerts.is_large(size) {
+            alerts.large_allocation(&tracker_state.allocations, callstack_id, size, kind);
+        }
+    }
+}
+
+/// Writing the snapshot is too expensive to do in the middle of an
+/// allocation, so just note it's due if memory grew enough.
+fn note_snapshot_growth(tracker_state: &TrackerState) {
+    if let Some(schedule) = &tracker_state.snapshots {
+        if schedule.grew_enough(tracker_state.allocations.get_current_allocated_bytes()) {
+            AUTO_SNAPSHOT_REQUESTED.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Record an allocation event in the current thread's buffer, applying all
+/// buffered events if it's full. `generation` is the generation of the
+/// event's callstack ID, see compact_callstacks().
+///
+/// If the callstack ID is from an older generation, the event isn't recorded
+/// and is returned instead.
+fn buffer_event(generation: u32, event: Event) -> Result<(), Event> {
+    let mut event = Some(event);
+    let pushed = THREAD_BUFFER
+        .try_with(|buffer| EVENT_BUFFERS.push(buffer, generation, event.take().unwrap()));
+    match pushed {
+        Ok(Ok(true)) => flush_events(&mut TRACKER_STATE.lock()),
+        Ok(Ok(false)) => {}
+        Ok(Err(stale)) => return Err(stale),
+        Err(_) => {
+            // The thread-local is gone because the thread is exiting, so apply
+            // the event directly. Holding the tracker lock means the
+            // generation can't change while we check it:
+            let mut tracker_state = lock_tracker();
+            let event = event.unwrap();
+            if matches!(event, Event::Allocation { .. }) && generation != EVENT_BUFFERS.generation()
+            {
+                return Err(event);
+            }
+            apply_events(&mut tracker_state.allocations, vec![event]);
+        }
+    }
+    Ok(())
+}
+
+/// Get the ID for a parent callstack extended with a call, from the thread's
+/// cache if possible. `parent` must be from the given generation; if that's
+/// no longer the current one, the result is meaningless, and the caller will
+/// find out when buffering the event.
+fn get_child_callstack_id(generation: u32, parent: CallstackId, call: CallSiteId) -> CallstackId {
+    let cached = CALLSTACK_IDS
+        .try_with(|ids| {
+            let mut ids = ids.borrow_mut();
+            if ids.0 != generation {
+                *ids = (generation, HashMap::new());
+            }
+            ids.1.get(&(parent, call)).copied()
+        })
+        .ok()
+        .flatten();
+    if let Some(callstack_id) = cached {
+        return callstack_id;
+    }
+    let mut tracker_state = TRACKER_STATE.lock();
+    // Compaction happens with the tracker lock held, so the generation can't
+    // change while we hold it. A stale parent ID mustn't be added to the
+    // interner, since it may not exist (yet):
+    if generation != EVENT_BUFFERS.generation() {
+        return CallstackInterner::ROOT;
+    }
+    let callstack_id = tracker_state
+        .allocations
+        .get_child_callstack_id(parent, call);
+    drop(tracker_state);
+    let _ = CALLSTACK_IDS.try_with(|ids| {
+        let mut ids = ids.borrow_mut();
+        if ids.1.len() >= MAX_CACHED_CALLSTACK_IDS {
+            ids.1.clear();
+        }
+        ids.1.insert((parent, call), callstack_id);
     });
+    callstack_id
+}
+
+/// Drop callstacks that aren't using any memory, and return how many bytes
+/// were freed.
+///
+/// Compaction renumbers callstack IDs, so any IDs threads have cached or
+/// buffered become invalid. Buffered events are therefore applied first, and
+/// a new generation is started at the same time, so threads recalculate
+/// callstack IDs instead of using ones from the previous generation.
+fn compact_callstacks(tracker_state: &mut TrackerState) -> usize {
+    compact_tracker(
+        &mut tracker_state.allocations,
+        tracker_state.alerts.as_mut(),
+    )
+}
+
+/// Like compact_callstacks(), given the parts of the tracker state it needs.
+fn compact_tracker(
+    allocations: &mut AllocationTracker,
+    alerts: Option<&mut AllocationAlerts>,
+) -> usize {
+    if let Some(alerts) = alerts {
+        alerts.reset();
+    }
+    EVENT_BUFFERS.advance_generation(|events| {
+        apply_events(allocations, events);
+        allocations.compact()
+    })
+}
+
+/// Create the AllocationTracker. The FIL_MMAP_IN_PEAK environment variable can
+/// be set to a comma-separated list of mmap() categories ("file-backed",
+/// "shared") that count towards peak memory; by default only "shared" does.
+fn new_allocation_tracker() -> AllocationTracker {
+    let mut allocations = AllocationTracker::new("/tmp".to_string());
+    if let Ok(value) = std::env::var("FIL_MMAP_IN_PEAK") {
+        match MmapCategory::parse_list(&value) {
+            Ok(in_peak) => {
+                for category in MmapCategory::ALL.iter() {
+                    allocations.set_mmap_in_peak(*category, in_peak.contains(category));
+                }
+            }
+            Err(message) => eprintln!(
+                "=fil-profile= WARNING: Bad FIL_MMAP_IN_PEAK={}: {}, using the default.",
+                value, message
+            ),
+        }
+    }
+    allocations
+}
+
+/// Set by the dump signal handler, and checked at the next safe point.
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set when an automatic snapshot is due, and checked at the next safe point.
+static AUTO_SNAPSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The signal the dump handler is installed for, or 0 if there isn't one.
+static DUMP_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Signal handler. Setting an atomic flag is all we can safely do here; the
+/// actual dump happens in dump_if_requested().
+extern "C" fn request_dump(_signum: c_int) {
+    DUMP_REQUESTED.store(true, Ordering::Release);
+}
+
+/// If the FIL_DUMP_SIGNAL environment variable is set (e.g. to "SIGUSR2"),
+/// install a handler for that signal that requests a dump of peak and current
+/// memory usage.
+fn install_dump_signal_handler() {
+    let name = match std::env::var("FIL_DUMP_SIGNAL") {
+        Ok(name) => name,
+        Err(_) => return,
+    };
+    let signum = match name.trim_start_matches("SIG") {
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        other => match other.parse::<c_int>() {
+            Ok(signum) if is_user_signal(signum) => signum,
+            _ => {
+                eprintln!(
+                    "=fil-profile= WARNING: Unsupported signal FIL_DUMP_SIGNAL={}, only SIGUSR1, SIGUSR2 and real-time signals can be used; not installing dump handler.",
+                    name
+                );
+                return;
+            }
+        },
+    };
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = request_dump as extern "C" fn(c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signum, &action, std::ptr::null_mut()) != 0 {
+            eprintln!(
+                "=fil-profile= WARNING: Failed to install handler for FIL_DUMP_SIGNAL={}.",
+                name
+            );
+            return;
+        }
+    }
+    DUMP_SIGNAL.store(signum, Ordering::Release);
+}
+
+/// Whether a signal is meant for applications to use. Installing the dump
+/// handler, which just sets a flag and returns, for anything else would break
+/// it: e.g. SIGSEGV would fault again forever, and SIGTERM would no longer
+/// terminate the process.
+fn is_user_signal(signum: c_int) -> bool {
+    #[cfg(target_os = "linux")]
+    if (libc::SIGRTMIN()..=libc::SIGRTMAX()).contains(&signum) {
+        return true;
+    }
+    signum == libc::SIGUSR1 || signum == libc::SIGUSR2
+}
+
+/// If automatic snapshots are enabled with an interval, start a thread that
+/// requests them periodically. The thread never touches the tracker state, the
+/// dumping happens in dump_if_requested().
+fn start_snapshot_timer() {
+    let interval = match TRACKER_STATE
+        .lock()
+        .snapshots
+        .as_ref()
+        .and_then(|schedule| schedule.policy().interval)
+    {
+        Some(interval) => interval,
+        None => return,
+    };
+    let result = std::thread::Builder::new()
+        .name("fil-snapshot-timer".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            AUTO_SNAPSHOT_REQUESTED.store(true, Ordering::Release);
+        });
+    if let Err(e) = result {
+        eprintln!(
+            "=fil-profile= WARNING: Couldn't start snapshot timer thread: {}",
+            e
+        );
+    }
+}
+
+/// If the FIL_QUERY_SOCKET environment variable is set to a path, start a
+/// thread that answers queries about memory usage on a Unix socket at that
+/// path; see memapi/src/query.rs for the protocol.
+fn start_query_server() {
+    let path = match std::env::var("FIL_QUERY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    // Remove stale socket from a previous run, if any, but never a file that
+    // just happens to be at that path:
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!(
+                "=fil-profile= WARNING: Couldn't listen on FIL_QUERY_SOCKET={}: {}",
+                path, e
+            );
+            return;
+        }
+    };
+    spawn_untracked("fil-query-server", move || {
+        for mut stream in listener.incoming().flatten() {
+            // Each client gets its own thread, so one that's connected but
+            // idle doesn't block everyone else:
+            let client = match QueryClient::connect() {
+                Some(client) => client,
+                None => {
+                    let _ = stream.write_all(error_response("Too many clients").as_bytes());
+                    continue;
+                }
+            };
+            let mut connection = Some((stream, client));
+            spawn_untracked("fil-query-client", move || {
+                if let Some((stream, _client)) = connection.take() {
+                    // Errors just mean the client went away:
+                    let _ = answer_queries(stream);
+                }
+            });
+        }
+    });
+}
+
+/// Number of connected query clients, see QueryClient.
+static QUERY_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A connected query client, counted until it's dropped.
+struct QueryClient;
+
+impl QueryClient {
+    /// Count a new client, unless query::MAX_CLIENTS are already connected.
+    fn connect() -> Option<QueryClient> {
+        QUERY_CLIENTS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |clients| {
+                (clients < query::MAX_CLIENTS).then(|| clients + 1)
+            })
+            .ok()
+            .map(|_| QueryClient)
+    }
+}
+
+impl Drop for QueryClient {
+    fn drop(&mut self) {
+        QUERY_CLIENTS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Expose metrics in the Prometheus text format, depending on environment
+/// variables:
+///
+/// * FIL_PROMETHEUS_ADDRESS: serve them over HTTP at e.g. 127.0.0.1:9100.
+/// * FIL_PROMETHEUS_TEXTFILE: rewrite them to this file periodically, for
+///   node_exporter's textfile collector.
+/// * FIL_PROMETHEUS_INTERVAL_SECONDS: how often to rewrite the file, 15 by
+///   default.
+/// * FIL_PROMETHEUS_TOP_FUNCTIONS: how many per-function gauges to include, 10
+///   by default.
+fn start_prometheus_exporter() {
+    let get_number = |name, default| match std::env::var(name) {
+        Ok(value) => match value.parse::<f64>() {
+            Ok(number) if number > 0.0 => number,
+            _ => {
+                eprintln!(
+                    "=fil-profile= WARNING: Bad {}={}, using default of {}.",
+                    name, value, default
+                );
+                default
+            }
+        },
+        Err(_) => default,
+    };
+    let top_functions = get_number("FIL_PROMETHEUS_TOP_FUNCTIONS", 10.0) as usize;
+    let render = move || prometheus::render(&mut lock_tracker().allocations, top_functions);
+
+    if let Ok(address) = std::env::var("FIL_PROMETHEUS_ADDRESS") {
+        match TcpListener::bind(&address) {
+            Ok(listener) => spawn_untracked("fil-prometheus-server", move || {
+                for stream in listener.incoming().flatten() {
+                    let timeout = Some(prometheus::SCRAPE_TIMEOUT);
+                    if stream.set_read_timeout(timeout).is_err()
+                        || stream.set_write_timeout(timeout).is_err()
+                    {
+                        continue;
+                    }
+                    // Errors just mean the client went away or timed out:
+                    let _ = prometheus::answer_scrape(stream, render);
+                }
+            }),
+            Err(e) => eprintln!(
+                "=fil-profile= WARNING: Couldn't listen on FIL_PROMETHEUS_ADDRESS={}: {}",
+                address, e
+            ),
+        }
+    }
+
+    if let Ok(path) = std::env::var("FIL_PROMETHEUS_TEXTFILE") {
+        let interval =
+            std::time::Duration::from_secs_f64(get_number("FIL_PROMETHEUS_INTERVAL_SECONDS", 15.0));
+        spawn_untracked("fil-prometheus-textfile", move || loop {
+            if let Err(e) = prometheus::write_textfile(path.as_ref(), &render()) {
+                eprintln!(
+                    "=fil-profile= WARNING: Couldn't write FIL_PROMETHEUS_TEXTFILE={}: {}",
+                    path, e
+                );
+            }
+            std::thread::sleep(interval);
+        });
+    }
+}
+
+/// Start a background thread whose allocations aren't tracked. These aren't
+/// the user's allocations, and tracking them while we hold the tracker lock
+/// would deadlock.
+fn spawn_untracked<F: FnMut() + Send + 'static>(name: &str, mut f: F) {
+    let result = std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || untracked(&mut f));
+    if let Err(e) = result {
+        eprintln!(
+            "=fil-profile= WARNING: Couldn't start {} thread: {}",
+            name, e
+        );
+    }
+}
+
+/// Answer queries from a single client until it disconnects. Runs on its own
+/// thread, see start_query_server().
+fn answer_queries(stream: UnixStream) -> std::io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let response = match Command::parse(&line?) {
+            Ok(command) => run_query(&command),
+            Err(message) => error_response(&message),
+        };
+        writer.write_all(response.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Run a query against the tracker state.
+fn run_query(command: &Command) -> String {
+    let run = || {
+        let mut tracker_state = lock_tracker();
+        let tracker_state = &mut *tracker_state;
+        let alerts = &mut tracker_state.alerts;
+        // Compacting needs to start a new generation of callstack IDs:
+        command.run(&mut tracker_state.allocations, |allocations| {
+            compact_tracker(allocations, alerts.as_mut())
+        })
+    };
+    if command.needs_python() {
+        if unsafe { pyo3::ffi::Py_IsInitialized() } == 0 {
+            return error_response("Python isn't running yet");
+        }
+        // Acquire the GIL before the tracker lock, the same order as Python
+        // threads do, to prevent deadlocks:
+        pyo3::Python::with_gil(|_| run())
+    } else {
+        run()
+    }
+}
+
+/// Write out peak and current memory usage if a dump was requested, either via
+/// signal or because an automatic snapshot is due.
+///
+/// Must only be called from safe points, i.e. the Python tracer callbacks:
+/// the GIL is held (needed to get source code lines) and we're not in the
+/// middle of an allocation.
+fn dump_if_requested() {
+    if DUMP_REQUESTED.load(Ordering::Acquire) && DUMP_REQUESTED.swap(false, Ordering::AcqRel) {
+        let mut tracker_state = lock_tracker();
+        let directory = tracker_state.allocations.dump_snapshot();
+        eprintln!(
+            "=fil-profile= Wrote memory usage snapshot to {}",
+            directory.display()
+        );
+    }
+    if AUTO_SNAPSHOT_REQUESTED.load(Ordering::Acquire)
+        && AUTO_SNAPSHOT_REQUESTED.swap(false, Ordering::AcqRel)
+    {
+        let mut tracker_state = lock_tracker();
+        let directory = tracker_state.allocations.dump_snapshot();
+        let current_allocated_bytes = tracker_state.allocations.get_current_allocated_bytes();
+        if let Some(schedule) = tracker_state.snapshots.as_mut() {
+            schedule.snapshot_written(directory, current_allocated_bytes);
+        }
+    }
 }
 
 /// Register a new function/filename location.
-fn add_function(filename: String, function_name: String) -> FunctionId {
+fn add_function(filename: String, qualname: String, first_line: u32) -> FunctionId {
     let mut tracker_state = TRACKER_STATE.lock();
     tracker_state
         .allocations
         .functions
-        .add_function(filename, function_name)
+        .add_function_definition(filename, qualname, first_line)
 }
 
 /// Add to per-thread function stack:
-fn start_call(call_site: FunctionId, parent_line_number: u16, line_number: u16) {
+fn start_call(call_site: FunctionId, parent_line_number: u32, line_number: u32) {
     THREAD_CALLSTACK.with(|cs| {
         cs.borrow_mut()
             .start_call(parent_line_number, CallSiteId::new(call_site, line_number));
     });
+    dump_if_requested();
 }
 
 /// Finish off (and move to reporting structure) current function in function
@@ -58,6 +588,7 @@ fn finish_call() {
     THREAD_CALLSTACK.with(|cs| {
         cs.borrow_mut().finish_call();
     });
+    dump_if_requested();
 }
 
 /// Get the current thread's callstack.
@@ -85,10 +616,75 @@ extern "C" {
 fn add_allocation(
     address: usize,
     size: usize,
-    line_number: u16,
+    line_number: u32,
     is_mmap: bool,
 ) -> Result<(), std::thread::AccessError> {
-    let mut tracker_state = TRACKER_STATE.lock();
+    // Small allocations are buffered; large ones, mmap()s and failures need
+    // the out-of-memory check, so they take the slow path.
+    if !is_mmap && address != 0 && size < *UNBUFFERED_SIZE {
+        loop {
+            // If callstacks get compacted in the meantime, the event is
+            // rejected and we need to calculate the callstack ID again:
+            let generation = EVENT_BUFFERS.generation();
+            let callstack_id = THREAD_CALLSTACK.try_with(|tcs| {
+                let mut callstack = tcs.borrow_mut();
+                callstack.set_generation(generation);
+                callstack.id_for_new_allocation(line_number, |parent, call| {
+                    get_child_callstack_id(generation, parent, call)
+                })
+            })?;
+            let event = Event::Allocation {
+                address,
+                size,
+                callstack_id,
+            };
+            if buffer_event(generation, event).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+
+    let mut tracker_state = lock_tracker();
+    let oom = check_for_oom(&mut tracker_state, address, size, is_mmap);
+
+    let allocations = &mut tracker_state.allocations;
+    // Will fail during thread shutdown, but not much we can do at that point.
+    let callstack_id = THREAD_CALLSTACK.try_with(|tcs| {
+        let mut callstack = tcs.borrow_mut();
+        // We hold the tracker lock, so the generation can't change:
+        callstack.set_generation(EVENT_BUFFERS.generation());
+        callstack.id_for_new_allocation(line_number, |parent, call| {
+            allocations.get_child_callstack_id(parent, call)
+        })
+    })?;
+
+    if is_mmap {
+        allocations.add_anon_mmap(address, size, callstack_id);
+    } else {
+        allocations.add_allocation(address, size, callstack_id);
+    }
+
+    if oom {
+        // Uh-oh, we're out of memory.
+        allocations.oom_dump();
+    };
+
+    let kind = if is_mmap { "mmap()" } else { "malloc()" };
+    alert_if_large(&mut tracker_state, callstack_id, size, kind);
+    note_snapshot_growth(&tracker_state);
+    Ok(())
+}
+
+/// Check whether a new allocation or mmap() means we're out of memory. If so,
+/// the memory is released, since it's never going to be used, and the tracker
+/// is cleaned up in preparation for oom_dump(), which the caller should call
+/// once the allocation is added.
+fn check_for_oom(
+    tracker_state: &mut TrackerState,
+    address: usize,
+    size: usize,
+    is_mmap: bool,
+) -> bool {
     let current_allocated_bytes = tracker_state.allocations.get_current_allocated_bytes();
 
     // Check if we're out of memory:
@@ -121,40 +717,58 @@ fn add_allocation(
         eprintln!("=fil-profile= WARNING: Detected out-of-memory condition, exiting soon.");
         tracker_state.oom.print_info();
     }
+    oom
+}
 
+/// Add a new file-backed or shared mmap() based off the current callstack.
+///
+/// Only categories that count towards peak memory get the out-of-memory
+/// check: e.g. file-backed pages can be evicted.
+fn add_categorized_mmap(
+    address: usize,
+    size: usize,
+    line_number: u32,
+    category: MmapCategory,
+) -> Result<(), std::thread::AccessError> {
+    let mut tracker_state = lock_tracker();
+    let oom = tracker_state.allocations.is_mmap_in_peak(category)
+        && check_for_oom(&mut tracker_state, address, size, true);
     let allocations = &mut tracker_state.allocations;
-    // Will fail during thread shutdown, but not much we can do at that point.
     let callstack_id = THREAD_CALLSTACK.try_with(|tcs| {
         let mut callstack = tcs.borrow_mut();
-        callstack.id_for_new_allocation(line_number, |callstack| {
-            allocations.get_callstack_id(callstack)
+        // We hold the tracker lock, so the generation can't change:
+        callstack.set_generation(EVENT_BUFFERS.generation());
+        callstack.id_for_new_allocation(line_number, |parent, call| {
+            allocations.get_child_callstack_id(parent, call)
         })
     })?;
-
-    if is_mmap {
-        allocations.add_anon_mmap(address, size, callstack_id);
-    } else {
-        allocations.add_allocation(address, size, callstack_id);
-    }
-
+    allocations.add_categorized_mmap(category, address, size, callstack_id);
     if oom {
-        // Uh-oh, we're out of memory.
         allocations.oom_dump();
-    };
+    }
+    alert_if_large(&mut tracker_state, callstack_id, size, "mmap()");
     Ok(())
 }
 
 /// Free an existing allocation.
 fn free_allocation(address: usize) {
-    let mut tracker_state = TRACKER_STATE.lock();
-
-    let allocations = &mut tracker_state.allocations;
-    allocations.free_allocation(address);
+    if *DEBUG_MODE {
+        // Diagnosing untracked frees needs the backtrace, which can only be
+        // captured now, but is too slow to capture for every free. So skip
+        // the buffer and check whether the allocation is tracked right away:
+        let mut tracker_state = lock_tracker();
+        tracker_state
+            .allocations
+            .free_allocation_with_backtrace(address, || Some(capture_native_backtrace()));
+        return;
+    }
+    // Frees don't have a callstack ID, so they're never rejected:
+    let _ = buffer_event(EVENT_BUFFERS.generation(), Event::Free { address });
 }
 
 /// Get the size of an allocation, or 0 if it's not tracked.
 fn get_allocation_size(address: usize) -> usize {
-    let tracker_state = TRACKER_STATE.lock();
+    let tracker_state = lock_tracker();
     let allocations = &tracker_state.allocations;
     allocations.get_allocation_size(address)
 }
@@ -163,19 +777,52 @@ fn get_allocation_size(address: usize) -> usize {
 fn reset(default_path: String) {
     // Make sure we initialize this static, to prevent deadlocks:
     pymemprofile_api::ffi::initialize();
-    let mut tracker_state = TRACKER_STATE.lock();
+    // The first reset happens before Python starts, so there's nothing to ask
+    // it yet. Otherwise, ask before taking the tracker lock, again to prevent
+    // deadlocks:
+    let python = if pymemprofile_api::python::is_initialized() {
+        pymemprofile_api::packages::initialize();
+        Some((
+            pymemprofile_api::python::frame_filter(),
+            pymemprofile_api::python::source_provider(),
+        ))
+    } else {
+        None
+    };
+    static START_DUMP_TRIGGERS: Once = Once::new();
+    START_DUMP_TRIGGERS.call_once(|| {
+        install_dump_signal_handler();
+        start_snapshot_timer();
+        start_query_server();
+        start_prometheus_exporter();
+    });
+    let mut tracker_state = lock_tracker();
     tracker_state.allocations.reset(default_path);
+    if let Some((frame_filter, source_provider)) = python {
+        tracker_state
+            .allocations
+            .set_frame_filter(Box::new(frame_filter));
+        tracker_state
+            .allocations
+            .set_source_provider(Box::new(source_provider));
+    }
+    // Nothing uses memory anymore, so this drops all previous callstacks:
+    compact_callstacks(&mut tracker_state);
+    if let Some(schedule) = tracker_state.snapshots.as_mut() {
+        schedule.reset();
+    }
+    AUTO_SNAPSHOT_REQUESTED.store(false, Ordering::Release);
 }
 
 /// Dump all callstacks in peak memory usage to format used by flamegraph.
 fn dump_peak_to_flamegraph(path: &str) {
-    let mut tracker_state = TRACKER_STATE.lock();
+    let mut tracker_state = lock_tracker();
     let allocations = &mut tracker_state.allocations;
     allocations.dump_peak_to_flamegraph(path);
 }
 
 #[no_mangle]
-extern "C" fn pymemprofile_add_allocation(address: usize, size: usize, line_number: u16) {
+extern "C" fn pymemprofile_add_allocation(address: usize, size: usize, line_number: u32) {
     add_allocation(address, size, line_number, false).unwrap_or(());
 }
 
@@ -184,6 +831,13 @@ extern "C" fn pymemprofile_free_allocation(address: usize) {
     free_allocation(address);
 }
 
+/// Returns the signal that requests a dump, or 0 if FIL_DUMP_SIGNAL wasn't
+/// set or couldn't be used.
+#[no_mangle]
+extern "C" fn pymemprofile_dump_signal() -> c_int {
+    DUMP_SIGNAL.load(Ordering::Acquire)
+}
+
 /// Returns allocation size, or 0 if not stored. Useful for tests, mostly.
 #[no_mangle]
 extern "C" fn pymemprofile_get_allocation_size(address: usize) -> usize {
@@ -191,26 +845,52 @@ extern "C" fn pymemprofile_get_allocation_size(address: usize) -> usize {
 }
 
 #[no_mangle]
-extern "C" fn pymemprofile_add_anon_mmap(address: usize, size: usize, line_number: u16) {
+extern "C" fn pymemprofile_add_anon_mmap(address: usize, size: usize, line_number: u32) {
     add_allocation(address, size, line_number, true).unwrap_or(());
 }
 
+#[no_mangle]
+extern "C" fn pymemprofile_remap_mmap(
+    old_address: usize,
+    old_size: usize,
+    new_address: usize,
+    new_size: usize,
+) {
+    let mut tracker_state = lock_tracker();
+    tracker_state
+        .allocations
+        .remap_mmap(old_address, old_size, new_address, new_size);
+}
+
+#[no_mangle]
+extern "C" fn pymemprofile_add_categorized_mmap(
+    address: usize,
+    size: usize,
+    line_number: u32,
+    category: u8,
+) {
+    if let Some(category) = MmapCategory::from_u8(category) {
+        add_categorized_mmap(address, size, line_number, category).unwrap_or(());
+    }
+}
+
 #[no_mangle]
 unsafe extern "C" fn pymemprofile_add_function_location(
     filename: *const c_char,
     filename_length: u64,
-    function_name: *const c_char,
-    function_length: u64,
+    qualname: *const c_char,
+    qualname_length: u64,
+    first_line: u32,
 ) -> u64 {
     let filename = std::str::from_utf8_unchecked(std::slice::from_raw_parts(
         filename as *const u8,
         filename_length as usize,
     ));
-    let function_name = std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-        function_name as *const u8,
-        function_length as usize,
+    let qualname = std::str::from_utf8_unchecked(std::slice::from_raw_parts(
+        qualname as *const u8,
+        qualname_length as usize,
     ));
-    let function_id = add_function(filename.to_string(), function_name.to_string());
+    let function_id = add_function(filename.to_string(), qualname.to_string(), first_line);
     function_id.as_u32() as u64
 }
 
@@ -218,9 +898,9 @@ unsafe extern "C" fn pymemprofile_add_function_location(
 /// Intended for use from C APIs, what can I say.
 #[no_mangle]
 unsafe extern "C" fn pymemprofile_start_call(
-    parent_line_number: u16,
+    parent_line_number: u32,
     function_id: u64,
-    line_number: u16,
+    line_number: u32,
 ) {
     let function_id = FunctionId::new(function_id as u32);
     start_call(function_id, parent_line_number, line_number);
@@ -296,10 +976,18 @@ extern "C" {
     // Call function conditonally in non-reentrant way.
     fn call_if_tracking(f: CCallback, user_data: *mut c_void) -> c_void;
 
+    // Call function with tracking disabled for the current thread.
+    fn call_untracked(f: CCallback, user_data: *mut c_void) -> c_void;
+
     // Return whether C code has initialized.
     fn is_initialized() -> c_int;
 }
 
+/// Run a closure without tracking any allocations it does.
+fn untracked<F: FnMut()>(mut f: F) {
+    unsafe { call_untracked(trampoline::<F>, &mut f as *mut _ as *mut c_void) };
+}
+
 struct FilMmapAPI;
 
 impl pymemprofile_api::mmap::MmapAPI for FilMmapAPI {
@@ -308,7 +996,7 @@ impl pymemprofile_api::mmap::MmapAPI for FilMmapAPI {
     }
 
     fn remove_mmap(&self, address: usize, length: usize) {
-        let mut tracker_state = TRACKER_STATE.lock();
+        let mut tracker_state = lock_tracker();
 
         let allocations = &mut tracker_state.allocations;
         allocations.free_anon_mmap(address, length);
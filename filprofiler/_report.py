@@ -21,6 +21,50 @@ Python: {sys.version}
 )
 
 
+MMAP_CATEGORIES = [
+    ("file-backed", "File-backed <tt>mmap()</tt>"),
+    ("shared", "Shared anonymous <tt>mmap()</tt>"),
+]
+
+
+def render_mmap_reports(output_path: str) -> str:
+    """HTML for the per-category mmap() graphs that were written, if any."""
+    result = []
+    for name, description in MMAP_CATEGORIES:
+        svg = f"peak-mmap-{name}.svg"
+        if not os.path.exists(os.path.join(output_path, svg)):
+            continue
+        result.append(
+            f"""
+<h2>{description} at peak</h2>
+<div><iframe id="mmap-{name}" src="{svg}" width="100%" height="200" scrolling="auto" frameborder="0"></iframe><br>
+<p><input type="button" onclick="fullScreen('#mmap-{name}');" value="Full screen"></p></div>
+"""
+        )
+    return "".join(result)
+
+
+def render_package_report(output_path: str) -> str:
+    """HTML for the per-package graph, if it was written."""
+    if not os.path.exists(os.path.join(output_path, "peak-packages.svg")):
+        return ""
+    return """
+<h2>Memory by package at peak</h2>
+<p>See also <a href="peak-packages.txt">the table of memory usage by package</a>.</p>
+<div><iframe id="packages" src="peak-packages.svg" width="100%" height="200" scrolling="auto" frameborder="0"></iframe><br>
+<p><input type="button" onclick="fullScreen('#packages');" value="Full screen"></p></div>
+"""
+
+
+def render_viewer_link(output_path: str) -> str:
+    """HTML linking to the interactive viewer, if it was written."""
+    if not os.path.exists(os.path.join(output_path, "viewer.html")):
+        return ""
+    return """
+<p>To explore every callstack, including peak and current memory usage and allocation counts, <a href="viewer.html">open the interactive call tree viewer</a>.</p>
+"""
+
+
 def render_report(output_path: str, now: datetime) -> str:
     """Write out the HTML index and improve the SVGs."""
     index_path = os.path.join(output_path, "index.html")
@@ -73,6 +117,7 @@ def render_report(output_path: str, now: datetime) -> str:
 <p><code>{argv}</code><p>
 
 <h2>Profiling result</h2>
+{viewer_link}
 <div><iframe id="peak" src="peak-memory.svg" width="100%" height="200" scrolling="auto" frameborder="0"></iframe><br>
 <p><input type="button" onclick="fullScreen('#peak');" value="Full screen"></p></div>
 
@@ -80,6 +125,8 @@ def render_report(output_path: str, now: datetime) -> str:
 
 <div><iframe id="peak-reversed" src="peak-memory-reversed.svg" width="100%" height="200" scrolling="auto" frameborder="0"></iframe><br>
 <p><input type="button" onclick="fullScreen('#peak-reversed');" value="Full screen"></p></div>
+{mmap_reports}
+{package_report}
 
 <h2>Need help, or does something look wrong? <a href="https://github.com/pythonspeed/filprofiler/issues/new?body={bugreport}">Please file an issue</a> and I'll try to help</h2>
 
@@ -102,6 +149,9 @@ In the second reversed graph all calls to <tt>f()</tt> will be merged together.<
                 now=now.ctime(),
                 argv=" ".join(map(shlex.quote, sys.argv)),
                 bugreport=DEBUGGING_INFO,
+                mmap_reports=render_mmap_reports(output_path),
+                package_report=render_package_report(output_path),
+                viewer_link=render_viewer_link(output_path),
             )
         )
     return index_path
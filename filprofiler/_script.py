@@ -244,18 +244,28 @@ def stage_2():
 
     # Only import here since we don't want the parent process accessing any of
     # the _filpread.so code.
-    from ._tracer import trace_until_exit, create_report
-
-    signal.signal(
-        signal.SIGUSR2,
-        lambda *args: create_report(arguments.output_path),
-    )
-    print(
-        "=fil-profile= Memory usage will be written out at exit, and opened automatically in a browser.\n"
-        "=fil-profile= You can also run the following command while the program is still running to write out peak memory usage up to that point: "
-        "kill -s SIGUSR2 {}".format(getpid()),
-        file=sys.stderr,
-    )
+    from ._tracer import trace_until_exit, create_report, dump_signal
+
+    if dump_signal():
+        # The signal is handled by filpreload itself, see
+        # filpreload/src/lib.rs.
+        print(
+            "=fil-profile= Memory usage will be written out at exit, and opened automatically in a browser.\n"
+            "=fil-profile= You can also run the following command while the program is still running to write out peak and current memory usage up to that point, into a new subdirectory of the report directory: "
+            "kill -s {} {}".format(environ["FIL_DUMP_SIGNAL"], getpid()),
+            file=sys.stderr,
+        )
+    else:
+        signal.signal(
+            signal.SIGUSR2,
+            lambda *args: create_report(arguments.output_path),
+        )
+        print(
+            "=fil-profile= Memory usage will be written out at exit, and opened automatically in a browser.\n"
+            "=fil-profile= You can also run the following command while the program is still running to write out peak memory usage up to that point: "
+            "kill -s SIGUSR2 {}".format(getpid()),
+            file=sys.stderr,
+        )
     if not exists(arguments.output_path):
         makedirs(arguments.output_path)
 
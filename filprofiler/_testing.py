@@ -1,6 +1,7 @@
 """Utility functions for testing."""
 
 import os
+import re
 from glob import glob
 from pathlib import Path
 
@@ -12,6 +13,12 @@ def get_allocations(
         "peak-memory-reversed.svg",
         "index.html",
         "peak-memory.prof",
+        "fil-overhead.txt",
+        "size-histograms.json",
+        "peak-packages.txt",
+        "peak-packages.prof",
+        "peak-packages.svg",
+        "viewer.html",
     ],
     prof_file="peak-memory.prof",
 ):
@@ -31,11 +38,11 @@ def get_allocations(
                 result[calls] = size_kb
                 continue
             for call in calls.split(";"):
-                part1, func_name = call.rsplit(" ", 1)
-                assert func_name[0] == "("
-                assert func_name[-1] == ")"
-                func_name = func_name[1:-1]
-                file_name, line = part1.split(":")
+                # e.g. "foo.py:12 (Example.method, defined at line 10)":
+                file_name, line, func_name = re.match(
+                    r"^(.*):(\d+) \((.*)\)$", call
+                ).groups()
+                func_name = func_name.split(", defined at line ")[0]
                 line = int(line)
                 path.append((file_name, func_name, line))
             if size_kb > 900:
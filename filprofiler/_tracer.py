@@ -96,6 +96,14 @@ def stop_tracing(output_path: str) -> str:
     return result
 
 
+def dump_signal() -> int:
+    """
+    The signal that makes filpreload dump memory usage, or 0 if FIL_DUMP_SIGNAL
+    isn't set or couldn't be used.
+    """
+    return preload.fil_dump_signal()
+
+
 def create_report(output_path: Union[str, Path]) -> str:
     preload.fil_dump_peak_to_flamegraph(str(output_path).encode("utf-8"))
     now = datetime.now()
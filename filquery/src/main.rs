@@ -0,0 +1,127 @@
+// Command-line client for the query socket Fil listens on when the
+// FIL_QUERY_SOCKET environment variable is set.
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+const USAGE: &str = "\
+Usage: fil-query SOCKET COMMAND [ARGUMENT]
+
+Commands:
+  current        Currently allocated bytes.
+  peak           Peak allocated bytes.
+  top N          The N callstacks currently using the most memory.
+  top-peak N     The N callstacks using the most memory at peak.
+  dump PATH      Write out peak and current memory usage to directory PATH.
+  reset-peak     Forget the previous peak; current usage becomes the peak.
+  compact        Forget callstacks that use no memory; prints bytes freed.";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let command = to_request(&args[1..]);
+    match query(&args[0], &command, &mut std::io::stdout()) {
+        Ok(Ok(())) => {}
+        Ok(Err(message)) => {
+            eprintln!("fil-query: {}", message);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("fil-query: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Turn command-line arguments into a request line. The profiled process
+/// likely has a different working directory, so dump paths are made absolute.
+fn to_request(args: &[String]) -> String {
+    if args[0] == "dump" && args.len() > 1 {
+        let path = args[1..].join(" ");
+        let path = std::env::current_dir()
+            .map(|cwd| cwd.join(&path))
+            .unwrap_or_else(|_| Path::new(&path).to_path_buf());
+        return format!("dump {}", path.display());
+    }
+    args.join(" ")
+}
+
+/// Send the request, and write out the response's data. The outer error is
+/// for I/O problems, the inner one for errors reported by the server.
+fn query<W: Write>(
+    socket: &str,
+    request: &str,
+    output: &mut W,
+) -> std::io::Result<Result<(), String>> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(format!("{}\n", request).as_bytes())?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "OK" {
+            return Ok(Ok(()));
+        }
+        if let Some(message) = line.strip_prefix("ERROR ") {
+            return Ok(Err(message.to_string()));
+        }
+        writeln!(output, "{}", line)?;
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "connection closed in the middle of the response",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{query, to_request};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn request_from_arguments() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(to_request(&args(&["top", "5"])), "top 5");
+        assert_eq!(to_request(&args(&["dump", "/tmp/x"])), "dump /tmp/x");
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            to_request(&args(&["dump", "out"])),
+            format!("dump {}", cwd.join("out").display())
+        );
+    }
+
+    #[test]
+    fn responses() {
+        let socket = std::env::temp_dir().join(format!("fil-query-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for response in &["1 a:1 (f)\n2 b:2 (g)\nOK\n", "ERROR bad command\n"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut line = String::new();
+                BufReader::new(stream.try_clone().unwrap())
+                    .read_line(&mut line)
+                    .unwrap();
+                requests.push(line);
+                (&stream).write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let socket = socket.to_str().unwrap();
+        let mut output = vec![];
+        assert_eq!(query(socket, "top 2", &mut output).unwrap(), Ok(()));
+        assert_eq!(output, b"1 a:1 (f)\n2 b:2 (g)\n");
+        let mut output = vec![];
+        assert_eq!(
+            query(socket, "bottom", &mut output).unwrap(),
+            Err("bad command".to_string())
+        );
+        assert!(output.is_empty());
+        assert_eq!(server.join().unwrap(), vec!["top 2\n", "bottom\n"]);
+        std::fs::remove_file(socket).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+// Profile a Rust program: `cargo run --example profile_rust -- <output
+// directory> [scopes]`.
+use pymemprofile_api::allocator::{self, Callstacks, ProfilerConfig, ProfilingAllocator};
+use std::alloc::System;
+
+#[global_allocator]
+static ALLOCATOR: ProfilingAllocator<System> = ProfilingAllocator::new(System);
+
+fn make_vec(size: usize) -> Vec<u8> {
+    vec![1; size]
+}
+
+fn make_strings(count: usize) -> Vec<String> {
+    let _scope = allocator::scope("make_strings");
+    (0..count).map(|i| format!("string number {}", i)).collect()
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| "fil-result".to_string());
+    let callstacks = match args.next().as_deref() {
+        Some("scopes") => Callstacks::Scopes,
+        _ => Callstacks::Backtraces,
+    };
+    allocator::start_tracking(
+        &path,
+        ProfilerConfig {
+            callstacks,
+            detect_out_of_memory: true,
+        },
+    );
+    let _scope = allocator::scope("main");
+    let big = make_vec(50 * 1024 * 1024);
+    let strings = make_strings(100_000);
+    let threads: Vec<_> = (0..4)
+        .map(|_| std::thread::spawn(|| make_vec(10 * 1024 * 1024)))
+        .collect();
+    let small: Vec<Vec<u8>> = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect();
+    println!(
+        "Allocated {} bytes",
+        big.len() + strings.len() + small.iter().map(|v| v.len()).sum::<usize>()
+    );
+    allocator::dump_peak_to_flamegraph(&path);
+}
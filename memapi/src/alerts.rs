@@ -0,0 +1,269 @@
+// Alerts about individual allocations above a size threshold, so users find
+// out about them right away, with a traceback, rather than only after the run.
+use crate::memorytracking::{AllocationTracker, CallstackId};
+use crate::util::new_hashmap;
+use ahash::RandomState as ARandomState;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Which allocations to alert about, and how.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlertPolicy {
+    /// Alert about allocations of at least this many bytes.
+    pub threshold_bytes: usize,
+    /// Append alerts to this file, instead of writing them to stderr.
+    pub log_path: Option<PathBuf>,
+    /// Alert at most once per interval for any given callstack.
+    pub interval: Duration,
+}
+
+impl AlertPolicy {
+    /// Load the policy from environment variables:
+    ///
+    /// * FIL_LARGE_ALLOCATION_MIB: alert about single allocations of at least
+    ///   this many MiB.
+    /// * FIL_LARGE_ALLOCATION_LOG: append alerts to this file rather than
+    ///   writing them to stderr.
+    /// * FIL_LARGE_ALLOCATION_INTERVAL_SECONDS: alert at most once per this
+    ///   many seconds for each callstack, 60 by default.
+    ///
+    /// Returns None if alerts aren't enabled.
+    pub fn from_env() -> Option<Self> {
+        let get = |name| std::env::var(name).ok();
+        match Self::parse(
+            get("FIL_LARGE_ALLOCATION_MIB").as_deref(),
+            get("FIL_LARGE_ALLOCATION_LOG").as_deref(),
+            get("FIL_LARGE_ALLOCATION_INTERVAL_SECONDS").as_deref(),
+        ) {
+            Ok(policy) => policy,
+            Err(message) => {
+                eprintln!(
+                    "=fil-profile= WARNING: {}, large allocation alerts are disabled.",
+                    message
+                );
+                None
+            }
+        }
+    }
+
+    fn parse(
+        mib: Option<&str>,
+        log_path: Option<&str>,
+        interval_seconds: Option<&str>,
+    ) -> Result<Option<Self>, String> {
+        let threshold_bytes = match mib {
+            Some(mib) => match mib.parse::<f64>() {
+                Ok(mib) if mib > 0.0 => (mib * MIB) as usize,
+                _ => return Err(format!("Bad FIL_LARGE_ALLOCATION_MIB={}", mib)),
+            },
+            None => return Ok(None),
+        };
+        let interval = match interval_seconds {
+            Some(seconds) => match seconds.parse::<f64>() {
+                Ok(seconds) if seconds >= 0.0 => Duration::from_secs_f64(seconds),
+                _ => {
+                    return Err(format!(
+                        "Bad FIL_LARGE_ALLOCATION_INTERVAL_SECONDS={}",
+                        seconds
+                    ))
+                }
+            },
+            None => Duration::from_secs(60),
+        };
+        Ok(Some(AlertPolicy {
+            threshold_bytes,
+            log_path: log_path.map(PathBuf::from),
+            interval,
+        }))
+    }
+}
+
+/// Decides which large allocations to alert about, and writes the alerts.
+pub struct AllocationAlerts {
+    policy: AlertPolicy,
+    // For each callstack that alerted, when it last did and how many alerts
+    // were suppressed since then:
+    last_alerts: HashMap<CallstackId, (Instant, usize), ARandomState>,
+}
+
+impl AllocationAlerts {
+    pub fn new(policy: AlertPolicy) -> Self {
+        AllocationAlerts {
+            policy,
+            last_alerts: new_hashmap(),
+        }
+    }
+
+    pub fn policy(&self) -> &AlertPolicy {
+        &self.policy
+    }
+
+    /// Whether an allocation is large enough to alert about.
+    #[inline]
+    pub fn is_large(&self, size: usize) -> bool {
+        size >= self.policy.threshold_bytes
+    }
+
+    /// Whether a large allocation from the given callstack should be reported
+    /// now. If so, also returns how many previous ones were suppressed.
+    fn should_report(&mut self, callstack_id: CallstackId, now: Instant) -> Option<usize> {
+        match self.last_alerts.get_mut(&callstack_id) {
+            Some((last, suppressed)) if now.duration_since(*last) < self.policy.interval => {
+                *suppressed += 1;
+                None
+            }
+            Some((last, suppressed)) => {
+                *last = now;
+                Some(std::mem::take(suppressed))
+            }
+            None => {
+                self.last_alerts.insert(callstack_id, (now, 0));
+                Some(0)
+            }
+        }
+    }
+
+    /// Alert about a large allocation, unless there was a recent alert for the
+    /// same callstack. `kind` is e.g. "malloc()" or "mmap()".
+    pub fn large_allocation(
+        &mut self,
+        tracker: &AllocationTracker,
+        callstack_id: CallstackId,
+        size: usize,
+        kind: &str,
+    ) {
+        let suppressed = match self.should_report(callstack_id, Instant::now()) {
+            Some(suppressed) => suppressed,
+            None => return,
+        };
+        let mut message = format!(
+            "=fil-profile= Large allocation of {} bytes ({:.1} MiB) via {}",
+            size,
+            size as f64 / MIB,
+            kind
+        );
+        if suppressed > 0 {
+            message.push_str(&format!(
+                " ({} more from this callstack weren't reported)",
+                suppressed
+            ));
+        }
+        message.push_str(&format!(
+            ":\n=| {}\n",
+            tracker.format_traceback(callstack_id, "\n=| ")
+        ));
+        match &self.policy.log_path {
+            Some(path) => {
+                let result = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| {
+                        let timestamp = crate::util::timestamp_now();
+                        file.write_all(format!("{} {}", timestamp, message).as_bytes())
+                    });
+                if let Err(e) = result {
+                    eprintln!(
+                        "=fil-profile= WARNING: Couldn't write to FIL_LARGE_ALLOCATION_LOG={}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+            None => eprint!("{}", message),
+        }
+    }
+
+    /// Forget which callstacks alerted recently, e.g. because callstack IDs
+    /// were renumbered.
+    pub fn reset(&mut self) {
+        self.last_alerts.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AlertPolicy, AllocationAlerts};
+    use crate::memorytracking::{AllocationTracker, CallSiteId, Callstack};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn policy_parsing() {
+        assert_eq!(
+            AlertPolicy::parse(None, Some("/tmp/x"), Some("5")),
+            Ok(None)
+        );
+        assert_eq!(
+            AlertPolicy::parse(Some("1024"), None, None),
+            Ok(Some(AlertPolicy {
+                threshold_bytes: 1024 * 1024 * 1024,
+                log_path: None,
+                interval: Duration::from_secs(60),
+            }))
+        );
+        assert_eq!(
+            AlertPolicy::parse(Some("0.5"), Some("/tmp/alerts.log"), Some("0")),
+            Ok(Some(AlertPolicy {
+                threshold_bytes: 512 * 1024,
+                log_path: Some(PathBuf::from("/tmp/alerts.log")),
+                interval: Duration::from_secs(0),
+            }))
+        );
+        assert!(AlertPolicy::parse(Some("0"), None, None).is_err());
+        assert!(AlertPolicy::parse(Some("lots"), None, None).is_err());
+        assert!(AlertPolicy::parse(Some("1"), None, Some("-1")).is_err());
+    }
+
+    #[test]
+    fn rate_limited_per_callstack() {
+        let mut alerts = AllocationAlerts::new(AlertPolicy {
+            threshold_bytes: 1000,
+            log_path: None,
+            interval: Duration::from_secs(60),
+        });
+        assert!(!alerts.is_large(999));
+        assert!(alerts.is_large(1000));
+        let start = Instant::now();
+        let later = |seconds| start + Duration::from_secs(seconds);
+        assert_eq!(alerts.should_report(1, start), Some(0));
+        assert_eq!(alerts.should_report(1, later(10)), None);
+        // Other callstacks aren't affected:
+        assert_eq!(alerts.should_report(2, later(10)), Some(0));
+        assert_eq!(alerts.should_report(1, later(59)), None);
+        // Once the interval has passed, suppressed alerts are counted:
+        assert_eq!(alerts.should_report(1, later(60)), Some(2));
+        assert_eq!(alerts.should_report(1, later(121)), Some(0));
+        alerts.reset();
+        assert_eq!(alerts.should_report(1, later(122)), Some(0));
+    }
+
+    #[test]
+    fn log_file() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid = tracker
+            .functions
+            .add_function("a.py".to_string(), "af".to_string());
+        let callstack_id =
+            tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid, 7)]));
+        let temp_dir = crate::util::TempDir::new("fil-alerts-test");
+        let log_path = temp_dir.path().join("alerts.log");
+        let mut alerts = AllocationAlerts::new(AlertPolicy {
+            threshold_bytes: 1000,
+            log_path: Some(log_path.clone()),
+            interval: Duration::from_secs(60),
+        });
+        alerts.large_allocation(&tracker, callstack_id, 2 * 1024 * 1024, "malloc()");
+        // Rate-limited:
+        alerts.large_allocation(&tracker, callstack_id, 3 * 1024 * 1024, "mmap()");
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.ends_with(
+            " =fil-profile= Large allocation of 2097152 bytes (2.0 MiB) via malloc():\n=| a.py:7 (af)\n"
+        ));
+    }
+}
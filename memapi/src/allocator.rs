@@ -0,0 +1,561 @@
+// Profiling Rust programs. ProfilingAllocator wraps a GlobalAlloc and records
+// allocations in an AllocationTracker, so Rust code gets the same reports as
+// Python code profiled with Fil:
+//
+//     use pymemprofile_api::allocator::{self, ProfilerConfig, ProfilingAllocator};
+//     use std::alloc::System;
+//
+//     #[global_allocator]
+//     static ALLOCATOR: ProfilingAllocator<System> = ProfilingAllocator::new(System);
+//
+//     fn main() {
+//         allocator::start_tracking("fil-result", ProfilerConfig::default());
+//         // ... do work ...
+//         allocator::dump_peak_to_flamegraph("fil-result/final");
+//     }
+//
+// Callstacks come either from Rust backtraces, which needs debug info and is
+// slow, or from scopes the program marks with scope(), which is much cheaper.
+//
+// The tracker allocates memory itself, so a thread-local flag makes sure that
+// allocations made while tracking an allocation aren't tracked in turn.
+use crate::memorytracking::{
+    AllocationTracker, CallSiteId, CallstackId, CallstackInterner, FunctionId,
+};
+use crate::oom::{OutOfMemoryEstimator, RealMemoryInfo};
+use crate::util::new_hashmap;
+use ahash::RandomState as ARandomState;
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+// Only look at the innermost frames of backtraces:
+const MAX_FRAMES: usize = 128;
+
+// Frames of the allocator and the profiler, which are dropped from the
+// innermost end of backtraces:
+const OWN_FRAMES: &[&str] = &[
+    "pymemprofile_api::allocator::ProfilingAllocator",
+    "pymemprofile_api::allocator::track_",
+    "pymemprofile_api::allocator::capture_backtrace",
+    "__rust_alloc",
+    "__rust_realloc",
+    "__rg_alloc",
+    "__rg_realloc",
+    "__rdl_alloc",
+    "__rdl_realloc",
+];
+
+// The standard library calls main() and thread closures via this, so it and
+// the frames outside it are dropped, the same way panic backtraces do:
+const START_FRAME: &str = "__rust_begin_short_backtrace";
+
+/// What part of a backtrace a frame is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FrameKind {
+    Own,
+    Start,
+    Program,
+}
+
+/// Where callstacks come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Callstacks {
+    /// Rust backtraces, resolved using debug info.
+    Backtraces,
+    /// The scopes entered with scope() on the allocating thread.
+    Scopes,
+}
+
+/// How to profile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfilerConfig {
+    pub callstacks: Callstacks,
+    /// Like Fil does for Python programs, when about to run out of memory
+    /// dump current memory usage to the default path and exit.
+    pub detect_out_of_memory: bool,
+}
+
+impl Default for ProfilerConfig {
+    fn default() -> Self {
+        ProfilerConfig {
+            callstacks: Callstacks::Backtraces,
+            detect_out_of_memory: false,
+        }
+    }
+}
+
+/// A GlobalAlloc that tracks the allocations made with the wrapped allocator,
+/// once start_tracking() is called.
+pub struct ProfilingAllocator<A> {
+    inner: A,
+}
+
+impl<A> ProfilingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        ProfilingAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ProfilingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = self.inner.alloc(layout);
+        track_allocation(pointer, layout.size());
+        pointer
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let pointer = self.inner.alloc_zeroed(layout);
+        track_allocation(pointer, layout.size());
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        // Before the memory can be reused by another thread's allocation:
+        track_free(pointer);
+        self.inner.dealloc(pointer, layout);
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        track_free(pointer);
+        let new_pointer = self.inner.realloc(pointer, layout, new_size);
+        if new_pointer.is_null() {
+            // The old allocation is still there:
+            track_allocation(pointer, layout.size());
+        } else {
+            track_allocation(new_pointer, new_size);
+        }
+        new_pointer
+    }
+}
+
+/// The profiler's state while tracking.
+struct ProfilerState {
+    tracker: AllocationTracker,
+    oom: Option<OutOfMemoryEstimator>,
+    // For backtraces: the frames of each instruction pointer, innermost
+    // first.
+    resolved: HashMap<usize, Vec<(CallSiteId, FrameKind)>, ARandomState>,
+    // For backtraces: the callstack ids of backtraces seen before.
+    backtraces: HashMap<Vec<usize>, CallstackId, ARandomState>,
+    // For scopes: the function id of each scope name and file.
+    scope_functions: HashMap<(&'static str, &'static str), FunctionId, ARandomState>,
+}
+
+impl ProfilerState {
+    fn new(default_path: String, oom: Option<OutOfMemoryEstimator>) -> Self {
+        ProfilerState {
+            tracker: AllocationTracker::new(default_path),
+            oom,
+            resolved: new_hashmap(),
+            backtraces: new_hashmap(),
+            scope_functions: new_hashmap(),
+        }
+    }
+
+    /// Add an allocation; `backtrace` is None if using scopes.
+    fn add_allocation(&mut self, address: usize, size: usize, backtrace: Option<Vec<usize>>) {
+        let current_allocated_bytes = self.tracker.get_current_allocated_bytes();
+        let oom = match self.oom.as_mut() {
+            Some(oom) => address == 0 || oom.too_big_allocation(size, current_allocated_bytes),
+            None => false,
+        };
+        if address == 0 && !oom {
+            return;
+        }
+        if oom {
+            if address == 0 {
+                eprintln!("=fil-profile= WARNING: Allocation of size {} failed", size);
+            }
+            self.tracker.oom_break_glass();
+            eprintln!("=fil-profile= WARNING: Detected out-of-memory condition, exiting soon.");
+            if let Some(oom) = &self.oom {
+                oom.print_info();
+            }
+        }
+        let callstack_id = match backtrace {
+            Some(backtrace) => self.backtrace_callstack_id(backtrace),
+            None => self.scopes_callstack_id(),
+        };
+        self.tracker.add_allocation(address, size, callstack_id);
+        if oom {
+            self.tracker.oom_dump();
+        }
+    }
+
+    /// Get the callstack id for a backtrace, innermost frame first.
+    fn backtrace_callstack_id(&mut self, backtrace: Vec<usize>) -> CallstackId {
+        if let Some(callstack_id) = self.backtraces.get(&backtrace) {
+            return *callstack_id;
+        }
+        let mut frames = vec![];
+        for ip in backtrace.iter() {
+            let tracker = &mut self.tracker;
+            let resolved = self
+                .resolved
+                .entry(*ip)
+                .or_insert_with(|| resolve(*ip, tracker));
+            frames.extend_from_slice(resolved);
+        }
+        let first_caller = frames
+            .iter()
+            .rposition(|(_, kind)| *kind == FrameKind::Own)
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        let start = frames
+            .iter()
+            .position(|(_, kind)| *kind == FrameKind::Start)
+            .filter(|start| *start >= first_caller)
+            .unwrap_or(frames.len());
+        let tracker = &mut self.tracker;
+        let callstack_id = frames[first_caller..start]
+            .iter()
+            .rev()
+            .fold(CallstackInterner::ROOT, |parent, (call, _)| {
+                tracker.get_child_callstack_id(parent, *call)
+            });
+        self.backtraces.insert(backtrace, callstack_id);
+        callstack_id
+    }
+
+    /// Get the callstack id for this thread's scopes.
+    fn scopes_callstack_id(&mut self) -> CallstackId {
+        let tracker = &mut self.tracker;
+        let scope_functions = &mut self.scope_functions;
+        SCOPES
+            .try_with(|scopes| match scopes.try_borrow() {
+                Ok(scopes) => {
+                    scopes
+                        .iter()
+                        .fold(CallstackInterner::ROOT, |parent, (name, location)| {
+                            let function = *scope_functions
+                                .entry((name, location.file()))
+                                .or_insert_with(|| {
+                                    tracker
+                                        .functions
+                                        .add_function(location.file().to_string(), name.to_string())
+                                });
+                            tracker.get_child_callstack_id(
+                                parent,
+                                CallSiteId::new(function, location.line()),
+                            )
+                        })
+                }
+                Err(_) => CallstackInterner::ROOT,
+            })
+            .unwrap_or(CallstackInterner::ROOT)
+    }
+}
+
+/// Resolve an instruction pointer to frames, innermost first; there can be
+/// more than one due to inlining.
+fn resolve(ip: usize, tracker: &mut AllocationTracker) -> Vec<(CallSiteId, FrameKind)> {
+    let mut frames = vec![];
+    backtrace::resolve(ip as *mut std::ffi::c_void, |symbol| {
+        // The alternate format leaves out the hash:
+        let name = match symbol.name() {
+            Some(name) => format!("{:#}", name),
+            None => format!("{:#x}", ip),
+        };
+        let kind = if OWN_FRAMES.iter().any(|own| name.contains(own)) {
+            FrameKind::Own
+        } else if name.contains(START_FRAME) {
+            FrameKind::Start
+        } else {
+            FrameKind::Program
+        };
+        let filename = match symbol.filename() {
+            Some(filename) => filename.to_string_lossy().into_owned(),
+            None => "<unknown>".to_string(),
+        };
+        let function = tracker.functions.add_function(filename, name);
+        frames.push((
+            CallSiteId::new(function, symbol.lineno().unwrap_or(0)),
+            kind,
+        ));
+    });
+    if frames.is_empty() {
+        let function = tracker
+            .functions
+            .add_function("<unknown>".to_string(), format!("{:#x}", ip));
+        frames.push((CallSiteId::new(function, 0), FrameKind::Program));
+    }
+    frames
+}
+
+lazy_static! {
+    static ref STATE: Mutex<Option<ProfilerState>> = Mutex::new(None);
+}
+
+// Whether start_tracking() was called, and whether to capture backtraces.
+// These are checked before doing anything else, so they're separate from the
+// state:
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static USE_BACKTRACES: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Whether allocations on this thread are currently not tracked, because
+    // the profiler is running:
+    static IN_PROFILER: Cell<bool> = const { Cell::new(false) };
+    // The scopes entered with scope(), outermost first:
+    static SCOPES: RefCell<Vec<(&'static str, &'static Location<'static>)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// While this exists, allocations on this thread aren't tracked.
+struct Untracked;
+
+impl Untracked {
+    /// Returns None if allocations already aren't tracked, i.e. the profiler
+    /// is running further up the stack.
+    fn enter() -> Option<Untracked> {
+        let entered = IN_PROFILER
+            .try_with(|in_profiler| !in_profiler.replace(true))
+            .unwrap_or(false);
+        if entered {
+            Some(Untracked)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Untracked {
+    fn drop(&mut self) {
+        let _ = IN_PROFILER.try_with(|in_profiler| in_profiler.set(false));
+    }
+}
+
+fn lock_state() -> MutexGuard<'static, Option<ProfilerState>> {
+    // A panic while tracking shouldn't break every later allocation:
+    STATE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Capture the instruction pointers of the current backtrace.
+#[inline(never)]
+fn capture_backtrace() -> Vec<usize> {
+    let mut ips = Vec::with_capacity(MAX_FRAMES);
+    backtrace::trace(|frame| {
+        ips.push(frame.ip() as usize);
+        ips.len() < MAX_FRAMES
+    });
+    ips
+}
+
+#[inline(never)]
+fn track_allocation(pointer: *mut u8, size: usize) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let _untracked = match Untracked::enter() {
+        Some(untracked) => untracked,
+        None => return,
+    };
+    // Capturing is slow, so do it before taking the lock:
+    let backtrace = if USE_BACKTRACES.load(Ordering::Relaxed) {
+        Some(capture_backtrace())
+    } else {
+        None
+    };
+    if let Some(state) = lock_state().as_mut() {
+        state.add_allocation(pointer as usize, size, backtrace);
+    }
+}
+
+fn track_free(pointer: *mut u8) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let _untracked = match Untracked::enter() {
+        Some(untracked) => untracked,
+        None => return,
+    };
+    if let Some(state) = lock_state().as_mut() {
+        state.tracker.free_allocation(pointer as usize);
+    }
+}
+
+/// Start tracking allocations made with ProfilingAllocator, forgetting any
+/// tracked previously. `default_path` is where out-of-memory reports go.
+pub fn start_tracking(default_path: &str, config: ProfilerConfig) {
+    let _untracked = Untracked::enter();
+    // Load this now, rather than while holding the lock:
+    crate::packages::initialize();
+    let oom = if config.detect_out_of_memory {
+        Some(OutOfMemoryEstimator::new(Box::new(RealMemoryInfo::new())))
+    } else {
+        None
+    };
+    let mut state = lock_state();
+    *state = Some(ProfilerState::new(default_path.to_string(), oom));
+    USE_BACKTRACES.store(
+        config.callstacks == Callstacks::Backtraces,
+        Ordering::SeqCst,
+    );
+    TRACKING.store(true, Ordering::SeqCst);
+}
+
+/// Stop tracking allocations, and forget the ones tracked so far.
+pub fn stop_tracking() {
+    TRACKING.store(false, Ordering::SeqCst);
+    let _untracked = Untracked::enter();
+    *lock_state() = None;
+}
+
+/// Run a function with the tracker, e.g. to call top_callstacks(). Returns
+/// None if tracking isn't running. Allocations made by the function aren't
+/// tracked.
+pub fn with_tracker<R>(f: impl FnOnce(&mut AllocationTracker) -> R) -> Option<R> {
+    let _untracked = Untracked::enter();
+    lock_state().as_mut().map(|state| f(&mut state.tracker))
+}
+
+/// Dump peak memory usage to the given directory, with the same reports as
+/// for Python programs.
+pub fn dump_peak_to_flamegraph(path: &str) {
+    with_tracker(|tracker| tracker.dump_peak_to_flamegraph(path));
+}
+
+/// A scope allocations on this thread are attributed to, until it's dropped;
+/// see scope().
+#[must_use = "the scope ends when this is dropped"]
+pub struct Scope {
+    // Scopes are per-thread, so this mustn't be sent elsewhere:
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Enter a scope. When using Callstacks::Scopes, allocations on this thread
+/// are attributed to the scopes entered and not yet dropped, which are shown
+/// as "<file>:<line> (<name>)" with the location scope() was called from.
+#[track_caller]
+pub fn scope(name: &'static str) -> Scope {
+    let location = Location::caller();
+    // Growing the stack allocates, and tracking that would need to look at the
+    // stack while it's being changed:
+    let _untracked = Untracked::enter();
+    let _ = SCOPES.try_with(|scopes| scopes.borrow_mut().push((name, location)));
+    Scope {
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let _untracked = Untracked::enter();
+        let _ = SCOPES.try_with(|scopes| scopes.borrow_mut().pop());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dump_peak_to_flamegraph, scope, start_tracking, stop_tracking, with_tracker, Callstacks,
+        ProfilerConfig, ProfilingAllocator,
+    };
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    static ALLOCATOR: ProfilingAllocator<System> = ProfilingAllocator::new(System);
+
+    #[inline(never)]
+    fn allocate_here(size: usize) -> *mut u8 {
+        unsafe { ALLOCATOR.alloc(Layout::from_size_align(size, 8).unwrap()) }
+    }
+
+    fn free(pointer: *mut u8, size: usize) {
+        unsafe { ALLOCATOR.dealloc(pointer, Layout::from_size_align(size, 8).unwrap()) }
+    }
+
+    fn top_callstacks() -> Vec<(String, usize)> {
+        with_tracker(|tracker| {
+            tracker
+                .top_callstacks(true, 10)
+                .into_iter()
+                .map(|top| (top.callstack, top.bytes))
+                .collect()
+        })
+        .unwrap()
+    }
+
+    // The state is global, so everything is tested in one go:
+    #[test]
+    fn tracking() {
+        // Not tracking yet:
+        let untracked = allocate_here(100);
+        assert_eq!(with_tracker(|_| ()), None);
+
+        start_tracking(
+            ".",
+            ProfilerConfig {
+                callstacks: Callstacks::Scopes,
+                detect_out_of_memory: false,
+            },
+        );
+        free(untracked, 100);
+        let (outer_scope, outer_line) = (scope("outer"), line!());
+        let (inner, inner_line) = {
+            let (_inner_scope, inner_line) = (scope("inner"), line!());
+            (allocate_here(1000), inner_line)
+        };
+        let outer = allocate_here(500);
+        let grown =
+            unsafe { ALLOCATOR.realloc(outer, Layout::from_size_align(500, 8).unwrap(), 700) };
+        drop(outer_scope);
+        let no_scope = allocate_here(30);
+        assert_eq!(
+            top_callstacks(),
+            vec![
+                (
+                    format!(
+                        "{file}:{outer} (outer);{file}:{inner} (inner)",
+                        file = file!(),
+                        outer = outer_line,
+                        inner = inner_line
+                    ),
+                    1000
+                ),
+                (format!("{}:{} (outer)", file!(), outer_line), 700),
+                ("[No Python stack]".to_string(), 30),
+            ]
+        );
+        free(inner, 1000);
+        free(grown, 700);
+        free(no_scope, 30);
+        assert_eq!(
+            with_tracker(|tracker| tracker.get_current_allocated_bytes()),
+            Some(0)
+        );
+
+        // Backtraces:
+        start_tracking(".", ProfilerConfig::default());
+        let pointer = allocate_here(2000);
+        let callstacks = top_callstacks();
+        assert_eq!(callstacks.len(), 1);
+        let (callstack, bytes) = &callstacks[0];
+        assert_eq!(*bytes, 2000);
+        // The innermost frame is the allocating function:
+        let innermost = callstack.rsplit(';').next().unwrap();
+        assert!(
+            innermost.ends_with("(pymemprofile_api::allocator::tests::allocate_here)"),
+            "{}",
+            callstack
+        );
+        assert!(callstack.contains("(pymemprofile_api::allocator::tests::tracking)"));
+        // Same outputs as for Python:
+        let temp_dir = crate::util::TempDir::new("fil-allocator-test");
+        let directory = temp_dir.path();
+        dump_peak_to_flamegraph(directory.to_str().unwrap());
+        let prof = std::fs::read_to_string(directory.join("peak-memory.prof")).unwrap();
+        assert!(prof.ends_with("(pymemprofile_api::allocator::tests::allocate_here) 2000\n"));
+        assert!(directory.join("peak-memory.svg").exists());
+        assert!(directory.join("viewer.html").exists());
+        free(pointer, 2000);
+
+        stop_tracking();
+        assert_eq!(with_tracker(|_| ()), None);
+    }
+}
@@ -0,0 +1,317 @@
+// Problems with the tracker's internal bookkeeping. These indicate bugs in
+// Fil, but they're usually small, so rather than crashing the user's program
+// they get written out as a diagnostics file next to the profile, which can be
+// attached to bug reports.
+//
+// Also included are allocations that disappeared without being freed, and
+// frees of allocations Fil never saw. Some of these are expected, e.g. for
+// memory allocated before tracking started, so this is to help tell whether
+// they matter.
+use crate::util::{json_string, new_hashmap};
+use ahash::RandomState as ARandomState;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// Identifies a native (C/Rust) backtrace captured with
+/// capture_native_backtrace().
+pub type BacktraceHash = u64;
+
+// Only look at the innermost frames, to keep capturing cheap-ish:
+const MAX_FRAMES: usize = 32;
+
+// Don't keep more than this many distinct backtraces around:
+const MAX_BACKTRACES: usize = 10_000;
+
+lazy_static! {
+    // Instruction pointers of each captured backtrace, so they can be
+    // resolved to function names when the report is written:
+    static ref NATIVE_BACKTRACES: Mutex<HashMap<BacktraceHash, Vec<usize>, ARandomState>> =
+        Mutex::new(new_hashmap());
+}
+
+/// Capture the current native backtrace, returning its hash. This is too
+/// slow to do on every free(), so it's only done in debug mode, for frees of
+/// allocations Fil doesn't know about.
+pub fn capture_native_backtrace() -> BacktraceHash {
+    let mut ips = Vec::with_capacity(MAX_FRAMES);
+    backtrace::trace(|frame| {
+        ips.push(frame.ip() as usize);
+        ips.len() < MAX_FRAMES
+    });
+    let mut hasher = DefaultHasher::new();
+    ips.hash(&mut hasher);
+    let hash = hasher.finish();
+    let mut backtraces = NATIVE_BACKTRACES.lock().unwrap();
+    if backtraces.len() < MAX_BACKTRACES {
+        backtraces.entry(hash).or_insert(ips);
+    }
+    hash
+}
+
+/// Render a captured native backtrace as function names, outermost first and
+/// separated by semicolons, like the .prof files.
+pub fn describe_native_backtrace(hash: BacktraceHash) -> Option<String> {
+    let ips = NATIVE_BACKTRACES.lock().unwrap().get(&hash)?.clone();
+    let frames: Vec<String> = ips
+        .iter()
+        .rev()
+        .map(|ip| {
+            let mut name = None;
+            backtrace::resolve(*ip as *mut std::ffi::c_void, |symbol| {
+                if name.is_none() {
+                    name = symbol.name().map(|name| name.to_string());
+                }
+            });
+            name.unwrap_or_else(|| format!("{:#x}", ip))
+        })
+        .collect();
+    Some(frames.join(";"))
+}
+
+/// Something that doesn't add up in the tracker's state.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inconsistency {
+    /// A running total doesn't match the sum of the things it counts.
+    SumMismatch {
+        what: &'static str,
+        total: usize,
+        sum: usize,
+    },
+    /// A callstack was asked to give up more memory than it was using.
+    Underflow {
+        callstack: String,
+        times: usize,
+        bytes: usize,
+    },
+    /// The peak is smaller than current usage.
+    PeakBelowCurrent {
+        what: &'static str,
+        peak: usize,
+        current: usize,
+    },
+}
+
+impl Inconsistency {
+    fn kind(&self) -> &'static str {
+        match self {
+            Inconsistency::SumMismatch { .. } => "sum_mismatch",
+            Inconsistency::Underflow { .. } => "underflow",
+            Inconsistency::PeakBelowCurrent { .. } => "peak_below_current",
+        }
+    }
+
+    /// Render as a JSON object.
+    pub fn to_json(&self) -> String {
+        let fields = match self {
+            Inconsistency::SumMismatch { what, total, sum } => format!(
+                "\"what\": {}, \"total\": {}, \"sum\": {}",
+                json_string(what),
+                total,
+                sum
+            ),
+            Inconsistency::Underflow {
+                callstack,
+                times,
+                bytes,
+            } => format!(
+                "\"callstack\": {}, \"times\": {}, \"bytes\": {}",
+                json_string(callstack),
+                times,
+                bytes
+            ),
+            Inconsistency::PeakBelowCurrent {
+                what,
+                peak,
+                current,
+            } => format!(
+                "\"what\": {}, \"peak\": {}, \"current\": {}",
+                json_string(what),
+                peak,
+                current
+            ),
+        };
+        format!(
+            "{{\"kind\": {}, \"description\": {}, {}}}",
+            json_string(self.kind()),
+            json_string(&self.to_string()),
+            fields
+        )
+    }
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::SumMismatch { what, total, sum } => {
+                write!(f, "{} is {}, but adds up to {}", what, total, sum)
+            }
+            Inconsistency::Underflow {
+                callstack,
+                times,
+                bytes,
+            } => write!(
+                f,
+                "Usage went below zero {} times, by {} bytes in total, for callstack {}",
+                times, bytes, callstack
+            ),
+            Inconsistency::PeakBelowCurrent {
+                what,
+                peak,
+                current,
+            } => write!(f, "Peak {} is {}, below current {}", what, peak, current),
+        }
+    }
+}
+
+/// Allocations from one callstack that disappeared without being freed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MissingAllocations {
+    pub callstack: String,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Frees of allocations Fil didn't know about, from one native backtrace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UntrackedFrees {
+    /// None if no backtrace was captured.
+    pub backtrace_hash: Option<BacktraceHash>,
+    pub backtrace: Option<String>,
+    pub count: usize,
+}
+
+/// Everything that goes into the diagnostics file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Diagnostics {
+    pub inconsistencies: Vec<Inconsistency>,
+    pub missing_allocated_bytes: usize,
+    pub failed_deallocations: usize,
+    /// Largest first.
+    pub missing_allocations: Vec<MissingAllocations>,
+    /// Most common first.
+    pub untracked_frees: Vec<UntrackedFrees>,
+}
+
+impl Diagnostics {
+    /// Render as JSON.
+    pub fn to_json(&self) -> String {
+        let inconsistencies: Vec<String> =
+            self.inconsistencies.iter().map(|i| i.to_json()).collect();
+        let missing_allocations: Vec<String> = self
+            .missing_allocations
+            .iter()
+            .map(|missing| {
+                format!(
+                    "{{\"callstack\": {}, \"count\": {}, \"bytes\": {}}}",
+                    json_string(&missing.callstack),
+                    missing.count,
+                    missing.bytes
+                )
+            })
+            .collect();
+        let optional_string = |value: Option<String>| match value {
+            Some(value) => json_string(&value),
+            None => "null".to_string(),
+        };
+        let untracked_frees: Vec<String> = self
+            .untracked_frees
+            .iter()
+            .map(|frees| {
+                format!(
+                    "{{\"backtrace_hash\": {}, \"backtrace\": {}, \"count\": {}}}",
+                    optional_string(frees.backtrace_hash.map(|hash| format!("{:016x}", hash))),
+                    optional_string(frees.backtrace.clone()),
+                    frees.count
+                )
+            })
+            .collect();
+        format!(
+            "{{\"missing_allocated_bytes\": {}, \"failed_deallocations\": {}, \"inconsistencies\": [\n{}\n], \"missing_allocations\": [\n{}\n], \"untracked_frees\": [\n{}\n]}}\n",
+            self.missing_allocated_bytes,
+            self.failed_deallocations,
+            inconsistencies.join(",\n"),
+            missing_allocations.join(",\n"),
+            untracked_frees.join(",\n"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        capture_native_backtrace, describe_native_backtrace, Diagnostics, Inconsistency,
+        MissingAllocations, UntrackedFrees,
+    };
+
+    #[test]
+    fn json() {
+        let diagnostics = Diagnostics {
+            inconsistencies: vec![
+                Inconsistency::SumMismatch {
+                    what: "current allocated bytes",
+                    total: 100,
+                    sum: 90,
+                },
+                Inconsistency::Underflow {
+                    callstack: "a:1 (\"af\")".to_string(),
+                    times: 2,
+                    bytes: 30,
+                },
+            ],
+            missing_allocated_bytes: 16,
+            failed_deallocations: 3,
+            missing_allocations: vec![MissingAllocations {
+                callstack: "a:1 (af)".to_string(),
+                count: 1,
+                bytes: 16,
+            }],
+            untracked_frees: vec![
+                UntrackedFrees {
+                    backtrace_hash: Some(0xabc),
+                    backtrace: Some("main;free".to_string()),
+                    count: 2,
+                },
+                UntrackedFrees {
+                    backtrace_hash: None,
+                    backtrace: None,
+                    count: 1,
+                },
+            ],
+        };
+        assert_eq!(
+            diagnostics.to_json(),
+            "{\"missing_allocated_bytes\": 16, \"failed_deallocations\": 3, \"inconsistencies\": [\n\
+             {\"kind\": \"sum_mismatch\", \"description\": \"current allocated bytes is 100, but adds up to 90\", \"what\": \"current allocated bytes\", \"total\": 100, \"sum\": 90},\n\
+             {\"kind\": \"underflow\", \"description\": \"Usage went below zero 2 times, by 30 bytes in total, for callstack a:1 (\\\"af\\\")\", \"callstack\": \"a:1 (\\\"af\\\")\", \"times\": 2, \"bytes\": 30}\n\
+             ], \"missing_allocations\": [\n\
+             {\"callstack\": \"a:1 (af)\", \"count\": 1, \"bytes\": 16}\n\
+             ], \"untracked_frees\": [\n\
+             {\"backtrace_hash\": \"0000000000000abc\", \"backtrace\": \"main;free\", \"count\": 2},\n\
+             {\"backtrace_hash\": null, \"backtrace\": null, \"count\": 1}\n\
+             ]}\n"
+        );
+        assert_eq!(
+            Diagnostics::default().to_json(),
+            "{\"missing_allocated_bytes\": 0, \"failed_deallocations\": 0, \"inconsistencies\": [\n\n], \"missing_allocations\": [\n\n], \"untracked_frees\": [\n\n]}\n"
+        );
+    }
+
+    #[inline(never)]
+    fn capture_here() -> u64 {
+        capture_native_backtrace()
+    }
+
+    #[test]
+    fn native_backtraces() {
+        let (first, second) = (capture_here(), capture_here());
+        // Different call sites give different backtraces:
+        assert_ne!(first, second);
+        assert_eq!(describe_native_backtrace(12345), None);
+        let backtrace = describe_native_backtrace(first).unwrap();
+        assert!(backtrace.contains("native_backtraces"), "{}", backtrace);
+        assert!(backtrace.contains("capture_here"), "{}", backtrace);
+    }
+}
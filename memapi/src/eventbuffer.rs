@@ -0,0 +1,311 @@
+// Per-thread buffers of allocation events, so threads don't have to take the
+// global tracker lock on every malloc() and free().
+//
+// Every event gets a global sequence number, all buffers are drained together,
+// and the drained events are applied in sequence order. The tracker therefore
+// sees the same sequence of events it would have seen without buffering, so
+// peak memory is still calculated exactly.
+//
+// Compacting the tracker's callstacks renumbers them, so threads that cached
+// callstack IDs need to recalculate them. Every compaction starts a new
+// generation, and allocations from a previous generation are rejected.
+use crate::memorytracking::{AllocationTracker, CallstackId};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// An allocation event that hasn't been applied to the tracker yet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Allocation {
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    },
+    Free {
+        address: usize,
+    },
+}
+
+/// The buffer for a single thread.
+pub struct ThreadBuffer {
+    events: Mutex<Vec<(u64, Event)>>,
+}
+
+/// All the threads' buffers.
+pub struct EventBuffers {
+    next_sequence: AtomicU64,
+    generation: AtomicU32,
+    buffers: Mutex<Vec<Arc<ThreadBuffer>>>,
+    capacity: usize,
+}
+
+impl EventBuffers {
+    /// Threads should flush once their buffer has `capacity` events.
+    pub fn new(capacity: usize) -> Self {
+        EventBuffers {
+            next_sequence: AtomicU64::new(0),
+            generation: AtomicU32::new(0),
+            buffers: Mutex::new(vec![]),
+            capacity,
+        }
+    }
+
+    /// Create and register a buffer for a new thread.
+    pub fn new_thread_buffer(&self) -> Arc<ThreadBuffer> {
+        let buffer = Arc::new(ThreadBuffer {
+            events: Mutex::new(Vec::with_capacity(self.capacity)),
+        });
+        self.buffers.lock().unwrap().push(buffer.clone());
+        buffer
+    }
+
+    /// The current generation of callstack IDs.
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Record an event, where `generation` is the generation its callstack ID
+    /// was calculated in. Returns whether the buffer is full, in which case
+    /// the caller should drain the buffers, or gives back an allocation event
+    /// whose callstack ID is from an older generation.
+    ///
+    /// For correct ordering, frees must be recorded before the memory is
+    /// actually freed, and allocations after the memory is allocated.
+    pub fn push(
+        &self,
+        buffer: &ThreadBuffer,
+        generation: u32,
+        event: Event,
+    ) -> Result<bool, Event> {
+        let mut events = buffer.events.lock().unwrap();
+        // Compaction holds all the buffers' locks while changing the
+        // generation, so this can't become stale before we're done:
+        if matches!(event, Event::Allocation { .. }) && generation != self.generation() {
+            return Err(event);
+        }
+        // The sequence number is assigned while holding the buffer's lock, so
+        // drain() never sees a later event without also seeing earlier ones.
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        events.push((sequence, event));
+        Ok(events.len() >= self.capacity)
+    }
+
+    /// Remove all buffered events, in the order they happened.
+    ///
+    /// To ensure events get applied in order, the caller should hold the lock
+    /// for the tracker they're applying the events to.
+    pub fn drain(&self) -> Vec<Event> {
+        self.drain_then(|events| events)
+    }
+
+    /// Remove all buffered events and pass them to `compact`, then start a new
+    /// generation. No events can be recorded in the meantime, so callstack
+    /// IDs recorded later are either in the new generation or rejected.
+    pub fn advance_generation<R, F: FnOnce(Vec<Event>) -> R>(&self, compact: F) -> R {
+        self.drain_then(|events| {
+            let result = compact(events);
+            self.generation.fetch_add(1, Ordering::AcqRel);
+            result
+        })
+    }
+
+    /// Remove all buffered events, in the order they happened, and pass them
+    /// to `f` while still holding all the buffers' locks.
+    fn drain_then<R, F: FnOnce(Vec<Event>) -> R>(&self, f: F) -> R {
+        let mut buffers = self.buffers.lock().unwrap();
+        // Lock all buffers at once, so we get every event up to a single point
+        // in time:
+        let mut locked: Vec<_> = buffers.iter().map(|b| b.events.lock().unwrap()).collect();
+        let mut events: Vec<(u64, Event)> = locked.iter_mut().flat_map(|e| e.drain(..)).collect();
+        events.sort_unstable_by_key(|(sequence, _)| *sequence);
+        let result = f(events.into_iter().map(|(_, event)| event).collect());
+        drop(locked);
+        // Forget buffers of threads that have exited:
+        buffers.retain(|buffer| Arc::strong_count(buffer) > 1);
+        result
+    }
+}
+
+/// Apply events to the tracker, returning the total bytes newly allocated.
+pub fn apply_events(tracker: &mut AllocationTracker, events: Vec<Event>) -> usize {
+    let mut allocated = 0;
+    for event in events {
+        match event {
+            Event::Allocation {
+                address,
+                size,
+                callstack_id,
+            } => {
+                tracker.add_allocation(address, size, callstack_id);
+                allocated += size;
+            }
+            Event::Free { address } => {
+                tracker.free_allocation(address);
+            }
+        }
+    }
+    allocated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_events, Event, EventBuffers};
+    use crate::memorytracking::AllocationTracker;
+    use std::sync::{Arc, Mutex};
+
+    fn allocation(address: usize, size: usize) -> Event {
+        Event::Allocation {
+            address,
+            size,
+            callstack_id: 0,
+        }
+    }
+
+    // Events use callstack 0, the empty callstack, which always exists.
+    fn tracker() -> AllocationTracker {
+        AllocationTracker::new(".".to_string())
+    }
+
+    #[test]
+    fn drained_in_order_across_threads() {
+        let buffers = EventBuffers::new(10);
+        let thread1 = buffers.new_thread_buffer();
+        let thread2 = buffers.new_thread_buffer();
+        assert!(!buffers.push(&thread1, 0, allocation(1, 100)).unwrap());
+        assert!(!buffers
+            .push(&thread2, 0, Event::Free { address: 1 })
+            .unwrap());
+        assert!(!buffers.push(&thread1, 0, allocation(1, 50)).unwrap());
+        assert!(!buffers.push(&thread2, 0, allocation(2, 70)).unwrap());
+        assert_eq!(
+            buffers.drain(),
+            vec![
+                allocation(1, 100),
+                Event::Free { address: 1 },
+                allocation(1, 50),
+                allocation(2, 70)
+            ]
+        );
+        assert_eq!(buffers.drain(), vec![]);
+    }
+
+    #[test]
+    fn full_buffer() {
+        let buffers = EventBuffers::new(2);
+        let thread = buffers.new_thread_buffer();
+        assert!(!buffers.push(&thread, 0, allocation(1, 100)).unwrap());
+        assert!(buffers.push(&thread, 0, allocation(2, 100)).unwrap());
+        assert_eq!(buffers.drain().len(), 2);
+        assert!(!buffers.push(&thread, 0, allocation(3, 100)).unwrap());
+    }
+
+    #[test]
+    fn exited_threads_are_forgotten() {
+        let buffers = EventBuffers::new(10);
+        let thread = buffers.new_thread_buffer();
+        buffers.push(&thread, 0, allocation(1, 100)).unwrap();
+        drop(thread);
+        // Events from exited threads still get applied:
+        assert_eq!(buffers.drain(), vec![allocation(1, 100)]);
+        assert_eq!(buffers.buffers.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn stale_allocations_are_rejected() {
+        let buffers = EventBuffers::new(10);
+        let thread = buffers.new_thread_buffer();
+        assert_eq!(buffers.generation(), 0);
+        buffers.push(&thread, 0, allocation(1, 100)).unwrap();
+        let compacted = buffers.advance_generation(|events| {
+            // Nothing can be pushed while compacting, so all events are here:
+            assert_eq!(events, vec![allocation(1, 100)]);
+            123
+        });
+        assert_eq!(compacted, 123);
+        assert_eq!(buffers.generation(), 1);
+        // Allocations with callstack IDs from the old generation are rejected,
+        // frees are fine:
+        assert_eq!(
+            buffers.push(&thread, 0, allocation(2, 100)),
+            Err(allocation(2, 100))
+        );
+        assert_eq!(
+            buffers.push(&thread, 0, Event::Free { address: 1 }),
+            Ok(false)
+        );
+        assert_eq!(buffers.push(&thread, 1, allocation(2, 100)), Ok(false));
+        assert_eq!(
+            buffers.drain(),
+            vec![Event::Free { address: 1 }, allocation(2, 100)]
+        );
+    }
+
+    #[test]
+    fn peak_is_exact() {
+        // Each event gets applied both directly and via buffers; the result
+        // must be the same.
+        let mut direct = tracker();
+        let mut buffered = tracker();
+        let buffers = EventBuffers::new(1000);
+        let thread1 = buffers.new_thread_buffer();
+        let thread2 = buffers.new_thread_buffer();
+        let events = [
+            (&thread1, allocation(1, 100)),
+            (&thread2, allocation(2, 300)),
+            (&thread1, Event::Free { address: 2 }),
+            (&thread2, allocation(3, 250)),
+            (&thread1, Event::Free { address: 1 }),
+            (&thread2, allocation(4, 10)),
+        ];
+        for (thread, event) in events.iter() {
+            apply_events(&mut direct, vec![*event]);
+            buffers.push(thread, 0, *event).unwrap();
+        }
+        assert_eq!(apply_events(&mut buffered, buffers.drain()), 660);
+        assert_eq!(buffered.get_peak_allocated_bytes(), 400);
+        assert_eq!(
+            buffered.get_peak_allocated_bytes(),
+            direct.get_peak_allocated_bytes()
+        );
+        assert_eq!(
+            buffered.get_current_allocated_bytes(),
+            direct.get_current_allocated_bytes()
+        );
+    }
+
+    #[test]
+    fn concurrent_threads() {
+        // Threads allocate and free concurrently while another drains; every
+        // free must be applied after its allocation.
+        let buffers = Arc::new(EventBuffers::new(16));
+        let tracker = Arc::new(Mutex::new(tracker()));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let buffers = buffers.clone();
+                let tracker = tracker.clone();
+                std::thread::spawn(move || {
+                    let buffer = buffers.new_thread_buffer();
+                    for j in 0..10_000 {
+                        let address = i * 1_000_000 + j;
+                        let full = buffers.push(&buffer, 0, allocation(address, 10)).unwrap();
+                        let full =
+                            buffers.push(&buffer, 0, Event::Free { address }).unwrap() || full;
+                        if full {
+                            let mut tracker = tracker.lock().unwrap();
+                            apply_events(&mut tracker, buffers.drain());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let mut tracker = tracker.lock().unwrap();
+        apply_events(&mut tracker, buffers.drain());
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert_eq!(tracker.get_failed_deallocations(), 0);
+        assert_eq!(tracker.get_missing_allocated_bytes(), 0);
+        assert!(tracker.get_peak_allocated_bytes() <= 4 * 10);
+    }
+}
@@ -0,0 +1,458 @@
+// Rules for which frames to show when rendering callstacks, and how to show
+// their filenames. They're configured with environment variables:
+//
+// * FIL_HIDE_FRAMES: comma-separated globs; frames whose module (e.g.
+//   `numpy.core.*`) or, for globs containing a `/`, whose filename matches
+//   are hidden.
+// * FIL_COLLAPSE_IMPORTLIB=0: show every frame of the import machinery,
+//   rather than just the outermost one of each import.
+// * FIL_SHORTEN_PATHS=1: show files in site-packages relative to it, and
+//   other files in the Python installation relative to sys.prefix.
+// * FIL_SHOW_ALL_FRAMES=1: don't hide any frames, including the runpy frames
+//   that are an implementation detail of how Fil runs programs.
+// * FIL_FOLD_RECURSION=1: show repeated sequences of recursive calls once,
+//   annotated with the recursion depth.
+//
+// Recursion is only folded when rendering: folding when interning would lose
+// the line numbers of the innermost frames, which are the interesting ones.
+const IMPORTLIB_FILENAMES: &[&str] = &[
+    "<frozen importlib._bootstrap>",
+    "<frozen importlib._bootstrap_external>",
+];
+
+const PACKAGE_DIRECTORIES: &[&str] = &["/site-packages/", "/dist-packages/"];
+
+// Longest sequence of frames that gets folded, e.g. 2 for a() calling b()
+// calling a() and so on:
+const MAX_RECURSION_PERIOD: usize = 8;
+
+/// Which frames to show when rendering callstacks, and how to show their
+/// filenames.
+pub trait FrameFilter: Send + Sync {
+    /// Whether to show each frame of a callstack, given their filenames,
+    /// outermost first. At least one frame of a non-empty callstack must be
+    /// shown.
+    fn visible(&self, filenames: &[&str]) -> Vec<bool>;
+
+    /// Whether to fold repeated sequences of recursive calls; see fold().
+    fn fold_recursion(&self) -> bool;
+
+    /// The filename to show.
+    fn display_filename<'a>(&self, filename: &'a str) -> &'a str;
+}
+
+impl<'f> dyn FrameFilter + 'f {
+    /// Remove hidden frames, outermost first, given a way to get each frame's
+    /// filename. Hiding never leaves a callstack empty.
+    pub fn filter<T>(&self, frames: Vec<T>, filename: impl Fn(&T) -> &str) -> Vec<T> {
+        let visible = self.visible(&frames.iter().map(filename).collect::<Vec<_>>());
+        frames
+            .into_iter()
+            .zip(visible)
+            .filter(|(_, visible)| *visible)
+            .map(|(frame, _)| frame)
+            .collect()
+    }
+
+    /// Fold repeated sequences of frames, outermost first, whose keys (e.g.
+    /// the function) are the same. Only the last, i.e. innermost, repetition
+    /// of each sequence is kept, so its line numbers show where the recursion
+    /// ended up. Its last frame is returned along with the number of
+    /// repetitions, i.e. the recursion depth.
+    pub fn fold<T, K: PartialEq>(
+        &self,
+        frames: Vec<T>,
+        key: impl Fn(&T) -> K,
+    ) -> Vec<(T, Option<usize>)> {
+        if !self.fold_recursion() {
+            return frames.into_iter().map(|frame| (frame, None)).collect();
+        }
+        let keys: Vec<K> = frames.iter().map(key).collect();
+        // For each kept frame, the depth to annotate it with:
+        let mut kept: Vec<Option<Option<usize>>> = vec![None; frames.len()];
+        let mut start = 0;
+        while start < keys.len() {
+            // Find the period that folds away the most frames:
+            let mut best: Option<(usize, usize)> = None;
+            for period in 1..=MAX_RECURSION_PERIOD {
+                let block = &keys[start..(start + period).min(keys.len())];
+                let mut repetitions = 1;
+                while keys
+                    .get(start + repetitions * period..start + (repetitions + 1) * period)
+                    .is_some_and(|next| next == block)
+                {
+                    repetitions += 1;
+                }
+                let folds_more = match best {
+                    Some((best_period, best_repetitions)) => {
+                        period * repetitions > best_period * best_repetitions
+                    }
+                    None => true,
+                };
+                if repetitions > 1 && folds_more {
+                    best = Some((period, repetitions));
+                }
+            }
+            match best {
+                Some((period, repetitions)) => {
+                    let last = start + period * (repetitions - 1);
+                    for depth in kept[last..last + period - 1].iter_mut() {
+                        *depth = Some(None);
+                    }
+                    kept[last + period - 1] = Some(Some(repetitions));
+                    start += period * repetitions;
+                }
+                None => {
+                    kept[start] = Some(None);
+                    start += 1;
+                }
+            }
+        }
+        frames
+            .into_iter()
+            .zip(kept)
+            .filter_map(|(frame, depth)| depth.map(|depth| (frame, depth)))
+            .collect()
+    }
+}
+
+/// Rules configured with environment variables; see the top of the module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameRules {
+    /// Hide leading frames from this file, i.e. runpy.
+    hide_leading: Option<String>,
+    /// Globs matching module names or filenames of frames to hide.
+    hide: Vec<String>,
+    /// Show only the outermost of consecutive importlib frames.
+    collapse_importlib: bool,
+    /// Rewrite filenames to be shorter.
+    shorten_paths: bool,
+    /// sys.prefix and friends, with a trailing slash.
+    prefixes: Vec<String>,
+    /// Fold repeated sequences of recursive calls.
+    fold_recursion: bool,
+}
+
+impl FrameRules {
+    /// Load the rules from environment variables, for a program that isn't
+    /// running Python code.
+    pub fn from_env() -> Self {
+        Self::load(None, &[])
+    }
+
+    /// Load the rules from environment variables, given the filename of
+    /// Python's runpy module and sys.prefix and friends.
+    pub fn from_env_for_python(runpy_path: &str, prefixes: &[String]) -> Self {
+        Self::load(Some(runpy_path), prefixes)
+    }
+
+    fn load(runpy_path: Option<&str>, prefixes: &[String]) -> Self {
+        let get = |name| std::env::var(name).ok();
+        let show_all = get("FIL_SHOW_ALL_FRAMES").as_deref() == Some("1");
+        let hide_leading = match runpy_path {
+            Some(path) if !show_all && !cfg!(feature = "fil4prod") => Some(path.to_string()),
+            _ => None,
+        };
+        let hide = match get("FIL_HIDE_FRAMES") {
+            Some(globs) if !show_all => parse_globs(&globs),
+            _ => vec![],
+        };
+        let collapse_importlib = !show_all && get("FIL_COLLAPSE_IMPORTLIB").as_deref() != Some("0");
+        let shorten_paths = get("FIL_SHORTEN_PATHS").as_deref() == Some("1");
+        let fold_recursion = get("FIL_FOLD_RECURSION").as_deref() == Some("1");
+        FrameRules {
+            hide_leading,
+            hide,
+            collapse_importlib,
+            shorten_paths,
+            prefixes: prefixes
+                .iter()
+                .map(|prefix| format!("{}/", prefix.trim_end_matches('/')))
+                .collect(),
+            fold_recursion,
+        }
+    }
+
+    /// Whether frames from this file are hidden by a glob.
+    fn is_hidden(&self, filename: &str) -> bool {
+        if self.hide.is_empty() {
+            return false;
+        }
+        let module = self.module_name(filename);
+        self.hide.iter().any(|glob| {
+            if glob.contains('/') {
+                glob_matches(glob, filename)
+            } else {
+                glob_matches(glob, &module)
+            }
+        })
+    }
+
+    /// Best guess at the module name for a filename.
+    fn module_name(&self, filename: &str) -> String {
+        if let Some(module) = filename
+            .strip_prefix("<frozen ")
+            .and_then(|rest| rest.strip_suffix('>'))
+        {
+            return module.to_string();
+        }
+        let relative = match package_relative(filename) {
+            Some(relative) => relative,
+            None => {
+                // The standard library is in e.g. lib/python3.9/ inside the
+                // prefix; anything else we can't tell, so use the filename.
+                let stdlib = self
+                    .strip_prefix(filename)
+                    .and_then(|rest| rest.strip_prefix("lib/python"))
+                    .and_then(|rest| rest.split_once('/'))
+                    .map(|(_, rest)| rest);
+                stdlib.unwrap_or_else(|| filename.rsplit('/').next().unwrap_or(filename))
+            }
+        };
+        let relative = relative.strip_suffix(".py").unwrap_or(relative);
+        let relative = relative.strip_suffix("/__init__").unwrap_or(relative);
+        relative.replace('/', ".")
+    }
+
+    /// Strip sys.prefix or similar from the start of the filename.
+    fn strip_prefix<'a>(&self, filename: &'a str) -> Option<&'a str> {
+        self.prefixes
+            .iter()
+            .find_map(|prefix| filename.strip_prefix(prefix.as_str()))
+    }
+}
+
+impl FrameFilter for FrameRules {
+    fn visible(&self, filenames: &[&str]) -> Vec<bool> {
+        let leading = match &self.hide_leading {
+            Some(hidden) => filenames
+                .iter()
+                .take_while(|filename| **filename == hidden)
+                .count(),
+            None => 0,
+        };
+        let leading = if leading == filenames.len() {
+            0
+        } else {
+            leading
+        };
+        let mut previous_was_importlib = false;
+        let keep: Vec<bool> = filenames[leading..]
+            .iter()
+            .map(|filename| {
+                let is_importlib =
+                    self.collapse_importlib && IMPORTLIB_FILENAMES.contains(filename);
+                let collapsed = is_importlib && previous_was_importlib;
+                previous_was_importlib = is_importlib;
+                !collapsed && !self.is_hidden(filename)
+            })
+            .collect();
+        let all_hidden = !keep.iter().any(|keep| *keep);
+        let mut visible = vec![false; leading];
+        visible.extend(keep.into_iter().map(|keep| keep || all_hidden));
+        visible
+    }
+
+    fn fold_recursion(&self) -> bool {
+        self.fold_recursion
+    }
+
+    fn display_filename<'a>(&self, filename: &'a str) -> &'a str {
+        if !self.shorten_paths {
+            return filename;
+        }
+        package_relative(filename)
+            .or_else(|| self.strip_prefix(filename))
+            .unwrap_or(filename)
+    }
+}
+
+/// The part of a filename after site-packages or similar, e.g.
+/// "numpy/core/numeric.py".
+pub(crate) fn package_relative(filename: &str) -> Option<&str> {
+    PACKAGE_DIRECTORIES.iter().find_map(|directory| {
+        filename
+            .rfind(directory)
+            .map(|index| &filename[index + directory.len()..])
+    })
+}
+
+fn parse_globs(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|glob| glob.trim())
+        .filter(|glob| !glob.is_empty())
+        .map(|glob| glob.to_string())
+        .collect()
+}
+
+/// Match a glob, where `*` matches any number of characters (including `/`)
+/// and `?` matches one character.
+fn glob_matches(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Classic backtracking on the last `*`:
+    let (mut g, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == text[t]) {
+            g += 1;
+            t += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            star = Some((g, t));
+            g += 1;
+        } else if let Some((star_g, star_t)) = star {
+            g = star_g + 1;
+            t = star_t + 1;
+            star = Some((star_g, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_matches, parse_globs, FrameFilter, FrameRules};
+
+    fn rules(hide: &str, collapse_importlib: bool, shorten_paths: bool) -> FrameRules {
+        FrameRules {
+            hide_leading: Some("/usr/lib/python3.9/runpy.py".to_string()),
+            hide: parse_globs(hide),
+            collapse_importlib,
+            shorten_paths,
+            prefixes: vec!["/usr/".to_string(), "/opt/venv/".to_string()],
+            fold_recursion: true,
+        }
+    }
+
+    fn filter(rules: &FrameRules, filenames: &[&'static str]) -> Vec<&'static str> {
+        (rules as &dyn FrameFilter).filter(filenames.to_vec(), |filename| filename)
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("numpy", "numpy"));
+        assert!(!glob_matches("numpy", "numpy.core"));
+        assert!(glob_matches("numpy.*", "numpy.core.numeric"));
+        assert!(glob_matches("*/tests/*", "/src/app/tests/test_x.py"));
+        assert!(glob_matches("a?c*", "abc"));
+        assert!(!glob_matches("a?c", "ac"));
+        assert!(glob_matches("*a*b", "xaxaxb"));
+        assert_eq!(parse_globs(" a, ,b.*,"), vec!["a", "b.*"]);
+    }
+
+    #[test]
+    fn module_names() {
+        let rules = rules("", false, false);
+        assert_eq!(
+            rules.module_name("/opt/venv/lib/python3.9/site-packages/numpy/core/numeric.py"),
+            "numpy.core.numeric"
+        );
+        assert_eq!(
+            rules.module_name("/opt/venv/lib/python3.9/site-packages/numpy/__init__.py"),
+            "numpy"
+        );
+        assert_eq!(
+            rules.module_name("/usr/lib/python3.9/json/decoder.py"),
+            "json.decoder"
+        );
+        assert_eq!(
+            rules.module_name("<frozen importlib._bootstrap>"),
+            "importlib._bootstrap"
+        );
+        assert_eq!(rules.module_name("/home/user/script.py"), "script");
+    }
+
+    #[test]
+    fn hiding() {
+        let runpy = "/usr/lib/python3.9/runpy.py";
+        let bootstrap = "<frozen importlib._bootstrap>";
+        let external = "<frozen importlib._bootstrap_external>";
+        let numpy = "/opt/venv/lib/python3.9/site-packages/numpy/core/numeric.py";
+        let test = "/src/tests/test_x.py";
+        let stack = [
+            runpy,
+            runpy,
+            "/src/main.py",
+            bootstrap,
+            bootstrap,
+            external,
+            numpy,
+            bootstrap,
+            test,
+            runpy,
+        ];
+        // Leading runpy frames are always hidden:
+        assert_eq!(filter(&rules("", false, false), &stack), &stack[2..]);
+        assert_eq!(
+            filter(&rules("", true, false), &stack),
+            vec!["/src/main.py", bootstrap, numpy, bootstrap, test, runpy]
+        );
+        assert_eq!(
+            filter(&rules("numpy.*,*/tests/*", true, false), &stack),
+            vec!["/src/main.py", bootstrap, bootstrap, runpy]
+        );
+        // If everything would be hidden, only the leading runpy frames are:
+        assert_eq!(
+            filter(&rules("*", true, false), &[runpy, numpy]),
+            vec![numpy]
+        );
+        let mut no_leading = rules("*", true, false);
+        no_leading.hide_leading = None;
+        assert_eq!(filter(&no_leading, &[numpy]), vec![numpy]);
+    }
+
+    #[test]
+    fn shortening() {
+        let long = rules("", false, false);
+        let short = rules("", false, true);
+        let numpy = "/opt/venv/lib/python3.9/site-packages/numpy/core/numeric.py";
+        assert_eq!(long.display_filename(numpy), numpy);
+        assert_eq!(short.display_filename(numpy), "numpy/core/numeric.py");
+        assert_eq!(
+            short.display_filename("/usr/lib/python3/dist-packages/yaml/__init__.py"),
+            "yaml/__init__.py"
+        );
+        assert_eq!(
+            short.display_filename("/usr/lib/python3.9/json/decoder.py"),
+            "lib/python3.9/json/decoder.py"
+        );
+        assert_eq!(short.display_filename("/src/main.py"), "/src/main.py");
+        assert_eq!(short.display_filename("<string>"), "<string>");
+    }
+
+    #[test]
+    fn recursion_folding() {
+        let fold = |frames: &[&'static str]| {
+            (&rules("", false, false) as &dyn FrameFilter)
+                .fold(frames.to_vec(), |frame| frame.chars().next())
+        };
+        // Keys are the first letter, so the rest is e.g. a line number:
+        assert_eq!(
+            fold(&["a1", "b1", "c1"]),
+            vec![("a1", None), ("b1", None), ("c1", None)]
+        );
+        assert_eq!(
+            fold(&["m1", "f1", "f2", "f2", "f2", "g1"]),
+            vec![("m1", None), ("f2", Some(4)), ("g1", None)]
+        );
+        // Mutual recursion:
+        assert_eq!(
+            fold(&["m1", "a1", "b1", "a2", "b2", "a3", "b3", "a4"]),
+            vec![("m1", None), ("a3", None), ("b3", Some(3)), ("a4", None)]
+        );
+        // The period that folds the most frames wins:
+        assert_eq!(
+            fold(&["a1", "a2", "b1", "a3", "a4", "b2"]),
+            vec![("a3", None), ("a4", None), ("b2", Some(2))]
+        );
+        let mut unfolded = rules("", false, false);
+        unfolded.fold_recursion = false;
+        assert_eq!(
+            (&unfolded as &dyn FrameFilter).fold(vec!["f1", "f2"], |frame| frame.chars().next()),
+            vec![("f1", None), ("f2", None)]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+// Histograms of allocation sizes, so it's possible to tell whether a callstack
+// makes many small objects or a few huge buffers.
+//
+// Sizes are bucketed by powers of two: bucket 0 is size 0, and bucket N holds
+// sizes from 2^(N-1) to 2^N - 1. Most callstacks only ever allocate a few
+// different sizes, so only non-empty buckets are stored, and the first few are
+// stored inline. That way copying histograms, which happens for the peak while
+// the tracker lock is held, usually doesn't allocate.
+use smallvec::SmallVec;
+use std::fmt;
+use std::mem::size_of;
+
+/// Counts of allocations by size bucket.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SizeHistogram {
+    // (bucket, count), sorted by bucket, without zero counts:
+    buckets: SmallVec<[(u8, u32); 4]>,
+}
+
+impl SizeHistogram {
+    /// The bucket for an allocation size.
+    pub fn bucket(size: usize) -> u8 {
+        (usize::BITS - size.leading_zeros()) as u8
+    }
+
+    /// The smallest and largest size in a bucket.
+    pub fn bucket_range(bucket: u8) -> (usize, usize) {
+        match bucket {
+            0 => (0, 0),
+            b if b as u32 >= usize::BITS => (1 << (usize::BITS - 1), usize::MAX),
+            b => (1 << (b - 1), (1 << b) - 1),
+        }
+    }
+
+    /// Count an allocation of the given size.
+    pub fn add(&mut self, size: usize) {
+        let bucket = Self::bucket(size);
+        match self.buckets.binary_search_by_key(&bucket, |(b, _)| *b) {
+            Ok(index) => {
+                let count = &mut self.buckets[index].1;
+                *count = count.saturating_add(1);
+            }
+            Err(index) => self.buckets.insert(index, (bucket, 1)),
+        }
+    }
+
+    /// Stop counting an allocation of the given size.
+    pub fn remove(&mut self, size: usize) {
+        let bucket = Self::bucket(size);
+        if let Ok(index) = self.buckets.binary_search_by_key(&bucket, |(b, _)| *b) {
+            let count = &mut self.buckets[index].1;
+            *count -= 1;
+            if *count == 0 {
+                self.buckets.remove(index);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Total number of allocations counted.
+    pub fn count(&self) -> usize {
+        self.buckets.iter().map(|(_, count)| *count as usize).sum()
+    }
+
+    /// Non-empty buckets and their counts, smallest sizes first.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u32)> + '_ {
+        self.buckets.iter().copied()
+    }
+
+    /// Bytes of memory used, not including the struct itself.
+    pub fn memory_usage(&self) -> usize {
+        if self.buckets.spilled() {
+            self.buckets.capacity() * size_of::<(u8, u32)>()
+        } else {
+            0
+        }
+    }
+
+    /// Render as a JSON list of {"min_size", "max_size", "count"} objects.
+    pub fn to_json(&self) -> String {
+        let buckets: Vec<String> = self
+            .iter()
+            .map(|(bucket, count)| {
+                let (min_size, max_size) = Self::bucket_range(bucket);
+                format!(
+                    "{{\"min_size\": {}, \"max_size\": {}, \"count\": {}}}",
+                    min_size, max_size, count
+                )
+            })
+            .collect();
+        format!("[{}]", buckets.join(", "))
+    }
+}
+
+/// Human-readable, e.g. "3 x 512-1023, 1 x 1024-2047".
+impl fmt::Display for SizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, (bucket, count)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match Self::bucket_range(bucket) {
+                (min_size, max_size) if min_size == max_size => {
+                    write!(f, "{} x {}", count, min_size)?
+                }
+                (min_size, max_size) => write!(f, "{} x {}-{}", count, min_size, max_size)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SizeHistogram;
+    use proptest::prelude::*;
+
+    proptest! {
+        // Every size is in the range of its bucket.
+        #[test]
+        fn sizes_are_in_their_bucket(size in any::<usize>()) {
+            let (min_size, max_size) = SizeHistogram::bucket_range(SizeHistogram::bucket(size));
+            prop_assert!(min_size <= size);
+            prop_assert!(size <= max_size);
+        }
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(SizeHistogram::bucket(0), 0);
+        assert_eq!(SizeHistogram::bucket(1), 1);
+        assert_eq!(SizeHistogram::bucket(2), 2);
+        assert_eq!(SizeHistogram::bucket(3), 2);
+        assert_eq!(SizeHistogram::bucket(1023), 10);
+        assert_eq!(SizeHistogram::bucket(1024), 11);
+        assert_eq!(SizeHistogram::bucket_range(11), (1024, 2047));
+        assert_eq!(SizeHistogram::bucket(usize::MAX), usize::BITS as u8);
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut histogram = SizeHistogram::default();
+        assert!(histogram.is_empty());
+        assert_eq!(histogram.to_string(), "none");
+        assert_eq!(histogram.to_json(), "[]");
+        assert_eq!(histogram.memory_usage(), 0);
+        for size in [1000, 600, 1, 2000, 700].iter() {
+            histogram.add(*size);
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(
+            histogram.iter().collect::<Vec<_>>(),
+            vec![(1, 1), (10, 3), (11, 1)]
+        );
+        assert_eq!(histogram.to_string(), "1 x 1, 3 x 512-1023, 1 x 1024-2047");
+        // Few enough buckets to be stored inline:
+        assert_eq!(histogram.memory_usage(), 0);
+        histogram.remove(1);
+        histogram.remove(600);
+        assert_eq!(histogram.to_string(), "2 x 512-1023, 1 x 1024-2047");
+        assert_eq!(
+            histogram.to_json(),
+            r#"[{"min_size": 512, "max_size": 1023, "count": 2}, {"min_size": 1024, "max_size": 2047, "count": 1}]"#
+        );
+        // More buckets go on the heap:
+        for size in [1, 2, 4, 8].iter() {
+            histogram.add(*size);
+        }
+        assert_eq!(histogram.count(), 7);
+        assert!(histogram.memory_usage() > 0);
+    }
+}
@@ -1,10 +1,24 @@
+pub mod alerts;
+pub mod allocator;
+pub mod diagnostics;
+pub mod eventbuffer;
 pub mod ffi;
+pub mod framefilter;
+pub mod histogram;
 pub mod memorytracking;
 pub mod mmap;
 pub mod oom;
-mod python;
+pub mod overhead;
+pub mod packages;
+pub mod prometheus;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod query;
 mod rangemap;
+pub mod snapshots;
+pub mod sourcecache;
 pub mod util;
+pub mod viewer;
 
 #[macro_use]
 extern crate lazy_static;
@@ -1,13 +1,24 @@
-use crate::python::get_runpy_path;
-
-use super::rangemap::RangeMap;
-use super::util::new_hashmap;
+use super::diagnostics::{
+    describe_native_backtrace, BacktraceHash, Diagnostics, Inconsistency, MissingAllocations,
+    UntrackedFrees,
+};
+use super::framefilter::{FrameFilter, FrameRules};
+use super::histogram::SizeHistogram;
+use super::overhead::Overhead;
+use super::packages::{PackageMapper, PackageReport};
+use super::rangemap::{RangeMap, Remapped};
+use super::sourcecache::{SourceCache, SourceProvider};
+use super::util::{json_string, new_hashmap};
+use super::viewer::{CallTree, Frame};
 use ahash::RandomState as ARandomState;
 use im::Vector as ImVector;
 use inferno::flamegraph;
 use itertools::Itertools;
+use std::collections::HashSet;
+use std::mem::size_of;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::{borrow::Cow, io::Write};
 use std::{collections::HashMap, io::Read};
 use std::{fs, io::Seek};
@@ -31,17 +42,26 @@ impl FunctionId {
     }
 }
 
-/// A function location in the Python source code, e.g. "example() in foo.py".
-#[derive(Clone)]
+/// A function location in the Python source code, e.g. "Example.method() in
+/// foo.py, defined at line 10".
+#[derive(Clone, PartialEq, Eq, Hash)]
 struct FunctionLocation {
-    filename: String,
-    function_name: String,
+    filename: Arc<str>,
+    /// The qualified name, e.g. "Example.method" rather than just "method".
+    qualname: Arc<str>,
+    /// First line of the definition, or 0 if unknown.
+    first_line: u32,
 }
 
 /// Stores FunctionLocations, returns a FunctionId
 #[derive(Clone)]
 pub struct FunctionLocations {
     functions: Vec<FunctionLocation>,
+    // Each location is only registered once, so the same location always gets
+    // the same FunctionId:
+    location_to_id: HashMap<FunctionLocation, FunctionId, ARandomState>,
+    // Many functions share the same file, so store each filename only once:
+    filenames: HashSet<Arc<str>, ARandomState>,
 }
 
 impl FunctionLocations {
@@ -49,27 +69,90 @@ impl FunctionLocations {
     pub fn new() -> Self {
         Self {
             functions: Vec::with_capacity(8192),
+            location_to_id: new_hashmap(),
+            filenames: HashSet::default(),
         }
     }
 
-    /// Register a function, get back its id.
-    pub fn add_function(&mut self, filename: String, function_name: String) -> FunctionId {
-        self.functions.push(FunctionLocation {
+    /// Register a function whose definition line isn't known, get back its
+    /// id.
+    pub fn add_function(&mut self, filename: String, qualname: String) -> FunctionId {
+        self.add_function_definition(filename, qualname, 0)
+    }
+
+    /// Register a function, get back its id. Registering the same filename,
+    /// qualified name and definition line again returns the same id.
+    pub fn add_function_definition(
+        &mut self,
+        filename: String,
+        qualname: String,
+        first_line: u32,
+    ) -> FunctionId {
+        let filename = match self.filenames.get(filename.as_str()) {
+            Some(filename) => filename.clone(),
+            None => {
+                let filename: Arc<str> = filename.into();
+                self.filenames.insert(filename.clone());
+                filename
+            }
+        };
+        let location = FunctionLocation {
             filename,
-            function_name,
-        });
-        // If we ever have 2 ** 32 or more functions in our program, this will
-        // break. Seems unlikely, even with long running workers.
-        FunctionId((self.functions.len() - 1) as u32)
+            qualname: qualname.into(),
+            first_line,
+        };
+        let functions = &mut self.functions;
+        *self
+            .location_to_id
+            .entry(location)
+            .or_insert_with_key(|location| {
+                functions.push(location.clone());
+                // If we ever have 2 ** 32 or more functions in our program,
+                // this will break. Seems unlikely, even with long running
+                // workers.
+                FunctionId((functions.len() - 1) as u32)
+            })
     }
 
-    /// Get the function name and filename.
-    fn get_function_and_filename(&self, id: FunctionId) -> (&str, &str) {
+    /// Approximate bytes of memory used to store the functions.
+    fn memory_usage(&self) -> usize {
+        let location_size = size_of::<FunctionLocation>();
+        let arc_str_size = size_of::<Arc<str>>();
+        // Arc<str> stores two reference counts before the string:
+        let strings: usize = self
+            .functions
+            .iter()
+            .map(|location| 2 * size_of::<usize>() + location.qualname.len())
+            .chain(
+                self.filenames
+                    .iter()
+                    .map(|filename| 2 * size_of::<usize>() + filename.len()),
+            )
+            .sum();
+        self.functions.capacity() * location_size
+            + self.location_to_id.capacity() * (location_size + size_of::<FunctionId>() + 1)
+            + self.filenames.capacity() * (arc_str_size + 1)
+            + strings
+    }
+
+    /// Get the qualified function name, filename, and first line of the
+    /// definition (0 if unknown).
+    fn get_location(&self, id: FunctionId) -> (&str, &str, u32) {
         if id == FunctionId::UNKNOWN {
-            return ("UNKNOWN", "UNKNOWN DUE TO BUG");
+            return ("UNKNOWN", "UNKNOWN DUE TO BUG", 0);
         }
         let location = &self.functions[id.0 as usize];
-        (&location.function_name, &location.filename)
+        (&location.qualname, &location.filename, location.first_line)
+    }
+}
+
+/// How a function is shown in reports, e.g. "Example.method, defined at line
+/// 10".
+fn describe_function(qualname: &str, first_line: u32) -> Cow<'_, str> {
+    if first_line == 0 {
+        Cow::Borrowed(qualname)
+    } else {
+        Cow::Owned(format!("{}, defined at line {}", qualname, first_line))
     }
 }
 
@@ -79,11 +162,11 @@ pub struct CallSiteId {
     /// The function + filename. We use IDs for performance reasons (faster hashing).
     function: FunctionId,
     /// Line number within the _file_, 1-indexed.
-    line_number: u16,
+    line_number: u32,
 }
 
 impl CallSiteId {
-    pub fn new(function: FunctionId, line_number: u16) -> CallSiteId {
+    pub fn new(function: FunctionId, line_number: u32) -> CallSiteId {
         CallSiteId {
             function,
             line_number,
@@ -96,43 +179,79 @@ impl CallSiteId {
 #[derivative(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Callstack {
     calls: Vec<CallSiteId>,
+    // The CallstackId of each prefix of calls, as far as it's known:
+    // prefix_ids[i] is the id of calls[..=i].
+    #[derivative(Hash = "ignore", PartialEq = "ignore")]
+    prefix_ids: Vec<CallstackId>,
     #[derivative(Hash = "ignore", PartialEq = "ignore")]
-    cached_callstack_id: Option<(u16, CallstackId)>, // first bit is line number
+    cached_callstack_id: Option<(u32, CallstackId)>, // first bit is line number
+    // The generation the cached ids belong to, see AllocationTracker::compact():
+    #[derivative(Hash = "ignore", PartialEq = "ignore")]
+    generation: u32,
 }
 
 impl Callstack {
     pub fn new() -> Callstack {
         Callstack {
             calls: Vec::new(),
+            prefix_ids: Vec::new(),
             cached_callstack_id: None,
+            generation: 0,
         }
     }
 
     pub fn from_vec(vec: Vec<CallSiteId>) -> Self {
         Self {
             calls: vec,
+            prefix_ids: Vec::new(),
             cached_callstack_id: None,
+            generation: 0,
         }
     }
 
-    pub fn start_call(&mut self, parent_line_number: u16, callsite_id: CallSiteId) {
-        if parent_line_number != 0 {
-            if let Some(mut call) = self.calls.last_mut() {
-                call.line_number = parent_line_number;
+    /// Change the line number of the innermost call, if there is one.
+    fn set_line_number(&mut self, line_number: u32) {
+        if let Some(call) = self.calls.last_mut() {
+            if call.line_number != line_number {
+                call.line_number = line_number;
+                // The innermost prefix is now a different callstack:
+                self.prefix_ids.truncate(self.calls.len() - 1);
             }
         }
+    }
+
+    /// Forget cached callstack ids if they're from a different generation,
+    /// i.e. the ids were renumbered since they were calculated.
+    pub fn set_generation(&mut self, generation: u32) {
+        if generation != self.generation {
+            self.generation = generation;
+            self.prefix_ids.clear();
+            self.cached_callstack_id = None;
+        }
+    }
+
+    pub fn start_call(&mut self, parent_line_number: u32, callsite_id: CallSiteId) {
+        if parent_line_number != 0 {
+            self.set_line_number(parent_line_number);
+        }
         self.calls.push(callsite_id);
         self.cached_callstack_id = None;
     }
 
     pub fn finish_call(&mut self) {
         self.calls.pop();
+        self.prefix_ids.truncate(self.calls.len());
         self.cached_callstack_id = None;
     }
 
-    pub fn id_for_new_allocation<F>(&mut self, line_number: u16, get_callstack_id: F) -> CallstackId
+    /// Get the id of the callstack with the innermost call at the given line.
+    ///
+    /// `get_child_id` gets the id of a parent callstack extended with one more
+    /// call. Since the ids of prefixes are remembered, it's typically only
+    /// called for calls added since the last time.
+    pub fn id_for_new_allocation<F>(&mut self, line_number: u32, mut get_child_id: F) -> CallstackId
     where
-        F: FnOnce(&Callstack) -> CallstackId,
+        F: FnMut(CallstackId, CallSiteId) -> CallstackId,
     {
         // If same line number as last callstack, and we have cached callstack
         // ID, reuse it:
@@ -144,13 +263,19 @@ impl Callstack {
 
         // Set the new line number:
         if line_number != 0 {
-            if let Some(call) = self.calls.last_mut() {
-                call.line_number = line_number;
-            }
+            self.set_line_number(line_number);
         }
 
         // Calculate callstack ID, cache it, and then return it;
-        let callstack_id = get_callstack_id(self);
+        let mut callstack_id = self
+            .prefix_ids
+            .last()
+            .copied()
+            .unwrap_or(CallstackInterner::ROOT);
+        for call in &self.calls[self.prefix_ids.len()..] {
+            callstack_id = get_child_id(callstack_id, *call);
+            self.prefix_ids.push(callstack_id);
+        }
         self.cached_callstack_id = Some((line_number, callstack_id));
         callstack_id
     }
@@ -159,122 +284,174 @@ impl Callstack {
         &self,
         to_be_post_processed: bool,
         functions: &FunctionLocations,
+        frame_filter: &dyn FrameFilter,
+        source_provider: &dyn SourceProvider,
         separator: &'static str,
     ) -> String {
         if self.calls.is_empty() {
             return "[No Python stack]".to_string();
         }
-        let calls: Vec<(CallSiteId, (&str, &str))> = self
+        let calls: Vec<(CallSiteId, (&str, &str, u32))> = self
             .calls
             .iter()
-            .map(|id| (*id, functions.get_function_and_filename(id.function)))
+            .map(|id| (*id, functions.get_location(id.function)))
             .collect();
-        let skip_prefix = if cfg!(feature = "fil4prod") {
-            0
-        } else {
-            // Due to implementation details we have some runpy() frames at the
-            // start; remove them.
-            runpy_prefix_length(calls.iter())
-        };
-        calls
+        let calls = frame_filter.filter(calls, |(_, (_, filename, _))| filename);
+        frame_filter
+            .fold(calls, |(id, _)| id.function)
             .into_iter()
-            .skip(skip_prefix)
-            .map(|(id, (function, filename))| {
-                if to_be_post_processed {
-                    // Get Python code.
-                    let code = crate::python::get_source_line(filename, id.line_number)
-                        .unwrap_or_else(|_| "".to_string());
-                    // Leading whitespace is dropped by SVG, so we'd like to
-                    // replace it with non-breaking space. However, inferno
-                    // trims whitespace
-                    // (https://github.com/jonhoo/inferno/blob/de3f7d94d4718bfee57655c1fddd4d2714bc78d0/src/flamegraph/merge.rs#L126)
-                    // and that causes incorrect "unsorted lines" errors
-                    // which I can't be bothered to fix right now, so for
-                    // now do hack where we shove in some other character
-                    // that can be fixed in post-processing.
-                    let code = code.replace(" ", "\u{12e4}");
-                    // Semicolons are used as separator in the flamegraph
-                    // input format, so need to replace them with some other
-                    // character. We use "full-width semicolon", and then
-                    // replace it back in post-processing.
-                    let code = code.replace(";", "\u{ff1b}");
-                    // The \u{2800} is to ensure we don't have empty lines,
-                    // and that whitespace doesn't get trimmed from start;
-                    // we'll get rid of this in post-processing.
-                    format!(
-                        "{filename}:{line} ({function});\u{2800}{code}",
-                        filename = filename,
-                        line = id.line_number,
-                        function = function,
-                        code = &code.trim_end(),
-                    )
-                } else {
-                    format!(
-                        "{filename}:{line} ({function})",
-                        filename = filename,
-                        line = id.line_number,
-                        function = function,
-                    )
-                }
-            })
+            .map(
+                |((id, (qualname, filename, first_line)), recursion_depth)| {
+                    let function = describe_function(qualname, first_line);
+                    let recursion = match recursion_depth {
+                        Some(depth) => format!(" [recursion depth {}]", depth),
+                        None => String::new(),
+                    };
+                    let display_filename = frame_filter.display_filename(filename);
+                    if to_be_post_processed {
+                        // Get Python code.
+                        let code = source_provider.get_line(filename, id.line_number);
+                        // Leading whitespace is dropped by SVG, so we'd like to
+                        // replace it with non-breaking space. However, inferno
+                        // trims whitespace
+                        // (https://github.com/jonhoo/inferno/blob/de3f7d94d4718bfee57655c1fddd4d2714bc78d0/src/flamegraph/merge.rs#L126)
+                        // and that causes incorrect "unsorted lines" errors
+                        // which I can't be bothered to fix right now, so for
+                        // now do hack where we shove in some other character
+                        // that can be fixed in post-processing.
+                        let code = code.replace(" ", "\u{12e4}");
+                        // Semicolons are used as separator in the flamegraph
+                        // input format, so need to replace them with some other
+                        // character. We use "full-width semicolon", and then
+                        // replace it back in post-processing.
+                        let code = code.replace(";", "\u{ff1b}");
+                        // The \u{2800} is to ensure we don't have empty lines,
+                        // and that whitespace doesn't get trimmed from start;
+                        // we'll get rid of this in post-processing.
+                        format!(
+                            "{filename}:{line} ({function}){recursion};\u{2800}{code}",
+                            filename = display_filename,
+                            line = id.line_number,
+                            function = function,
+                            recursion = recursion,
+                            code = &code.trim_end(),
+                        )
+                    } else {
+                        format!(
+                            "{filename}:{line} ({function}){recursion}",
+                            filename = display_filename,
+                            line = id.line_number,
+                            function = function,
+                            recursion = recursion,
+                        )
+                    }
+                },
+            )
             .join(separator)
     }
 }
 
-fn runpy_prefix_length(calls: std::slice::Iter<(CallSiteId, (&str, &str))>) -> usize {
-    let mut length = 0;
-    let runpy_path = get_runpy_path();
-    for (_, (_, filename)) in calls {
-        if *filename == runpy_path {
-            length += 1;
-        } else {
-            return length;
-        }
-    }
-    0
-}
-
 pub type CallstackId = u32;
 
-/// Maps Functions to integer identifiers used in CallStacks.
+/// Maps Callstacks to integer identifiers.
+///
+/// Callstacks are stored as a prefix tree: each id other than ROOT (the empty
+/// callstack) is its parent callstack's id plus one more call. Extending a
+/// callstack is therefore a single lookup, and memory usage is proportional
+/// to the number of unique frames rather than the total depth of all
+/// callstacks.
 pub struct CallstackInterner {
-    max_id: CallstackId,
-    callstack_to_id: HashMap<Callstack, u32, ARandomState>,
+    // Indexed by CallstackId, the parent and innermost call. The entry for
+    // ROOT is a placeholder.
+    nodes: Vec<(CallstackId, CallSiteId)>,
+    children: HashMap<(CallstackId, CallSiteId), CallstackId, ARandomState>,
 }
 
-impl<'a> CallstackInterner {
+impl CallstackInterner {
+    /// The id of the empty callstack.
+    pub const ROOT: CallstackId = 0;
+
     pub fn new() -> Self {
         CallstackInterner {
-            max_id: 0,
-            callstack_to_id: new_hashmap(),
+            nodes: vec![(Self::ROOT, CallSiteId::new(FunctionId::UNKNOWN, 0))],
+            children: new_hashmap(),
         }
     }
 
-    /// Add a (possibly) new Function, returning its ID.
-    pub fn get_or_insert_id<F: FnOnce() -> ()>(
+    /// Get the id of the parent callstack extended with the given call,
+    /// adding it if necessary.
+    pub fn get_or_insert_child<F: FnOnce()>(
         &mut self,
-        callstack: Cow<Callstack>,
+        parent: CallstackId,
+        call: CallSiteId,
         call_on_new: F,
     ) -> CallstackId {
-        let max_id = &mut self.max_id;
-        if let Some(result) = self.callstack_to_id.get(&*callstack) {
-            *result
-        } else {
-            let new_id = *max_id;
-            *max_id += 1;
-            self.callstack_to_id.insert(callstack.into_owned(), new_id);
+        let nodes = &mut self.nodes;
+        *self.children.entry((parent, call)).or_insert_with(|| {
+            nodes.push((parent, call));
             call_on_new();
-            new_id
+            (nodes.len() - 1) as CallstackId
+        })
+    }
+
+    /// Get the id of a callstack, adding it (and its prefixes) if necessary.
+    /// `call_on_new` is called for every id added.
+    pub fn get_or_insert_id<F: FnMut()>(
+        &mut self,
+        callstack: &Callstack,
+        mut call_on_new: F,
+    ) -> CallstackId {
+        callstack.calls.iter().fold(Self::ROOT, |parent, call| {
+            self.get_or_insert_child(parent, *call, &mut call_on_new)
+        })
+    }
+
+    /// Reconstruct the Callstack for an id.
+    fn get_callstack(&self, mut callstack_id: CallstackId) -> Callstack {
+        let mut calls = vec![];
+        while callstack_id != Self::ROOT {
+            let (parent, call) = self.nodes[callstack_id as usize];
+            calls.push(call);
+            callstack_id = parent;
+        }
+        calls.reverse();
+        Callstack::from_vec(calls)
+    }
+
+    /// Drop callstacks and renumber the rest, where `new_ids[id]` is the new id
+    /// of a callstack that is kept. The parents of kept callstacks must be
+    /// kept too, and the new ids must be in the same order as the old ones.
+    fn compact(&mut self, new_ids: &[Option<CallstackId>]) {
+        let old_nodes = std::mem::take(&mut self.nodes);
+        self.children = new_hashmap();
+        for (old_id, (parent, call)) in old_nodes.into_iter().enumerate() {
+            if let Some(new_id) = new_ids[old_id] {
+                let parent = new_ids[parent as usize].expect("parent callstack was dropped");
+                debug_assert_eq!(new_id as usize, self.nodes.len());
+                if new_id != Self::ROOT {
+                    self.children.insert((parent, call), new_id);
+                }
+                self.nodes.push((parent, call));
+            }
         }
+        self.nodes.shrink_to_fit();
+        self.children.shrink_to_fit();
+    }
+
+    /// Approximate bytes of memory used to store the callstacks.
+    fn memory_usage(&self) -> usize {
+        let node_size = size_of::<(CallstackId, CallSiteId)>();
+        self.nodes.capacity() * node_size
+            + self.children.capacity() * (node_size + size_of::<CallstackId>())
     }
 
-    /// Get map from IDs to Callstacks.
-    fn get_reverse_map(&self) -> HashMap<CallstackId, &Callstack, ARandomState> {
-        let mut result = new_hashmap();
-        for (call_site, csid) in self.callstack_to_id.iter() {
-            result.insert(*csid, call_site);
+    /// The innermost call of a callstack, if it's not empty.
+    fn innermost_call(&self, callstack_id: CallstackId) -> Option<CallSiteId> {
+        if callstack_id == Self::ROOT {
+            None
+        } else {
+            Some(self.nodes[callstack_id as usize].1)
         }
-        result
     }
 }
 
@@ -358,12 +535,178 @@ fn filter_to_useful_callstacks(
         .collect()
 }
 
+/// Kinds of mmap() that are tracked separately from heap memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmapCategory {
+    /// Mappings of files, e.g. from numpy.memmap() or Arrow IPC files.
+    FileBacked = 0,
+    /// Anonymous mappings with MAP_SHARED, e.g. from mmap.mmap(-1, size).
+    SharedAnonymous = 1,
+}
+
+impl MmapCategory {
+    pub const ALL: [MmapCategory; 2] = [MmapCategory::FileBacked, MmapCategory::SharedAnonymous];
+
+    /// Convert from the value passed in by the C code.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(MmapCategory::FileBacked),
+            1 => Some(MmapCategory::SharedAnonymous),
+            _ => None,
+        }
+    }
+
+    /// Short name, used in filenames and configuration.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MmapCategory::FileBacked => "file-backed",
+            MmapCategory::SharedAnonymous => "shared",
+        }
+    }
+
+    /// Parse a comma-separated list of category names, e.g. the value of the
+    /// FIL_MMAP_IN_PEAK environment variable.
+    pub fn parse_list(value: &str) -> Result<Vec<MmapCategory>, String> {
+        value
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Self::ALL
+                    .iter()
+                    .find(|category| category.name() == name)
+                    .copied()
+                    .ok_or_else(|| format!("unknown mmap() category {}", name))
+            })
+            .collect()
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            MmapCategory::FileBacked => "Peak File-Backed mmap() Usage",
+            MmapCategory::SharedAnonymous => "Peak Shared Anonymous mmap() Usage",
+        }
+    }
+}
+
+/// Memory usage for one MmapCategory.
+struct MmapCategoryUsage {
+    mappings: RangeMap<CallstackId>,
+    // Whether these mappings also count towards the main memory usage and peak:
+    in_peak: bool,
+    current_memory_usage: ImVector<usize>, // Map CallstackId -> total memory usage
+    peak_memory_usage: ImVector<usize>,    // Map CallstackId -> total memory usage
+    current_allocated_bytes: usize,
+    peak_allocated_bytes: usize,
+}
+
+impl MmapCategoryUsage {
+    fn new(in_peak: bool) -> Self {
+        MmapCategoryUsage {
+            mappings: RangeMap::new(),
+            in_peak,
+            current_memory_usage: ImVector::new(),
+            peak_memory_usage: ImVector::new(),
+            current_allocated_bytes: 0,
+            peak_allocated_bytes: 0,
+        }
+    }
+
+    fn check_if_new_peak(&mut self) {
+        if self.current_allocated_bytes > self.peak_allocated_bytes {
+            self.peak_allocated_bytes = self.current_allocated_bytes;
+            self.peak_memory_usage
+                .clone_from(&self.current_memory_usage);
+        }
+    }
+
+    fn reset_peak(&mut self) {
+        self.peak_allocated_bytes = self.current_allocated_bytes;
+        self.peak_memory_usage
+            .clone_from(&self.current_memory_usage);
+    }
+
+    fn add_usage(&mut self, callstack_id: CallstackId, size: usize) {
+        let index = callstack_id as usize;
+        while self.current_memory_usage.len() <= index {
+            self.current_memory_usage.push_back(0);
+        }
+        self.current_memory_usage[index] += size;
+        self.current_allocated_bytes += size;
+    }
+
+    fn remove_usage(&mut self, callstack_id: CallstackId, size: usize) {
+        self.current_memory_usage[callstack_id as usize] -= size;
+        self.current_allocated_bytes -= size;
+    }
+
+    /// Add a new range. Any overlapping ranges should have been removed first.
+    fn add(&mut self, address: usize, size: usize, callstack_id: CallstackId) {
+        self.mappings.add(address, size, callstack_id);
+        self.add_usage(callstack_id, size);
+    }
+
+    /// Remove the given range, returning what was removed.
+    fn remove(&mut self, address: usize, size: usize) -> Vec<(CallstackId, usize)> {
+        self.check_if_new_peak();
+        let removed = self.mappings.remove(address, size);
+        for (callstack_id, size) in removed.iter() {
+            self.remove_usage(*callstack_id, *size);
+        }
+        removed
+    }
+
+    /// Move and/or resize a range, returning what was removed and added.
+    fn remap(
+        &mut self,
+        old_address: usize,
+        old_size: usize,
+        new_address: usize,
+        new_size: usize,
+    ) -> Remapped<CallstackId> {
+        self.check_if_new_peak();
+        let (removed, added) = self
+            .mappings
+            .remap(old_address, old_size, new_address, new_size);
+        for (callstack_id, size) in removed.iter() {
+            self.remove_usage(*callstack_id, *size);
+        }
+        for (callstack_id, size) in added.iter() {
+            self.add_usage(*callstack_id, *size);
+        }
+        (removed, added)
+    }
+}
+
+/// One of the callstacks using the most memory, see
+/// AllocationTracker::top_callstacks().
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopCallstack {
+    /// Rendered in the same format as the .prof files.
+    pub callstack: String,
+    pub bytes: usize,
+    /// Sizes of the malloc()/calloc() allocations using the memory.
+    pub live_sizes: SizeHistogram,
+    /// Sizes of all malloc()/calloc() allocations so far.
+    pub all_sizes: SizeHistogram,
+}
+
+/// Which memory usage to dump.
+#[derive(Clone, Copy)]
+enum Usage {
+    Current,
+    Peak,
+    PeakMmap(MmapCategory),
+}
+
 /// The main data structure tracking everything.
 pub struct AllocationTracker {
     // malloc()/calloc():
     current_allocations: HashMap<usize, Allocation, ARandomState>,
     // anonymous mmap(), i.e. not file backed:
     current_anon_mmaps: RangeMap<CallstackId>,
+    // file-backed and shared mmap(), indexed by MmapCategory:
+    mmap_categories: [MmapCategoryUsage; 2],
 
     // Map FunctionIds to function + filename strings, so we can store the
     // former and save memory.
@@ -378,14 +721,35 @@ pub struct AllocationTracker {
     peak_memory_usage: ImVector<usize>,    // Map CallstackId -> total memory usage
     current_allocated_bytes: usize,
     peak_allocated_bytes: usize,
+
+    // Sizes of malloc()/calloc() allocations, indexed by CallstackId. mmap()s
+    // aren't included, since they can be partially unmapped.
+    allocation_histograms: Vec<SizeHistogram>, // every allocation so far
+    current_histograms: ImVector<SizeHistogram>, // live allocations
+    peak_histograms: ImVector<SizeHistogram>,  // live allocations at peak
+
     // Default directory to write out data lacking other info:
     default_path: String,
 
     // Allocations that somehow disappeared. Not relevant for sampling profiler.
     missing_allocated_bytes: usize,
+    // The same, by callstack: how many allocations, and how many bytes.
+    missing_allocations: HashMap<CallstackId, (usize, usize), ARandomState>,
 
     // free()/realloc() of unknown address. Not relevant for sampling profiler.
+    // Only counted in debug mode.
     failed_deallocations: usize,
+    // The same, by native backtrace, if one was captured.
+    untracked_frees: HashMap<Option<BacktraceHash>, usize, ARandomState>,
+
+    // Callstacks whose memory usage would have gone below zero, which
+    // indicates a bug: how many times, and by how many bytes in total.
+    underflows: HashMap<CallstackId, (usize, usize), ARandomState>,
+
+    // Where the source code shown in callstacks comes from, and which frames
+    // are shown:
+    source_provider: Box<dyn SourceProvider>,
+    frame_filter: Box<dyn FrameFilter>,
 }
 
 impl<'a> AllocationTracker {
@@ -393,33 +757,56 @@ impl<'a> AllocationTracker {
         AllocationTracker {
             current_allocations: new_hashmap(),
             current_anon_mmaps: RangeMap::new(),
+            // Shared anonymous mappings use RAM, and were historically
+            // counted, so they count towards the peak by default:
+            mmap_categories: [MmapCategoryUsage::new(false), MmapCategoryUsage::new(true)],
             interner: CallstackInterner::new(),
             functions: FunctionLocations::new(),
-            current_memory_usage: ImVector::new(),
+            // The interner starts out with the empty callstack:
+            current_memory_usage: im::vector![0],
             peak_memory_usage: ImVector::new(),
             current_allocated_bytes: 0,
             peak_allocated_bytes: 0,
+            allocation_histograms: Vec::new(),
+            current_histograms: ImVector::new(),
+            peak_histograms: ImVector::new(),
             missing_allocated_bytes: 0,
+            missing_allocations: new_hashmap(),
             failed_deallocations: 0,
+            untracked_frees: new_hashmap(),
+            underflows: new_hashmap(),
+            source_provider: Box::new(SourceCache::new()),
+            frame_filter: Box::new(FrameRules::from_env()),
             default_path,
         }
     }
 
     /// Print a traceback for the given CallstackId.
     pub fn print_traceback(&self, message: &'static str, callstack_id: CallstackId) {
-        let id_to_callstack = self.interner.get_reverse_map();
-        let callstack = id_to_callstack[&callstack_id];
         eprintln!("=fil-profile= {}", message);
-        eprintln!(
-            "=| {}",
-            callstack.as_string(false, &self.functions, "\n=| ")
-        );
+        eprintln!("=| {}", self.format_traceback(callstack_id, "\n=| "));
+    }
+
+    /// Render the callstack for the given CallstackId, one frame per line.
+    pub fn format_traceback(&self, callstack_id: CallstackId, separator: &'static str) -> String {
+        self.interner.get_callstack(callstack_id).as_string(
+            false,
+            &self.functions,
+            &*self.frame_filter,
+            &*self.source_provider,
+            separator,
+        )
     }
 
     pub fn get_current_allocated_bytes(&self) -> usize {
         self.current_allocated_bytes
     }
 
+    pub fn get_peak_allocated_bytes(&mut self) -> usize {
+        self.check_if_new_peak();
+        self.peak_allocated_bytes
+    }
+
     pub fn get_allocation_size(&self, address: usize) -> usize {
         if let Some(allocation) = self.current_allocations.get(&address) {
             allocation.size()
@@ -434,7 +821,27 @@ impl<'a> AllocationTracker {
             self.peak_allocated_bytes = self.current_allocated_bytes;
             self.peak_memory_usage
                 .clone_from(&self.current_memory_usage);
+            self.peak_histograms.clone_from(&self.current_histograms);
+        }
+    }
+
+    /// Count a new malloc()/calloc() allocation in the size histograms.
+    fn add_to_histograms(&mut self, callstack_id: CallstackId, size: usize) {
+        let index = callstack_id as usize;
+        if self.allocation_histograms.len() <= index {
+            self.allocation_histograms
+                .resize(index + 1, SizeHistogram::default());
+        }
+        self.allocation_histograms[index].add(size);
+        while self.current_histograms.len() <= index {
+            self.current_histograms.push_back(SizeHistogram::default());
         }
+        self.current_histograms[index].add(size);
+    }
+
+    /// A malloc()/calloc() allocation is no longer live.
+    fn remove_from_histograms(&mut self, callstack_id: CallstackId, size: usize) {
+        self.current_histograms[callstack_id as usize].remove(size);
     }
 
     fn add_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
@@ -444,18 +851,31 @@ impl<'a> AllocationTracker {
     }
 
     fn remove_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
-        self.current_allocated_bytes -= bytes;
+        self.current_allocated_bytes = self.current_allocated_bytes.saturating_sub(bytes);
         let index = callstack_id as usize;
-        // TODO what if goes below zero? add a check I guess, in case of bugs.
-        self.current_memory_usage[index] -= bytes;
+        let usage = &mut self.current_memory_usage[index];
+        if *usage < bytes {
+            // A bug somewhere; rather than crashing, record it so it shows up
+            // in the diagnostics:
+            let (times, missing) = self.underflows.entry(callstack_id).or_insert((0, 0));
+            *times += 1;
+            *missing += bytes - *usage;
+        }
+        *usage = usage.saturating_sub(bytes);
     }
 
     pub fn get_callstack_id(&mut self, callstack: &Callstack) -> CallstackId {
         let current_memory_usage = &mut self.current_memory_usage;
         self.interner
-            .get_or_insert_id(Cow::Borrowed(callstack), || {
-                current_memory_usage.push_back(0)
-            })
+            .get_or_insert_id(callstack, || current_memory_usage.push_back(0))
+    }
+
+    /// Get the id of the parent callstack extended with the given call; see
+    /// Callstack::id_for_new_allocation().
+    pub fn get_child_callstack_id(&mut self, parent: CallstackId, call: CallSiteId) -> CallstackId {
+        let current_memory_usage = &mut self.current_memory_usage;
+        self.interner
+            .get_or_insert_child(parent, call, || current_memory_usage.push_back(0))
     }
 
     /// Add a new allocation based off the current callstack.
@@ -474,8 +894,15 @@ impl<'a> AllocationTracker {
                 // outcomes. Apparently also happening on Linux, hope to fix this
                 // soon (https://github.com/pythonspeed/filprofiler/issues/149).
                 self.missing_allocated_bytes += previous.size();
+                let (count, bytes) = self
+                    .missing_allocations
+                    .entry(previous.callstack_id)
+                    .or_insert((0, 0));
+                *count += 1;
+                *bytes += previous.size();
                 // Cleanup the previous allocation, since we never saw its free():
                 self.remove_memory_usage(previous.callstack_id, previous.size());
+                self.remove_from_histograms(previous.callstack_id, previous.size());
                 if *crate::util::DEBUG_MODE {
                     self.print_traceback(
                         "The allocation from this traceback disappeared:",
@@ -493,27 +920,39 @@ impl<'a> AllocationTracker {
             }
         }
         self.add_memory_usage(callstack_id, compressed_size as usize);
+        self.add_to_histograms(callstack_id, compressed_size);
     }
 
     /// Free an existing allocation, return how much was removed, if any.
     pub fn free_allocation(&mut self, address: usize) -> Option<usize> {
+        self.free_allocation_with_backtrace(address, || None)
+    }
+
+    /// Free an existing allocation, return how much was removed, if any. If
+    /// the allocation isn't tracked, the free is counted in the diagnostics,
+    /// attributed to the native backtrace returned by `capture_backtrace`.
+    /// That's only called for untracked frees, since capturing is slow.
+    #[cfg_attr(feature = "fil4prod", allow(unused_variables))]
+    pub fn free_allocation_with_backtrace(
+        &mut self,
+        address: usize,
+        capture_backtrace: impl FnOnce() -> Option<BacktraceHash>,
+    ) -> Option<usize> {
         // Before we reduce memory, let's check if we've previously hit a peak:
         self.check_if_new_peak();
 
         if let Some(removed) = self.current_allocations.remove(&address) {
             self.remove_memory_usage(removed.callstack_id, removed.size());
+            self.remove_from_histograms(removed.callstack_id, removed.size());
             Some(removed.size())
         } else {
             // This allocation doesn't exist; often this will be something
             // allocated before Fil tracking was started, but it might also be a
             // bug.
             #[cfg(not(feature = "fil4prod"))]
-            if *crate::util::DEBUG_MODE {
+            {
                 self.failed_deallocations += 1;
-                eprintln!(
-                    "=fil-profile= Your program attempted to free an allocation at an address we don't know about:"
-                );
-                eprintln!("=| {:?}", backtrace::Backtrace::new());
+                *self.untracked_frees.entry(capture_backtrace()).or_insert(0) += 1;
             }
             None
         }
@@ -521,10 +960,48 @@ impl<'a> AllocationTracker {
 
     /// Add a new anonymous mmap() based of the current callstack.
     pub fn add_anon_mmap(&mut self, address: usize, size: usize, callstack_id: CallstackId) {
+        // With MAP_FIXED a new mapping can replace existing ones:
+        self.free_anon_mmap(address, size);
         self.current_anon_mmaps.add(address, size, callstack_id);
         self.add_memory_usage(callstack_id, size);
     }
 
+    /// Add a new file-backed or shared mmap() based of the current callstack.
+    pub fn add_categorized_mmap(
+        &mut self,
+        category: MmapCategory,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) {
+        // With MAP_FIXED a new mapping can replace existing ones:
+        self.free_anon_mmap(address, size);
+        let usage = &mut self.mmap_categories[category as usize];
+        usage.add(address, size, callstack_id);
+        if usage.in_peak {
+            self.add_memory_usage(callstack_id, size);
+        }
+    }
+
+    /// Set whether the given category of mmap() counts towards the main
+    /// memory usage and peak. Should be called before any tracking happens.
+    pub fn set_mmap_in_peak(&mut self, category: MmapCategory, in_peak: bool) {
+        self.mmap_categories[category as usize].in_peak = in_peak;
+    }
+
+    /// Whether the given category of mmap() counts towards the main memory
+    /// usage and peak.
+    pub fn is_mmap_in_peak(&self, category: MmapCategory) -> bool {
+        self.mmap_categories[category as usize].in_peak
+    }
+
+    /// Currently mapped bytes for the given category of mmap().
+    pub fn get_mmap_allocated_bytes(&self, category: MmapCategory) -> usize {
+        self.mmap_categories[category as usize].current_allocated_bytes
+    }
+
+    /// Remove an mmap()ed range. munmap() doesn't tell us what kind of mapping
+    /// it was, so this applies to all categories of mmap().
     pub fn free_anon_mmap(&mut self, address: usize, size: usize) {
         // Before we reduce memory, let's check if we've previously hit a peak:
         self.check_if_new_peak();
@@ -532,84 +1009,464 @@ impl<'a> AllocationTracker {
         for (callstack_id, removed) in self.current_anon_mmaps.remove(address, size) {
             self.remove_memory_usage(callstack_id, removed);
         }
+        for category in MmapCategory::ALL.iter() {
+            let usage = &mut self.mmap_categories[*category as usize];
+            let in_peak = usage.in_peak;
+            for (callstack_id, removed) in usage.remove(address, size) {
+                if in_peak {
+                    self.remove_memory_usage(callstack_id, removed);
+                }
+            }
+        }
+    }
+
+    /// Track mremap(): whatever was mapped at the old range moves to the new
+    /// range, keeping its callstack, and is truncated or extended to the new
+    /// size.
+    pub fn remap_mmap(
+        &mut self,
+        old_address: usize,
+        old_size: usize,
+        new_address: usize,
+        new_size: usize,
+    ) {
+        // Before we potentially reduce memory, let's check if we've previously
+        // hit a peak:
+        self.check_if_new_peak();
+        let (removed, added) =
+            self.current_anon_mmaps
+                .remap(old_address, old_size, new_address, new_size);
+        for (callstack_id, size) in removed {
+            self.remove_memory_usage(callstack_id, size);
+        }
+        for (callstack_id, size) in added {
+            self.add_memory_usage(callstack_id, size);
+        }
+        for category in MmapCategory::ALL.iter() {
+            let usage = &mut self.mmap_categories[*category as usize];
+            let in_peak = usage.in_peak;
+            let (removed, added) = usage.remap(old_address, old_size, new_address, new_size);
+            if in_peak {
+                for (callstack_id, size) in removed {
+                    self.remove_memory_usage(callstack_id, size);
+                }
+                for (callstack_id, size) in added {
+                    self.add_memory_usage(callstack_id, size);
+                }
+            }
+        }
     }
 
     /// Combine Callstacks and make them human-readable. Duplicate callstacks
     /// have their allocated memory summed.
-    fn combine_callstacks(
-        &mut self,
-        // If false, will do the current allocations:
-        peak: bool,
-    ) -> HashMap<CallstackId, usize, ARandomState> {
+    fn combine_callstacks(&mut self, usage: Usage) -> HashMap<CallstackId, usize, ARandomState> {
         // First, make sure peaks are correct:
         self.check_if_new_peak();
 
-        // Would be nice to validate if data is consistent. However, there are
-        // edge cases that make it slightly inconsistent (e.g. see the
-        // unexpected code path in add_allocation() above), and blowing up
-        // without giving the user their data just because of a small
-        // inconsistency doesn't seem ideal. Perhaps if validate() merely
-        // reported problems, or maybe validate() should only be enabled in
-        // development mode.
-        //self.validate();
+        // Consistency isn't checked here: there are edge cases that make the
+        // data slightly inconsistent (e.g. see the unexpected code path in
+        // add_allocation() above), and blowing up without giving the user
+        // their data just because of a small inconsistency isn't ideal.
+        // Instead, problems are reported by write_diagnostics().
 
         // We get a LOT of tiny allocations. To reduce overhead of creating
         // flamegraph (which currently loads EVERYTHING into memory), just do
         // the top 99% of allocations.
-        if peak {
-            filter_to_useful_callstacks(&self.peak_memory_usage)
-        } else {
-            filter_to_useful_callstacks(&self.current_memory_usage)
+        match usage {
+            Usage::Current => filter_to_useful_callstacks(&self.current_memory_usage),
+            Usage::Peak => filter_to_useful_callstacks(&self.peak_memory_usage),
+            Usage::PeakMmap(category) => {
+                let usage = &mut self.mmap_categories[category as usize];
+                usage.check_if_new_peak();
+                filter_to_useful_callstacks(&usage.peak_memory_usage)
+            }
         }
     }
 
     /// Dump all callstacks in peak memory usage to various files describing the
-    /// memory usage.
+    /// memory usage, and tell the user how much memory the profiler itself is
+    /// using. This is for the final report; snapshots use dump_snapshot_to().
     pub fn dump_peak_to_flamegraph(&mut self, path: &str) {
-        self.dump_to_flamegraph(path, true, "peak-memory", "Peak Tracked Memory Usage", true);
+        self.write_peak(path);
+        print_overhead(&self.overhead());
+    }
+
+    /// Write all the files describing peak memory usage.
+    fn write_peak(&mut self, path: &str) {
+        self.dump_to_flamegraph(
+            path,
+            Usage::Peak,
+            "peak-memory",
+            "Peak Tracked Memory Usage",
+            true,
+        );
+        // File-backed and shared mmap()s get their own reports, if there were
+        // any:
+        for category in MmapCategory::ALL.iter() {
+            let usage = &mut self.mmap_categories[*category as usize];
+            usage.check_if_new_peak();
+            if usage.peak_allocated_bytes > 0 {
+                self.dump_to_flamegraph(
+                    path,
+                    Usage::PeakMmap(*category),
+                    &format!("peak-mmap-{}", category.name()),
+                    category.title(),
+                    true,
+                );
+            }
+        }
+        self.write_size_histograms(path);
+        self.write_package_report(path);
+        self.write_viewer(path);
+        self.write_overhead(path);
+        self.write_diagnostics(path);
+    }
+
+    /// Attribute peak memory usage to packages; see packages.rs.
+    pub fn package_report(&mut self, mapper: &PackageMapper) -> PackageReport {
+        self.check_if_new_peak();
+        let mut report = PackageReport::new();
+        for (callstack_id, bytes) in self.peak_memory_usage.iter().enumerate() {
+            if *bytes == 0 {
+                continue;
+            }
+            let packages = self
+                .interner
+                .get_callstack(callstack_id as CallstackId)
+                .calls
+                .iter()
+                .map(|call| {
+                    let (_, filename, _) = self.functions.get_location(call.function);
+                    mapper.package(filename)
+                })
+                .collect();
+            report.add(packages, *bytes);
+        }
+        report
+    }
+
+    /// Write a table and a flamegraph of peak memory usage by package to the
+    /// given directory.
+    fn write_package_report(&mut self, path: &str) {
+        let report = self.package_report(crate::packages::mapper());
+        let directory_path = Path::new(path);
+        let table_path = directory_path.join("peak-packages.txt");
+        if let Err(e) = fs::write(&table_path, report.table()) {
+            eprintln!("=fil-profile= Error writing package report: {}", e);
+            return;
+        }
+        let raw_path = directory_path
+            .join("peak-packages.prof")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let svg_path = directory_path
+            .join("peak-packages.svg")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let result = write_lines(report.flamegraph_lines(), &raw_path).and_then(|_| {
+            write_flamegraph(
+                &raw_path,
+                &svg_path,
+                report.total_bytes(),
+                false,
+                "Peak Tracked Memory Usage by Package",
+                false,
+            )
+        });
+        if let Err(e) = result {
+            eprintln!("=fil-profile= Error writing package flamegraph: {}", e);
+        }
+    }
+
+    /// Merge all callstacks into a tree for the interactive viewer, with peak
+    /// and current memory usage and the number of malloc()/calloc()
+    /// allocations so far; see viewer.rs.
+    pub fn call_tree(&mut self) -> CallTree {
+        self.check_if_new_peak();
+        let frame_filter = &*self.frame_filter;
+        let mut tree = CallTree::new();
+        for (callstack_id, current) in self.current_memory_usage.iter().enumerate() {
+            let values = [
+                self.peak_memory_usage
+                    .get(callstack_id)
+                    .copied()
+                    .unwrap_or(0),
+                *current,
+                self.allocation_histograms
+                    .get(callstack_id)
+                    .map(|histogram| histogram.count())
+                    .unwrap_or(0),
+            ];
+            if values == [0, 0, 0] {
+                continue;
+            }
+            let calls: Vec<(CallSiteId, &str)> = self
+                .interner
+                .get_callstack(callstack_id as CallstackId)
+                .calls
+                .iter()
+                .map(|id| (*id, self.functions.get_location(id.function).1))
+                .collect();
+            let calls = frame_filter.filter(calls, |(_, filename)| filename);
+            let frames = frame_filter
+                .fold(calls, |(id, _)| id.function)
+                .into_iter()
+                .map(|((id, _), recursion_depth)| Frame {
+                    function: id.function,
+                    line_number: id.line_number,
+                    recursion_depth,
+                })
+                .collect();
+            tree.add(frames, values);
+        }
+        tree
+    }
+
+    /// Write the interactive viewer to the given directory.
+    fn write_viewer(&mut self, path: &str) {
+        let tree = self.call_tree();
+        let functions = &self.functions;
+        let frame_filter = &*self.frame_filter;
+        let source_provider = &*self.source_provider;
+        let json = tree.to_json(
+            |function| {
+                let (qualname, filename, first_line) = functions.get_location(function);
+                (
+                    frame_filter.display_filename(filename).to_string(),
+                    describe_function(qualname, first_line).into_owned(),
+                )
+            },
+            |function, line_number| {
+                let (_, filename, _) = functions.get_location(function);
+                source_provider.get_line(filename, line_number)
+            },
+        );
+        let html = crate::viewer::render_html("Fil Memory Profile", &json);
+        let viewer_path = Path::new(path).join("viewer.html");
+        if let Err(e) = fs::write(&viewer_path, html) {
+            eprintln!("=fil-profile= Error writing viewer: {}", e);
+        }
+    }
+
+    /// Write the size histograms as JSON to a file in the given directory.
+    fn write_size_histograms(&mut self, path: &str) {
+        let histograms_path = Path::new(path).join("size-histograms.json");
+        if let Err(e) = fs::write(&histograms_path, self.size_histograms_json()) {
+            eprintln!("=fil-profile= Error writing size histograms: {}", e);
+        }
+    }
+
+    /// Dump all callstacks in current memory usage to various files
+    /// describing the memory usage.
+    pub fn dump_current_to_flamegraph(&mut self, path: &str) {
+        self.dump_to_flamegraph(
+            path,
+            Usage::Current,
+            "current-memory",
+            "Current Tracked Memory Usage",
+            true,
+        );
+    }
+
+    /// Dump both peak and current memory usage into the given directory,
+    /// without otherwise changing any state, so tracking can continue.
+    pub fn dump_snapshot_to(&mut self, directory: &Path) {
+        let path = directory.to_str().unwrap().to_string();
+        self.write_peak(&path);
+        self.dump_current_to_flamegraph(&path);
+    }
+
+    /// Dump both peak and current memory usage into a new timestamped
+    /// subdirectory of the default path. Returns the subdirectory.
+    pub fn dump_snapshot(&mut self) -> PathBuf {
+        let directory =
+            create_unique_directory(Path::new(&self.default_path), &crate::util::timestamp_now());
+        self.dump_snapshot_to(&directory);
+        directory
+    }
+
+    /// Return the largest N callstacks, either currently or at peak. Largest
+    /// comes first.
+    pub fn top_callstacks(&mut self, peak: bool, count: usize) -> Vec<TopCallstack> {
+        self.check_if_new_peak();
+        let (usage, histograms) = if peak {
+            (&self.peak_memory_usage, &self.peak_histograms)
+        } else {
+            (&self.current_memory_usage, &self.current_histograms)
+        };
+        let top: Vec<(CallstackId, usize)> = usage
+            .iter()
+            .enumerate()
+            .filter(|(_, size)| **size > 0)
+            .sorted_by(|a, b| Ord::cmp(b.1, a.1))
+            .take(count)
+            .map(|(i, size)| (i as CallstackId, *size))
+            .collect();
+        top.into_iter()
+            .map(|(callstack_id, bytes)| TopCallstack {
+                callstack: self.interner.get_callstack(callstack_id).as_string(
+                    false,
+                    &self.functions,
+                    &*self.frame_filter,
+                    &*self.source_provider,
+                    ";",
+                ),
+                bytes,
+                live_sizes: histograms
+                    .get(callstack_id as usize)
+                    .cloned()
+                    .unwrap_or_default(),
+                all_sizes: self
+                    .allocation_histograms
+                    .get(callstack_id as usize)
+                    .cloned()
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Render the size histograms of all callstacks that allocated with
+    /// malloc()/calloc() as JSON, both for all allocations so far and for
+    /// those that were live at peak. Callstacks using the most memory at peak
+    /// come first.
+    pub fn size_histograms_json(&mut self) -> String {
+        self.check_if_new_peak();
+        let peak_bytes = |callstack_id: usize| {
+            self.peak_memory_usage
+                .get(callstack_id)
+                .copied()
+                .unwrap_or(0)
+        };
+        let callstacks: Vec<String> = self
+            .allocation_histograms
+            .iter()
+            .enumerate()
+            .filter(|(_, histogram)| !histogram.is_empty())
+            .sorted_by_key(|(callstack_id, histogram)| {
+                (
+                    std::cmp::Reverse(peak_bytes(*callstack_id)),
+                    std::cmp::Reverse(histogram.count()),
+                )
+            })
+            .map(|(callstack_id, histogram)| {
+                let callstack = self
+                    .interner
+                    .get_callstack(callstack_id as CallstackId)
+                    .as_string(false, &self.functions, &*self.frame_filter, &*self.source_provider, ";");
+                format!(
+                    "{{\"callstack\": {}, \"peak_bytes\": {}, \"allocations\": {}, \"live_at_peak\": {}}}",
+                    json_string(&callstack),
+                    peak_bytes(callstack_id),
+                    histogram.to_json(),
+                    self.peak_histograms
+                        .get(callstack_id)
+                        .cloned()
+                        .unwrap_or_default()
+                        .to_json(),
+                )
+            })
+            .collect();
+        format!("{{\"callstacks\": [\n{}\n]}}\n", callstacks.join(",\n"))
+    }
+
+    /// Forget the previous peak, making current memory usage the new peak.
+    pub fn reset_peak(&mut self) {
+        self.peak_allocated_bytes = self.current_allocated_bytes;
+        self.peak_memory_usage
+            .clone_from(&self.current_memory_usage);
+        self.peak_histograms.clone_from(&self.current_histograms);
+        for usage in self.mmap_categories.iter_mut() {
+            usage.reset_peak();
+        }
+    }
+
+    pub fn get_missing_allocated_bytes(&self) -> usize {
+        self.missing_allocated_bytes
+    }
+
+    pub fn get_failed_deallocations(&self) -> usize {
+        self.failed_deallocations
+    }
+
+    /// Return the N functions with the most memory currently allocated while
+    /// they were the innermost Python frame, as (filename, qualified function
+    /// name, first line of definition, bytes). Largest comes first.
+    ///
+    /// Unlike top_callstacks(), this doesn't need the Python interpreter.
+    pub fn top_functions(&self, count: usize) -> Vec<(&str, &str, u32, usize)> {
+        let mut by_function: HashMap<FunctionId, usize, ARandomState> = new_hashmap();
+        for (callstack_id, size) in self.current_memory_usage.iter().enumerate() {
+            if *size == 0 {
+                continue;
+            }
+            let function = self
+                .interner
+                .innermost_call(callstack_id as CallstackId)
+                .map(|call| call.function)
+                .unwrap_or(FunctionId::UNKNOWN);
+            *by_function.entry(function).or_insert(0) += size;
+        }
+        by_function
+            .into_iter()
+            .sorted_by(|a, b| Ord::cmp(&b.1, &a.1))
+            .take(count)
+            .map(|(function, size)| {
+                let (qualname, filename, first_line) = self.functions.get_location(function);
+                (filename, qualname, first_line, size)
+            })
+            .collect()
     }
 
     fn to_lines(
         &mut self,
-        peak: bool,
+        usage: Usage,
         to_be_post_processed: bool,
     ) -> impl Iterator<Item = String> + '_ {
-        let by_call = self.combine_callstacks(peak).into_iter();
-        let id_to_callstack = self.interner.get_reverse_map();
-        let functions = &self.functions;
-        by_call.map(move |(callstack_id, size)| {
-            format!(
-                "{} {}",
-                id_to_callstack.get(&callstack_id).unwrap().as_string(
-                    to_be_post_processed,
-                    functions,
-                    ";"
-                ),
-                size,
-            )
-        })
+        let by_call = self.combine_callstacks(usage);
+        // Hiding frames and folding recursion can render different
+        // callstacks the same way, so their sizes get added up:
+        let mut by_rendered: HashMap<String, usize, ARandomState> = new_hashmap();
+        for (callstack_id, size) in by_call {
+            let rendered = self.interner.get_callstack(callstack_id).as_string(
+                to_be_post_processed,
+                &self.functions,
+                &*self.frame_filter,
+                &*self.source_provider,
+                ";",
+            );
+            *by_rendered.entry(rendered).or_insert(0) += size;
+        }
+        by_rendered
+            .into_iter()
+            .map(|(rendered, size)| format!("{} {}", rendered, size))
     }
 
     fn dump_to_flamegraph(
         &mut self,
         path: &str,
-        peak: bool,
+        usage: Usage,
         base_filename: &str,
         title: &str,
         to_be_post_processed: bool,
     ) {
-        // Print warning if we're missing allocations.
+        let allocated_bytes = match usage {
+            Usage::Current => self.current_allocated_bytes,
+            Usage::Peak => self.peak_allocated_bytes,
+            Usage::PeakMmap(category) => {
+                self.mmap_categories[category as usize].peak_allocated_bytes
+            }
+        };
+
+        // Print warning if we're missing allocations. The mmap() reports come
+        // along with the main peak report, so no need to repeat it for them.
         #[cfg(not(feature = "fil4prod"))]
-        {
-            let allocated_bytes = if peak {
-                self.peak_allocated_bytes
-            } else {
-                self.current_allocated_bytes
-            };
+        if !matches!(usage, Usage::PeakMmap(_)) {
             if self.missing_allocated_bytes > 0 {
                 eprintln!("=fil-profile= WARNING: {:.2}% ({} bytes) of tracked memory somehow disappeared. If this is a small percentage you can just ignore this warning, since the missing allocations won't impact the profiling results. If the % is high, please run `export FIL_DEBUG=1` to get more output', re-run Fil on your script, and then file a bug report at https://github.com/pythonspeed/filprofiler/issues/new", self.missing_allocated_bytes as f64 * 100.0 / allocated_bytes as f64, self.missing_allocated_bytes);
             }
-            if self.failed_deallocations > 0 {
+            if *crate::util::DEBUG_MODE && self.failed_deallocations > 0 {
                 eprintln!("=fil-profile= WARNING: Encountered {} deallocations of untracked allocations. A certain number are expected in normal operation, of allocations created before Fil started tracking, and even more if you're using the Fil API to turn tracking on and off.", self.failed_deallocations);
             }
         }
@@ -638,7 +1495,7 @@ impl<'a> AllocationTracker {
 
         // Always write .prof file without source code, for use by tests and
         // other automated post-processing.
-        if let Err(e) = write_lines(self.to_lines(peak, false), &raw_path_without_source_code) {
+        if let Err(e) = write_lines(self.to_lines(usage, false), &raw_path_without_source_code) {
             eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
             return;
         }
@@ -646,7 +1503,7 @@ impl<'a> AllocationTracker {
         // Optionally write version with source code for SVGs, if we're using
         // source code.
         if to_be_post_processed {
-            if let Err(e) = write_lines(self.to_lines(peak, true), &raw_path_with_source_code) {
+            if let Err(e) = write_lines(self.to_lines(usage, true), &raw_path_with_source_code) {
                 eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
                 return;
             }
@@ -667,7 +1524,7 @@ impl<'a> AllocationTracker {
         match write_flamegraph(
             &raw_path,
             &svg_path,
-            self.peak_allocated_bytes,
+            allocated_bytes,
             false,
             title,
             to_be_post_processed,
@@ -690,7 +1547,7 @@ impl<'a> AllocationTracker {
         match write_flamegraph(
             &raw_path,
             &svg_path,
-            self.peak_allocated_bytes,
+            allocated_bytes,
             true,
             title,
             to_be_post_processed,
@@ -713,9 +1570,13 @@ impl<'a> AllocationTracker {
 
     /// Clear memory we won't be needing anymore, since we're going to exit out.
     pub fn oom_break_glass(&mut self) {
+        // Before clearing, so users can tell if the profiler contributed:
+        print_overhead(&self.overhead());
         self.current_allocations.clear();
         self.current_allocations.shrink_to_fit();
         self.peak_memory_usage.clear();
+        self.allocation_histograms = Vec::new();
+        self.peak_histograms.clear();
     }
 
     /// Dump information about where we are.
@@ -726,7 +1587,7 @@ impl<'a> AllocationTracker {
         let default_path = self.default_path.clone();
         self.dump_to_flamegraph(
             &default_path,
-            false,
+            Usage::Current,
             "out-of-memory",
             "Current allocations at out-of-memory time",
             false,
@@ -736,40 +1597,385 @@ impl<'a> AllocationTracker {
         }
     }
 
-    /// Validate internal state is in a good state. This won't pass until
+    /// Check whether internal state is consistent, returning any problems
+    /// found. There will be spurious problems with the peak until
     /// check_if_new_peak() is called.
-    fn validate(&self) {
-        assert!(self.peak_allocated_bytes >= self.current_allocated_bytes);
+    pub fn check_consistency(&self) -> Vec<Inconsistency> {
+        let mut problems = Vec::new();
+        let mut check_sum = |what, total, sum| {
+            if total != sum {
+                problems.push(Inconsistency::SumMismatch { what, total, sum });
+            }
+        };
         let current_allocations = self.current_anon_mmaps.size()
+            + self
+                .mmap_categories
+                .iter()
+                .filter(|usage| usage.in_peak)
+                .map(|usage| usage.mappings.size())
+                .sum::<usize>()
             + self
                 .current_allocations
                 .iter()
                 .map(|(_, alloc)| alloc.size())
                 .sum::<usize>();
-        assert!(
-            current_allocations == self.current_allocated_bytes,
-            "{} != {}",
+        check_sum(
+            "Current allocated bytes (vs. live allocations)",
+            self.current_allocated_bytes,
             current_allocations,
-            self.current_allocated_bytes
         );
-        assert!(self.current_memory_usage.iter().sum::<usize>() == self.current_allocated_bytes);
-        assert!(self.peak_memory_usage.iter().sum::<usize>() == self.peak_allocated_bytes);
+        check_sum(
+            "Current allocated bytes (vs. callstacks)",
+            self.current_allocated_bytes,
+            self.current_memory_usage.iter().sum(),
+        );
+        check_sum(
+            "Peak allocated bytes (vs. callstacks)",
+            self.peak_allocated_bytes,
+            self.peak_memory_usage.iter().sum(),
+        );
+        check_sum(
+            "Number of live allocations (vs. size histograms)",
+            self.current_allocations.len(),
+            self.current_histograms
+                .iter()
+                .map(|histogram| histogram.count())
+                .sum(),
+        );
+        for category in MmapCategory::ALL.iter() {
+            let usage = &self.mmap_categories[*category as usize];
+            check_sum(
+                "Current mmap() bytes of a category (vs. mappings)",
+                usage.current_allocated_bytes,
+                usage.mappings.size(),
+            );
+            check_sum(
+                "Current mmap() bytes of a category (vs. callstacks)",
+                usage.current_allocated_bytes,
+                usage.current_memory_usage.iter().sum(),
+            );
+        }
+        if self.peak_allocated_bytes < self.current_allocated_bytes {
+            problems.push(Inconsistency::PeakBelowCurrent {
+                what: "allocated bytes",
+                peak: self.peak_allocated_bytes,
+                current: self.current_allocated_bytes,
+            });
+        }
+        for (callstack_id, (times, bytes)) in self.underflows.iter().sorted() {
+            problems.push(Inconsistency::Underflow {
+                callstack: self.format_traceback(*callstack_id, ";"),
+                times: *times,
+                bytes: *bytes,
+            });
+        }
+        problems
+    }
+
+    /// Assert internal state is consistent, for tests. This won't pass until
+    /// check_if_new_peak() is called.
+    fn validate(&self) {
+        assert_eq!(self.check_consistency(), vec![]);
+    }
+
+    /// Gather inconsistencies, missing allocations and untracked frees.
+    pub fn diagnostics(&mut self) -> Diagnostics {
+        self.check_if_new_peak();
+        let missing_allocations = self
+            .missing_allocations
+            .iter()
+            .sorted_by_key(|(_, (_, bytes))| std::cmp::Reverse(*bytes))
+            .map(|(callstack_id, (count, bytes))| MissingAllocations {
+                callstack: self.format_traceback(*callstack_id, ";"),
+                count: *count,
+                bytes: *bytes,
+            })
+            .collect();
+        let untracked_frees = self
+            .untracked_frees
+            .iter()
+            .sorted_by_key(|(_, count)| std::cmp::Reverse(**count))
+            .map(|(backtrace_hash, count)| UntrackedFrees {
+                backtrace_hash: *backtrace_hash,
+                backtrace: backtrace_hash.and_then(describe_native_backtrace),
+                count: *count,
+            })
+            .collect();
+        Diagnostics {
+            inconsistencies: self.check_consistency(),
+            missing_allocated_bytes: self.missing_allocated_bytes,
+            failed_deallocations: self.failed_deallocations,
+            missing_allocations,
+            untracked_frees,
+        }
+    }
+
+    /// If internal state is inconsistent, allocations went missing, or
+    /// FIL_DEBUG is set, write a diagnostics file to the given directory,
+    /// and a flamegraph of the missing allocations if there are any.
+    fn write_diagnostics(&mut self, path: &str) {
+        let diagnostics = self.diagnostics();
+        if diagnostics.inconsistencies.is_empty()
+            && diagnostics.missing_allocations.is_empty()
+            && !*crate::util::DEBUG_MODE
+        {
+            return;
+        }
+        let directory_path = Path::new(path);
+        let diagnostics_path = directory_path.join("diagnostics.json");
+        if let Err(e) = fs::write(&diagnostics_path, diagnostics.to_json()) {
+            eprintln!("=fil-profile= Error writing diagnostics: {}", e);
+            return;
+        }
+        if !diagnostics.inconsistencies.is_empty() {
+            eprintln!("=fil-profile= WARNING: Fil's internal bookkeeping is inconsistent, so the profiling results may be slightly off. Please file a bug report at https://github.com/pythonspeed/filprofiler/issues/new and attach {}", diagnostics_path.display());
+        }
+        if diagnostics.missing_allocations.is_empty() {
+            return;
+        }
+        let raw_path = directory_path
+            .join("missing-allocations.prof")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let svg_path = directory_path
+            .join("missing-allocations.svg")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let lines = diagnostics
+            .missing_allocations
+            .iter()
+            .map(|missing| format!("{} {}", missing.callstack, missing.bytes));
+        let result = write_lines(lines, &raw_path).and_then(|_| {
+            write_flamegraph(
+                &raw_path,
+                &svg_path,
+                self.missing_allocated_bytes,
+                false,
+                "Allocations That Disappeared Without Being Freed",
+                false,
+            )
+        });
+        match result {
+            Ok(_) => eprintln!(
+                "=fil-profile= Wrote details of missing allocations to {} and {}",
+                diagnostics_path.display(),
+                svg_path
+            ),
+            Err(e) => eprintln!("=fil-profile= Error writing missing allocations: {}", e),
+        }
+    }
+
+    /// Drop callstacks that aren't using any memory, either currently or at
+    /// peak, and renumber the rest. Returns how many bytes of the profiler's
+    /// own memory were freed.
+    ///
+    /// This invalidates callstack IDs cached elsewhere, e.g. by Callstack, so
+    /// callers must make sure those get recalculated; see eventbuffer.rs.
+    pub fn compact(&mut self) -> usize {
+        // Peak usage needs to be up-to-date before deciding what's unused:
+        self.check_if_new_peak();
+        for usage in self.mmap_categories.iter_mut() {
+            usage.check_if_new_peak();
+        }
+        let before = self.overhead().estimated_total();
+
+        let uses_memory =
+            |usage: &ImVector<usize>, id: usize| usage.get(id).is_some_and(|bytes| *bytes > 0);
+        let mut keep: Vec<bool> = (0..self.interner.nodes.len())
+            .map(|id| {
+                id == CallstackInterner::ROOT as usize
+                    || self.underflows.contains_key(&(id as CallstackId))
+                    || self.missing_allocations.contains_key(&(id as CallstackId))
+                    || uses_memory(&self.current_memory_usage, id)
+                    || uses_memory(&self.peak_memory_usage, id)
+                    || self.mmap_categories.iter().any(|usage| {
+                        uses_memory(&usage.current_memory_usage, id)
+                            || uses_memory(&usage.peak_memory_usage, id)
+                    })
+            })
+            .collect();
+        // Parents always have smaller ids than their children, so going
+        // backwards marks all ancestors of kept callstacks:
+        for id in (1..keep.len()).rev() {
+            if keep[id] {
+                keep[self.interner.nodes[id].0 as usize] = true;
+            }
+        }
+        let mut next_id = 0;
+        let new_ids: Vec<Option<CallstackId>> = keep
+            .into_iter()
+            .map(|kept| {
+                kept.then(|| {
+                    next_id += 1;
+                    next_id - 1
+                })
+            })
+            .collect();
+
+        self.interner.compact(&new_ids);
+        let new_id = |id: CallstackId| new_ids[id as usize].expect("callstack in use was dropped");
+        let compact_usage =
+            |usage: &ImVector<usize>| -> ImVector<usize> { retain_kept(usage.iter(), &new_ids) };
+        self.current_memory_usage = compact_usage(&self.current_memory_usage);
+        self.peak_memory_usage = compact_usage(&self.peak_memory_usage);
+        // Histograms of allocations by dropped callstacks are forgotten:
+        self.allocation_histograms = retain_kept(self.allocation_histograms.iter(), &new_ids);
+        self.current_histograms = retain_kept(self.current_histograms.iter(), &new_ids);
+        self.peak_histograms = retain_kept(self.peak_histograms.iter(), &new_ids);
+        for usage in self.mmap_categories.iter_mut() {
+            usage.current_memory_usage = compact_usage(&usage.current_memory_usage);
+            usage.peak_memory_usage = compact_usage(&usage.peak_memory_usage);
+            for callstack_id in usage.mappings.values_mut() {
+                *callstack_id = new_id(*callstack_id);
+            }
+        }
+        for allocation in self.current_allocations.values_mut() {
+            allocation.callstack_id = new_id(allocation.callstack_id);
+        }
+        for callstack_id in self.current_anon_mmaps.values_mut() {
+            *callstack_id = new_id(*callstack_id);
+        }
+        renumber_keys(&mut self.underflows, new_id);
+        renumber_keys(&mut self.missing_allocations, new_id);
+        before.saturating_sub(self.overhead().estimated_total())
+    }
+
+    /// Memory used by the tracker itself, as opposed to the program being
+    /// profiled.
+    pub fn overhead(&self) -> Overhead {
+        let usage_lengths = self.current_memory_usage.len()
+            + self.peak_memory_usage.len()
+            + self
+                .mmap_categories
+                .iter()
+                .map(|usage| usage.current_memory_usage.len() + usage.peak_memory_usage.len())
+                .sum::<usize>();
+        Overhead {
+            // Keys, values, plus a control byte per bucket:
+            allocations: self.current_allocations.capacity()
+                * (size_of::<usize>() + size_of::<Allocation>() + 1),
+            callstacks: self.interner.memory_usage(),
+            functions: self.functions.memory_usage(),
+            mmaps: self.current_anon_mmaps.memory_usage()
+                + self
+                    .mmap_categories
+                    .iter()
+                    .map(|usage| usage.mappings.memory_usage())
+                    .sum::<usize>(),
+            // Peak usage is a copy of current usage, which shares unchanged
+            // chunks, so this is an upper bound:
+            usage: usage_lengths * size_of::<usize>()
+                + self
+                    .allocation_histograms
+                    .iter()
+                    .chain(self.current_histograms.iter())
+                    .chain(self.peak_histograms.iter())
+                    .map(|histogram| size_of::<SizeHistogram>() + histogram.memory_usage())
+                    .sum::<usize>(),
+        }
+    }
+
+    /// Set where source code lines shown in callstacks come from. By default
+    /// only source files are read; see python::source_provider().
+    pub fn set_source_provider(&mut self, source_provider: Box<dyn SourceProvider>) {
+        self.source_provider = source_provider;
+    }
+
+    /// Set which frames to show in callstacks. By default the rules come from
+    /// environment variables, without anything Python-specific; see
+    /// python::frame_filter().
+    pub fn set_frame_filter(&mut self, frame_filter: Box<dyn FrameFilter>) {
+        self.frame_filter = frame_filter;
+    }
+
+    /// Write how much memory the profiler itself is using to a file in the
+    /// given directory.
+    fn write_overhead(&self, path: &str) {
+        let overhead_path = Path::new(path).join("fil-overhead.txt");
+        if let Err(e) = fs::write(&overhead_path, self.overhead().to_string()) {
+            eprintln!("=fil-profile= Error writing profiler overhead: {}", e);
+        }
+    }
+
+    /// Reset internal state in way that doesn't invalidate e.g. thread-local
+    /// caching of callstack ID.
+    pub fn reset(&mut self, default_path: String) {
+        self.current_allocations.clear();
+        self.current_anon_mmaps = RangeMap::new();
+        for usage in self.mmap_categories.iter_mut() {
+            *usage = MmapCategoryUsage::new(usage.in_peak);
+        }
+        for i in self.current_memory_usage.iter_mut() {
+            *i = 0;
+        }
+        self.peak_memory_usage = ImVector::new();
+        self.current_allocated_bytes = 0;
+        self.peak_allocated_bytes = 0;
+        self.allocation_histograms = Vec::new();
+        self.current_histograms = ImVector::new();
+        self.peak_histograms = ImVector::new();
+        self.underflows.clear();
+        self.missing_allocations.clear();
+        self.missing_allocated_bytes = 0;
+        self.untracked_frees.clear();
+        self.failed_deallocations = 0;
+        self.default_path = default_path;
+        self.validate();
+    }
+}
+
+/// Write the overhead report to stderr, with every line prefixed so it's clear
+/// where it came from.
+fn print_overhead(overhead: &Overhead) {
+    for line in overhead.to_string().lines() {
+        eprintln!("=fil-profile= {}", line);
     }
+}
+
+/// Replace CallstackId keys with their new ids after compaction.
+fn renumber_keys<V>(
+    map: &mut HashMap<CallstackId, V, ARandomState>,
+    new_id: impl Fn(CallstackId) -> CallstackId,
+) {
+    let mut renumbered = new_hashmap();
+    renumbered.extend(
+        map.drain()
+            .map(|(callstack_id, value)| (new_id(callstack_id), value)),
+    );
+    *map = renumbered;
+}
+
+/// Keep the values, indexed by CallstackId, of callstacks that weren't dropped
+/// by AllocationTracker::compact().
+fn retain_kept<'a, T: Clone + 'a, C: std::iter::FromIterator<T>>(
+    values: impl Iterator<Item = &'a T>,
+    new_ids: &[Option<CallstackId>],
+) -> C {
+    values
+        .zip(new_ids.iter())
+        .filter(|(_, new_id)| new_id.is_some())
+        .map(|(value, _)| value.clone())
+        .collect()
+}
 
-    /// Reset internal state in way that doesn't invalidate e.g. thread-local
-    /// caching of callstack ID.
-    pub fn reset(&mut self, default_path: String) {
-        self.current_allocations.clear();
-        self.current_anon_mmaps = RangeMap::new();
-        for i in self.current_memory_usage.iter_mut() {
-            *i = 0;
+/// Create a new directory with the given name, adding a "-2", "-3" etc.
+/// suffix if it already exists, e.g. when two snapshots are written in the
+/// same millisecond. Returns the directory.
+fn create_unique_directory(parent: &Path, name: &str) -> PathBuf {
+    let _ = fs::create_dir_all(parent);
+    let mut directory = parent.join(name);
+    let mut suffix = 2;
+    // Other errors get reported when writing to the directory:
+    while let Err(e) = fs::create_dir(&directory) {
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            break;
         }
-        self.peak_memory_usage = ImVector::new();
-        self.current_allocated_bytes = 0;
-        self.peak_allocated_bytes = 0;
-        self.default_path = default_path;
-        self.validate();
+        directory = parent.join(format!("{}-{}", name, suffix));
+        suffix += 1;
     }
+    directory
 }
 
 /// Write strings to disk, one line per string.
@@ -846,14 +2052,17 @@ fn write_flamegraph(
 #[cfg(test)]
 mod tests {
     use super::{
-        filter_to_useful_callstacks, Allocation, AllocationTracker, CallSiteId, Callstack,
-        CallstackInterner, FunctionId, FunctionLocations, HIGH_32BIT, MIB,
+        create_unique_directory, describe_function, filter_to_useful_callstacks, Allocation,
+        AllocationTracker, CallSiteId, Callstack, CallstackInterner, FunctionId, FunctionLocations,
+        Inconsistency, MissingAllocations, MmapCategory, Usage, HIGH_32BIT, MIB,
     };
+    use crate::framefilter::FrameRules;
+    use crate::packages::{Package, PackageMapper};
+    use crate::util::TempDir;
     use im;
     use itertools::Itertools;
     use proptest::prelude::*;
-    use std::borrow::Cow;
-    use std::collections::HashMap;
+    use std::sync::Arc;
 
     proptest! {
         // Allocation sizes smaller than 2 ** 31 are round-tripped.
@@ -906,7 +2115,8 @@ mod tests {
             free_indices in prop::collection::btree_set(0..10 as usize, 1..5)
         ) {
             let mut tracker = AllocationTracker::new(".".to_string());
-            let mut expected_memory_usage = im::vector![];
+            // The first entry is for the empty callstack:
+            let mut expected_memory_usage = im::vector![0];
             for i in 0..allocated_sizes.len() {
                 let mut cs = Callstack::new();
                 cs.start_call(0, CallSiteId::new(FunctionId::new(i as u32), 0));
@@ -923,7 +2133,7 @@ mod tests {
                 expected_sum -= expected_removed;
                 let removed = tracker.free_allocation(*i);
                 prop_assert_eq!(removed, Some(*expected_removed));
-                expected_memory_usage[*i] -= allocated_sizes.get(*i).unwrap();
+                expected_memory_usage[*i + 1] -= allocated_sizes.get(*i).unwrap();
                 prop_assert_eq!(tracker.current_allocated_bytes, expected_sum);
                 prop_assert_eq!(&tracker.current_memory_usage, &expected_memory_usage);
             }
@@ -940,7 +2150,8 @@ mod tests {
             free_indices in prop::collection::btree_set(0..10 as usize, 1..5)
         ) {
             let mut tracker = AllocationTracker::new(".".to_string());
-            let mut expected_memory_usage = im::vector![];
+            // The first entry is for the empty callstack:
+            let mut expected_memory_usage = im::vector![0];
             // Make sure addresses don't overlap:
             let addresses : Vec<usize> = (0..allocated_sizes.len()).map(|i| i * 10000).collect();
             for i in 0..allocated_sizes.len() {
@@ -957,7 +2168,7 @@ mod tests {
             for i in free_indices.iter() {
                 expected_sum -= allocated_sizes.get(*i).unwrap();
                 tracker.free_anon_mmap(addresses[*i], *allocated_sizes.get(*i).unwrap());
-                expected_memory_usage[*i] -= allocated_sizes.get(*i).unwrap();
+                expected_memory_usage[*i + 1] -= allocated_sizes.get(*i).unwrap();
                 prop_assert_eq!(tracker.current_allocated_bytes, expected_sum);
                 prop_assert_eq!(&tracker.current_memory_usage, &expected_memory_usage);
             }
@@ -995,6 +2206,27 @@ mod tests {
     fn untracked_allocation_removal() {
         let mut tracker = AllocationTracker::new("/tmp".to_string());
         assert_eq!(tracker.free_allocation(123), None);
+        assert_eq!(
+            tracker.free_allocation_with_backtrace(123, || Some(7)),
+            None
+        );
+        // Backtraces are only captured for untracked frees:
+        tracker.add_allocation(123, 10, 0);
+        assert_eq!(
+            tracker.free_allocation_with_backtrace(123, || panic!("tracked")),
+            Some(10)
+        );
+        let diagnostics = tracker.diagnostics();
+        assert_eq!(diagnostics.failed_deallocations, 2);
+        assert_eq!(
+            diagnostics
+                .untracked_frees
+                .iter()
+                .map(|frees| (frees.backtrace_hash, frees.count))
+                .sorted()
+                .collect::<Vec<_>>(),
+            vec![(None, 1), (Some(7), 1)]
+        );
     }
 
     #[test]
@@ -1026,6 +2258,63 @@ mod tests {
         );
     }
 
+    #[test]
+    fn line_numbers_above_16_bits() {
+        // Line numbers don't affect the size of tracked allocations:
+        assert_eq!(std::mem::size_of::<Allocation>(), 8);
+
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid1 = tracker
+            .functions
+            .add_function("a.py".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b.py".to_string(), "bf".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid1, 1));
+        cs.start_call(70_000, CallSiteId::new(fid2, 99_999));
+        let id = cs.id_for_new_allocation(100_000, |parent, call| {
+            tracker.get_child_callstack_id(parent, call)
+        });
+        tracker.add_allocation(1, 123, id);
+        let lines: Vec<String> = tracker.to_lines(Usage::Peak, false).collect();
+        assert_eq!(lines, vec!["a.py:70000 (af);b.py:100000 (bf) 123"]);
+    }
+
+    #[test]
+    fn qualified_names_and_definition_lines() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid1 = tracker.functions.add_function_definition(
+            "a.py".to_string(),
+            "Example.method".to_string(),
+            10,
+        );
+        // Same name defined elsewhere in the file is a different function:
+        let fid2 = tracker.functions.add_function_definition(
+            "a.py".to_string(),
+            "Example.method".to_string(),
+            20,
+        );
+        assert_ne!(fid1, fid2);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid1, 12));
+        cs.start_call(0, CallSiteId::new(fid2, 21));
+        let id = tracker.get_callstack_id(&cs);
+        tracker.add_allocation(1, 123, id);
+        let lines: Vec<String> = tracker.to_lines(Usage::Peak, false).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "a.py:12 (Example.method, defined at line 10);\
+                 a.py:21 (Example.method, defined at line 20) 123"
+            ]
+        );
+        assert_eq!(
+            tracker.top_functions(1),
+            vec![("a.py", "Example.method", 20, 123)]
+        );
+    }
+
     #[test]
     fn callstackinterner_notices_duplicates() {
         let fid1 = FunctionId::new(1u32);
@@ -1042,23 +2331,25 @@ mod tests {
         let mut interner = CallstackInterner::new();
 
         let mut new = false;
-        let id1 = interner.get_or_insert_id(Cow::Borrowed(&cs1), || new = true);
+        let id1 = interner.get_or_insert_id(&cs1, || new = true);
         assert!(new);
 
         new = false;
-        let id1b = interner.get_or_insert_id(Cow::Borrowed(&cs1b), || new = true);
+        let id1b = interner.get_or_insert_id(&cs1b, || new = true);
         assert!(!new);
 
         new = false;
-        let id2 = interner.get_or_insert_id(Cow::Borrowed(&cs2), || new = true);
+        let id2 = interner.get_or_insert_id(&cs2, || new = true);
         assert!(new);
 
+        // The empty callstack always exists:
         new = false;
-        let id3 = interner.get_or_insert_id(Cow::Borrowed(&cs3), || new = true);
-        assert!(new);
+        let id3 = interner.get_or_insert_id(&cs3, || new = true);
+        assert!(!new);
+        assert_eq!(id3, CallstackInterner::ROOT);
 
         new = false;
-        let id3b = interner.get_or_insert_id(Cow::Borrowed(&cs3b), || new = true);
+        let id3b = interner.get_or_insert_id(&cs3b, || new = true);
         assert!(!new);
 
         assert_eq!(id1, id1b);
@@ -1066,11 +2357,9 @@ mod tests {
         assert_ne!(id1, id3);
         assert_ne!(id2, id3);
         assert_eq!(id3, id3b);
-        let mut expected = HashMap::default();
-        expected.insert(id1, &cs1);
-        expected.insert(id2, &cs2);
-        expected.insert(id3, &cs3);
-        assert_eq!(interner.get_reverse_map(), expected);
+        assert_eq!(interner.get_callstack(id1), cs1);
+        assert_eq!(interner.get_callstack(id2), cs2);
+        assert_eq!(interner.get_callstack(id3), cs3);
     }
 
     #[test]
@@ -1078,44 +2367,53 @@ mod tests {
         let mut interner = CallstackInterner::new();
 
         let mut cs1 = Callstack::new();
-        let id0 =
-            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id0b =
-            cs1.id_for_new_allocation(0, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id0 = cs1.id_for_new_allocation(0, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
+        let id0b = cs1.id_for_new_allocation(0, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
         assert_eq!(id0, id0b);
 
         let fid1 = FunctionId::new(1u32);
 
         cs1.start_call(0, CallSiteId::new(fid1, 2));
-        let id1 =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id2 =
-            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
-        let id1b =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id1 = cs1.id_for_new_allocation(1, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
+        let id2 = cs1.id_for_new_allocation(2, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
+        let id1b = cs1.id_for_new_allocation(1, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
         assert_eq!(id1, id1b);
         assert_ne!(id2, id0);
         assert_ne!(id2, id1);
 
         cs1.start_call(3, CallSiteId::new(fid1, 2));
-        let id3 =
-            cs1.id_for_new_allocation(4, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id3 = cs1.id_for_new_allocation(4, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
         assert_ne!(id3, id0);
         assert_ne!(id3, id1);
         assert_ne!(id3, id2);
 
         cs1.finish_call();
-        let id2b =
-            cs1.id_for_new_allocation(2, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id2b = cs1.id_for_new_allocation(2, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
         assert_eq!(id2, id2b);
-        let id1c =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id1c = cs1.id_for_new_allocation(1, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
         assert_eq!(id1, id1c);
 
         // Check for cache invalidation in start_call:
         cs1.start_call(1, CallSiteId::new(fid1, 1));
-        let id4 =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id4 = cs1.id_for_new_allocation(1, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
         assert_ne!(id4, id0);
         assert_ne!(id4, id1);
         assert_ne!(id4, id2);
@@ -1123,11 +2421,153 @@ mod tests {
 
         // Check for cache invalidation in finish_call:
         cs1.finish_call();
-        let id1d =
-            cs1.id_for_new_allocation(1, |cs| interner.get_or_insert_id(Cow::Borrowed(&cs), || ()));
+        let id1d = cs1.id_for_new_allocation(1, |parent, call| {
+            interner.get_or_insert_child(parent, call, || ())
+        });
         assert_eq!(id1, id1d);
     }
 
+    #[test]
+    fn callstacks_share_prefixes() {
+        let fid1 = FunctionId::new(1u32);
+        let fid2 = FunctionId::new(2u32);
+        let mut interner = CallstackInterner::new();
+
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid1, 1));
+        cs.start_call(0, CallSiteId::new(fid2, 2));
+        cs.start_call(0, CallSiteId::new(fid1, 3));
+        let mut lookups = 0;
+        let deep_id = cs.id_for_new_allocation(0, |parent, call| {
+            lookups += 1;
+            interner.get_or_insert_child(parent, call, || ())
+        });
+        assert_eq!(lookups, 3);
+        assert_eq!(interner.get_or_insert_id(&cs, || ()), deep_id);
+        assert_eq!(interner.get_callstack(deep_id), cs);
+
+        // Returning and calling another function only looks up the new frame,
+        // and only adds one node:
+        let nodes = interner.nodes.len();
+        cs.finish_call();
+        cs.start_call(0, CallSiteId::new(fid2, 4));
+        lookups = 0;
+        let sibling_id = cs.id_for_new_allocation(0, |parent, call| {
+            lookups += 1;
+            interner.get_or_insert_child(parent, call, || ())
+        });
+        assert_eq!(lookups, 1);
+        assert_eq!(interner.nodes.len(), nodes + 1);
+        assert_eq!(interner.get_callstack(sibling_id), cs);
+
+        // Changing the line number of the innermost frame only looks that
+        // frame up again:
+        lookups = 0;
+        let other_line_id = cs.id_for_new_allocation(5, |parent, call| {
+            lookups += 1;
+            interner.get_or_insert_child(parent, call, || ())
+        });
+        assert_eq!(lookups, 1);
+        assert_ne!(other_line_id, sibling_id);
+        assert_eq!(interner.get_callstack(other_line_id), cs);
+        assert_eq!(
+            interner.innermost_call(other_line_id),
+            Some(CallSiteId::new(fid2, 5))
+        );
+        assert_eq!(interner.innermost_call(CallstackInterner::ROOT), None);
+    }
+
+    #[test]
+    fn new_generation_invalidates_cached_ids() {
+        let fid1 = FunctionId::new(1u32);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid1, 1));
+        cs.start_call(0, CallSiteId::new(fid1, 2));
+        let mut lookups = 0;
+        let mut get_id = |cs: &mut Callstack| {
+            cs.id_for_new_allocation(3, |parent, _| {
+                lookups += 1;
+                parent + 1
+            })
+        };
+        assert_eq!(get_id(&mut cs), 2);
+        cs.set_generation(0);
+        assert_eq!(get_id(&mut cs), 2);
+        // A new generation means everything gets looked up again:
+        cs.set_generation(1);
+        assert_eq!(get_id(&mut cs), 2);
+        assert_eq!(lookups, 4);
+    }
+
+    #[test]
+    fn compact_drops_unused_callstacks() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid1 = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b".to_string(), "bf".to_string());
+        let callstack = |calls: &[(FunctionId, u32)]| {
+            let mut cs = Callstack::new();
+            for (fid, line) in calls {
+                cs.start_call(0, CallSiteId::new(*fid, *line));
+            }
+            cs
+        };
+        let unused = callstack(&[(fid1, 1), (fid2, 2)]);
+        let peak_only = callstack(&[(fid1, 1), (fid2, 3)]);
+        let current = callstack(&[(fid2, 4), (fid1, 5), (fid2, 6)]);
+        let mmapped = callstack(&[(fid1, 7)]);
+        let categorized = callstack(&[(fid2, 8)]);
+        let unused_id = tracker.get_callstack_id(&unused);
+        let peak_only_id = tracker.get_callstack_id(&peak_only);
+        let current_id = tracker.get_callstack_id(&current);
+        let mmapped_id = tracker.get_callstack_id(&mmapped);
+        let categorized_id = tracker.get_callstack_id(&categorized);
+        assert_eq!(tracker.interner.nodes.len(), 9);
+
+        tracker.add_allocation(1, 100, unused_id);
+        tracker.free_allocation(1);
+        tracker.add_allocation(2, 1000, peak_only_id);
+        tracker.free_allocation(2);
+        tracker.add_allocation(3, 50, current_id);
+        tracker.add_anon_mmap(4096, 20, mmapped_id);
+        tracker.add_categorized_mmap(MmapCategory::FileBacked, 8192, 30, categorized_id);
+        let peak = tracker.top_callstacks(true, 10);
+        let current_usage = tracker.top_callstacks(false, 10);
+
+        assert!(tracker.compact() > 0);
+        // The unused callstack's innermost call is dropped, but its parent is
+        // also peak_only's parent so it's kept:
+        assert_eq!(tracker.interner.nodes.len(), 8);
+        tracker.validate();
+        assert_eq!(tracker.top_callstacks(true, 10), peak);
+        assert_eq!(tracker.top_callstacks(false, 10), current_usage);
+        assert_eq!(
+            tracker.get_mmap_allocated_bytes(MmapCategory::FileBacked),
+            30
+        );
+
+        // Callstacks get the new ids, and remaining memory is attributed to
+        // them:
+        let new_current_id = tracker.get_callstack_id(&current);
+        let new_mmapped_id = tracker.get_callstack_id(&mmapped);
+        assert_eq!(tracker.interner.nodes.len(), 8);
+        assert_eq!(tracker.free_allocation(3), Some(50));
+        assert_eq!(tracker.current_memory_usage[new_current_id as usize], 0);
+        tracker.free_anon_mmap(4096, 20);
+        assert_eq!(tracker.current_memory_usage[new_mmapped_id as usize], 0);
+        tracker.validate();
+
+        // Once there's no usage at all, only the empty callstack is left:
+        tracker.reset(".".to_string());
+        tracker.compact();
+        assert_eq!(tracker.interner.nodes.len(), 1);
+        assert_eq!(tracker.current_memory_usage, im::vector![0]);
+        assert_eq!(tracker.get_callstack_id(&unused), 2);
+    }
+
     #[test]
     fn peak_allocations_only_updated_on_new_peaks() {
         let fid1 = FunctionId::new(1u32);
@@ -1144,7 +2584,7 @@ mod tests {
         tracker.add_allocation(1, 1000, cs1_id);
         tracker.check_if_new_peak();
         // Peak should now match current allocations:
-        assert_eq!(tracker.current_memory_usage, im::vector![1000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 1000]);
         assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
         assert_eq!(tracker.peak_allocated_bytes, 1000);
         let previous_peak = tracker.peak_memory_usage.clone();
@@ -1152,13 +2592,13 @@ mod tests {
         // Free the allocation:
         tracker.free_allocation(1);
         assert_eq!(tracker.current_allocated_bytes, 0);
-        assert_eq!(tracker.current_memory_usage, im::vector![0]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 0]);
         assert_eq!(previous_peak, tracker.peak_memory_usage);
         assert_eq!(tracker.peak_allocated_bytes, 1000);
 
         // Add allocation, still less than 1000:
         tracker.add_allocation(3, 123, cs1_id);
-        assert_eq!(tracker.current_memory_usage, im::vector![123]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123]);
         tracker.check_if_new_peak();
         assert_eq!(previous_peak, tracker.peak_memory_usage);
         assert_eq!(tracker.peak_allocated_bytes, 1000);
@@ -1167,16 +2607,16 @@ mod tests {
         let cs2_id = tracker.get_callstack_id(&cs2);
         tracker.add_allocation(2, 2000, cs2_id);
         tracker.check_if_new_peak();
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 2000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 2000]);
         assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
         assert_eq!(tracker.peak_allocated_bytes, 2123);
         let previous_peak = tracker.peak_memory_usage.clone();
 
         // Add anonymous mmap() that doesn't go past previous peak:
         tracker.free_allocation(2);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 0]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 0]);
         tracker.add_anon_mmap(50000, 1000, cs2_id);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 1000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 1000]);
         tracker.check_if_new_peak();
         assert_eq!(tracker.current_allocated_bytes, 1123);
         assert_eq!(tracker.peak_allocated_bytes, 2123);
@@ -1187,7 +2627,7 @@ mod tests {
 
         // Add anonymous mmap() that does go past previous peak:
         tracker.add_anon_mmap(600000, 2000, cs2_id);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 3000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 3000]);
         tracker.check_if_new_peak();
         assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
         assert_eq!(tracker.current_allocated_bytes, 3123);
@@ -1195,7 +2635,7 @@ mod tests {
 
         // Remove mmap():
         tracker.free_anon_mmap(50000, 1000);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 2000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 2000]);
         tracker.check_if_new_peak();
         assert_eq!(tracker.current_allocated_bytes, 2123);
         assert_eq!(tracker.peak_allocated_bytes, 3123);
@@ -1207,7 +2647,7 @@ mod tests {
 
         // Partial removal of anonmyous mmap():
         tracker.free_anon_mmap(600100, 1000);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 1000]);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 123, 1000]);
         assert_eq!(tracker.current_allocated_bytes, 1123);
         assert_eq!(tracker.peak_allocated_bytes, 3123);
         assert_eq!(tracker.current_anon_mmaps.size(), 1000);
@@ -1215,9 +2655,119 @@ mod tests {
         tracker.validate();
     }
 
+    #[test]
+    fn package_report() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let site_packages = "/venv/lib/python3.9/site-packages";
+        let script = tracker
+            .functions
+            .add_function("/app/script.py".to_string(), "main".to_string());
+        let pandas = tracker.functions.add_function(
+            format!("{}/pandas/core/frame.py", site_packages),
+            "DataFrame.__init__".to_string(),
+        );
+        let json = tracker.functions.add_function(
+            "/usr/lib/python3.9/json/decoder.py".to_string(),
+            "decode".to_string(),
+        );
+        let cs1 = tracker.get_callstack_id(&Callstack::from_vec(vec![
+            CallSiteId::new(script, 1),
+            CallSiteId::new(pandas, 2),
+        ]));
+        let cs2 = tracker.get_callstack_id(&Callstack::from_vec(vec![
+            CallSiteId::new(script, 3),
+            CallSiteId::new(json, 4),
+        ]));
+        tracker.add_allocation(1, 1000, cs1);
+        tracker.add_allocation(2, 300, cs2);
+        tracker.free_allocation(2);
+        tracker.add_allocation(3, 10, cs2);
+
+        let mapper = PackageMapper::new(
+            vec!["/usr/lib/python3.9".to_string()],
+            vec!["/app".to_string(), site_packages.to_string()],
+        );
+        // Peak usage is what counts:
+        let report = tracker.package_report(&mapper);
+        assert_eq!(
+            report.by_package(),
+            vec![
+                (&Package::ThirdParty("pandas".to_string()), 1000),
+                (&Package::FirstParty("script".to_string()), 300),
+            ]
+        );
+    }
+
+    #[test]
+    fn call_tree() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid1 = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function_definition("b".to_string(), "bf".to_string(), 7);
+        let cs1 = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid1, 1)]));
+        let cs2 = tracker.get_callstack_id(&Callstack::from_vec(vec![
+            CallSiteId::new(fid1, 1),
+            CallSiteId::new(fid2, 2),
+        ]));
+        tracker.add_allocation(1, 1000, cs2);
+        tracker.add_allocation(2, 500, cs1);
+        tracker.free_allocation(1);
+        tracker.add_allocation(3, 100, cs1);
+        tracker.add_anon_mmap(4000, 30, CallstackInterner::ROOT);
+
+        let tree = tracker.call_tree();
+        assert_eq!(tree.len(), 3);
+        let functions = &tracker.functions;
+        let json = tree.to_json(
+            |function| {
+                let (qualname, filename, first_line) = functions.get_location(function);
+                (
+                    filename.to_string(),
+                    describe_function(qualname, first_line).into_owned(),
+                )
+            },
+            |_, _| String::new(),
+        );
+        assert_eq!(
+            json,
+            "{\"files\":[\"a\",\"b\"],\n\
+             \"functions\":[[0,\"af\"],[1,\"bf, defined at line 7\"]],\n\
+             \"sources\":[\"\"],\n\
+             \"nodes\":[[-1,-1,0,-1,0],[-1,0,1,0,0],[1,1,2,0,0]],\n\
+             \"peak\":[0,500,1000],\n\
+             \"current\":[30,600,0],\n\
+             \"allocations\":[0,2,1]}"
+        );
+    }
+
+    #[test]
+    fn identically_rendered_callstacks_are_combined() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let runpy_path = "/usr/lib/python3.9/runpy.py";
+        tracker.set_frame_filter(Box::new(FrameRules::from_env_for_python(runpy_path, &[])));
+        let runpy = tracker
+            .functions
+            .add_function(runpy_path.to_string(), "_run_code".to_string());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        // Leading runpy frames are hidden, so these render the same way:
+        let cs1 = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid, 1)]));
+        let cs2 = tracker.get_callstack_id(&Callstack::from_vec(vec![
+            CallSiteId::new(runpy, 10),
+            CallSiteId::new(fid, 1),
+        ]));
+        tracker.add_allocation(1, 1000, cs1);
+        tracker.add_allocation(2, 234, cs2);
+        let lines: Vec<String> = tracker.to_lines(Usage::Current, false).collect();
+        assert_eq!(lines, vec!["a:1 (af) 1234".to_string()]);
+    }
+
     #[test]
     fn combine_callstacks_and_sum_allocations() {
-        pyo3::prepare_freethreaded_python();
         let mut tracker = AllocationTracker::new(".".to_string());
         let fid1 = tracker
             .functions
@@ -1270,19 +2820,433 @@ mod tests {
             "c:3 (cf) 234",
             "a:7 (af);b:2 (bf) 6000",
         ];
-        let mut result2: Vec<String> = tracker.to_lines(true, false).collect();
+        let mut result2: Vec<String> = tracker.to_lines(Usage::Peak, false).collect();
         result2.sort();
         expected2.sort();
         assert_eq!(expected2, result2);
     }
 
+    #[test]
+    fn mmap_category_parsing() {
+        assert_eq!(MmapCategory::parse_list(""), Ok(vec![]));
+        assert_eq!(
+            MmapCategory::parse_list("shared"),
+            Ok(vec![MmapCategory::SharedAnonymous])
+        );
+        assert_eq!(
+            MmapCategory::parse_list("file-backed, shared"),
+            Ok(vec![
+                MmapCategory::FileBacked,
+                MmapCategory::SharedAnonymous
+            ])
+        );
+        assert!(MmapCategory::parse_list("shared,heap").is_err());
+    }
+
+    #[test]
+    fn categorized_mmaps_are_tracked_separately() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, 2));
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, CallSiteId::new(fid, 3));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        let cs3_id = tracker.get_callstack_id(&cs3);
+
+        tracker.add_allocation(1, 1000, cs1_id);
+        tracker.add_categorized_mmap(MmapCategory::FileBacked, 100000, 8000, cs2_id);
+        tracker.add_categorized_mmap(MmapCategory::SharedAnonymous, 200000, 3000, cs3_id);
+
+        // By default only the shared anonymous mmap() counts towards the
+        // main memory usage:
+        assert_eq!(tracker.get_current_allocated_bytes(), 4000);
+        assert_eq!(
+            tracker.get_mmap_allocated_bytes(MmapCategory::FileBacked),
+            8000
+        );
+        assert_eq!(
+            tracker.get_mmap_allocated_bytes(MmapCategory::SharedAnonymous),
+            3000
+        );
+
+        // munmap() of part of the file-backed mapping:
+        tracker.free_anon_mmap(100000, 5000);
+        assert_eq!(
+            tracker.get_mmap_allocated_bytes(MmapCategory::FileBacked),
+            3000
+        );
+        assert_eq!(tracker.get_current_allocated_bytes(), 4000);
+        tracker.free_anon_mmap(200000, 3000);
+        assert_eq!(tracker.get_current_allocated_bytes(), 1000);
+        tracker.validate();
+
+        let lines = |tracker: &mut AllocationTracker, usage| {
+            let mut result: Vec<String> = tracker.to_lines(usage, false).collect();
+            result.sort();
+            result
+        };
+        assert_eq!(
+            lines(&mut tracker, Usage::Peak),
+            vec!["a:1 (af) 1000", "a:3 (af) 3000"]
+        );
+        assert_eq!(
+            lines(&mut tracker, Usage::PeakMmap(MmapCategory::FileBacked)),
+            vec!["a:2 (af) 8000"]
+        );
+        assert_eq!(
+            lines(&mut tracker, Usage::PeakMmap(MmapCategory::SharedAnonymous)),
+            vec!["a:3 (af) 3000"]
+        );
+
+        // Resetting the peak applies to mmap() categories too:
+        tracker.reset_peak();
+        assert_eq!(
+            lines(&mut tracker, Usage::PeakMmap(MmapCategory::FileBacked)),
+            vec!["a:2 (af) 3000"]
+        );
+        assert_eq!(
+            lines(&mut tracker, Usage::PeakMmap(MmapCategory::SharedAnonymous)),
+            Vec::<String>::new()
+        );
+
+        // File-backed mmap() can be included in the peak too:
+        tracker.reset(".".to_string());
+        tracker.set_mmap_in_peak(MmapCategory::FileBacked, true);
+        tracker.set_mmap_in_peak(MmapCategory::SharedAnonymous, false);
+        assert!(tracker.is_mmap_in_peak(MmapCategory::FileBacked));
+        assert!(!tracker.is_mmap_in_peak(MmapCategory::SharedAnonymous));
+        tracker.add_categorized_mmap(MmapCategory::FileBacked, 100000, 8000, cs2_id);
+        tracker.add_categorized_mmap(MmapCategory::SharedAnonymous, 200000, 3000, cs3_id);
+        assert_eq!(tracker.get_current_allocated_bytes(), 8000);
+        tracker.free_anon_mmap(100000, 8000);
+        assert_eq!(tracker.get_current_allocated_bytes(), 0);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 8000);
+        tracker.validate();
+    }
+
+    #[test]
+    fn mmap_fixed_and_mremap() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, 2));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+
+        // MAP_FIXED over the middle of an existing mapping replaces it, even if
+        // it's a different kind of mapping:
+        tracker.add_anon_mmap(10000, 6000, cs1_id);
+        tracker.add_categorized_mmap(MmapCategory::FileBacked, 12000, 1000, cs2_id);
+        assert_eq!(tracker.get_current_allocated_bytes(), 5000);
+        tracker.add_anon_mmap(12000, 2000, cs2_id);
+        assert_eq!(tracker.get_current_allocated_bytes(), 6000);
+        assert_eq!(
+            tracker.get_mmap_allocated_bytes(MmapCategory::FileBacked),
+            0
+        );
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 4000, 2000]);
+        tracker.validate();
+
+        // Grow in place, replacing part of the neighboring mapping; the
+        // extension is attributed to the same callstack:
+        tracker.remap_mmap(12000, 2000, 12000, 3000);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 3000, 3000]);
+        // Move and shrink:
+        tracker.remap_mmap(12000, 3000, 50000, 1000);
+        assert_eq!(tracker.current_memory_usage, im::vector![0, 3000, 1000]);
+        assert_eq!(tracker.current_anon_mmaps.size(), 4000);
+        tracker.validate();
+        // Remapping untracked memory does nothing:
+        tracker.remap_mmap(100000, 1000, 200000, 2000);
+        assert_eq!(tracker.get_current_allocated_bytes(), 4000);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 6000);
+
+        // Remapping file-backed mmap() keeps it in its category:
+        tracker.add_categorized_mmap(MmapCategory::FileBacked, 70000, 1000, cs1_id);
+        tracker.remap_mmap(70000, 1000, 80000, 5000);
+        assert_eq!(
+            tracker.get_mmap_allocated_bytes(MmapCategory::FileBacked),
+            5000
+        );
+        assert_eq!(tracker.get_current_allocated_bytes(), 4000);
+        tracker.validate();
+    }
+
+    #[test]
+    fn dump_snapshot_writes_peak_and_current() {
+        let temp_dir = TempDir::new("fil-snapshot-test");
+        let default_path = temp_dir.path();
+        let mut tracker = AllocationTracker::new(default_path.to_str().unwrap().to_string());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 1));
+        let cs_id = tracker.get_callstack_id(&cs);
+        tracker.add_allocation(1, 1000, cs_id);
+        tracker.add_allocation(2, 500, cs_id);
+        tracker.free_allocation(1);
+
+        let directory = tracker.dump_snapshot();
+        assert_eq!(directory.parent().unwrap(), default_path);
+        let read = |name: &str| std::fs::read_to_string(directory.join(name)).unwrap();
+        assert_eq!(read("peak-memory.prof"), "a:1 (af) 1500\n");
+        assert_eq!(read("current-memory.prof"), "a:1 (af) 500\n");
+        for name in &[
+            "peak-memory.svg",
+            "peak-memory-reversed.svg",
+            "current-memory.svg",
+            "current-memory-reversed.svg",
+        ] {
+            assert!(directory.join(name).exists());
+        }
+        assert!(read("fil-overhead.txt").starts_with("Fil's own memory usage"));
+        assert!(read("size-histograms.json").contains("\"callstack\": \"a:1 (af)\""));
+        assert!(read("peak-packages.txt").contains("first-party  a\n"));
+        assert!(directory.join("peak-packages.svg").exists());
+        assert!(read("viewer.html").contains("\"functions\":[[0,\"af\"]]"));
+        // Nothing went wrong, so no diagnostics:
+        assert!(!directory.join("diagnostics.json").exists());
+
+        // Tracking state is unchanged:
+        assert_eq!(tracker.current_allocated_bytes, 500);
+        assert_eq!(tracker.peak_allocated_bytes, 1500);
+
+        // Snapshots written in the same millisecond still get their own
+        // directories:
+        let name = directory.file_name().unwrap().to_str().unwrap();
+        let second = create_unique_directory(default_path, name);
+        let third = create_unique_directory(default_path, name);
+        assert_eq!(second, default_path.join(format!("{}-2", name)));
+        assert_eq!(third, default_path.join(format!("{}-3", name)));
+        assert!(third.is_dir());
+    }
+
+    #[test]
+    fn inconsistencies_are_reported() {
+        let temp_dir = TempDir::new("fil-diagnostics-test");
+        let directory = temp_dir.path();
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let cs_id = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid, 1)]));
+        tracker.add_allocation(1, 1000, cs_id);
+        tracker.check_if_new_peak();
+        assert_eq!(tracker.check_consistency(), vec![]);
+
+        // Simulate a bug, where more memory is freed than was allocated:
+        tracker.remove_memory_usage(cs_id, 1500);
+        tracker.remove_memory_usage(cs_id, 100);
+        assert_eq!(tracker.current_memory_usage[cs_id as usize], 0);
+        assert_eq!(
+            tracker.check_consistency(),
+            vec![
+                Inconsistency::SumMismatch {
+                    what: "Current allocated bytes (vs. live allocations)",
+                    total: 0,
+                    sum: 1000,
+                },
+                Inconsistency::Underflow {
+                    callstack: "a:1 (af)".to_string(),
+                    times: 2,
+                    bytes: 600,
+                },
+            ]
+        );
+        // Underflows survive compaction:
+        tracker.compact();
+        assert_eq!(tracker.check_consistency().len(), 2);
+
+        tracker.dump_peak_to_flamegraph(directory.to_str().unwrap());
+        let diagnostics = std::fs::read_to_string(directory.join("diagnostics.json")).unwrap();
+        assert!(diagnostics.contains("\"kind\": \"underflow\""));
+        assert!(diagnostics.contains("\"kind\": \"sum_mismatch\""));
+    }
+
+    #[test]
+    fn missing_allocations_are_reported() {
+        let temp_dir = TempDir::new("fil-missing-test");
+        let directory = temp_dir.path();
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let cs1 = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid, 1)]));
+        let cs2 = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid, 2)]));
+        // Allocations that get overwritten without a free() are missing:
+        tracker.add_allocation(1, 100, cs1);
+        tracker.add_allocation(1, 200, cs2);
+        tracker.add_allocation(1, 300, cs1);
+        tracker.free_allocation(1);
+        // Both callstacks now use no memory, but compaction doesn't lose them:
+        tracker.reset_peak();
+        tracker.compact();
+        let diagnostics = tracker.diagnostics();
+        assert_eq!(diagnostics.missing_allocated_bytes, 300);
+        assert_eq!(
+            diagnostics.missing_allocations,
+            vec![
+                MissingAllocations {
+                    callstack: "a:2 (af)".to_string(),
+                    count: 1,
+                    bytes: 200,
+                },
+                MissingAllocations {
+                    callstack: "a:1 (af)".to_string(),
+                    count: 1,
+                    bytes: 100,
+                },
+            ]
+        );
+        assert_eq!(diagnostics.inconsistencies, vec![]);
+
+        tracker.dump_peak_to_flamegraph(directory.to_str().unwrap());
+        let read = |name: &str| std::fs::read_to_string(directory.join(name)).unwrap();
+        assert!(read("diagnostics.json")
+            .contains("\"callstack\": \"a:2 (af)\", \"count\": 1, \"bytes\": 200"));
+        assert_eq!(
+            read("missing-allocations.prof"),
+            "a:2 (af) 200\na:1 (af) 100\n"
+        );
+        assert!(directory.join("missing-allocations.svg").exists());
+
+        // A reset starts over, so earlier losses aren't reported again:
+        tracker.free_allocation(2);
+        tracker.reset(".".to_string());
+        let diagnostics = tracker.diagnostics();
+        assert_eq!(diagnostics.missing_allocated_bytes, 0);
+        assert_eq!(diagnostics.failed_deallocations, 0);
+        assert_eq!(diagnostics.missing_allocations, vec![]);
+        assert!(diagnostics.untracked_frees.is_empty());
+        tracker.compact();
+        assert_eq!(tracker.interner.nodes.len(), 1);
+    }
+
+    #[test]
+    fn size_histograms() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid1 = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b\"c".to_string(), "bf".to_string());
+        let cs1_id = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid1, 1)]));
+        let cs2_id = tracker.get_callstack_id(&Callstack::from_vec(vec![CallSiteId::new(fid2, 2)]));
+        // Many small allocations, most freed before the peak:
+        for address in 1..=10 {
+            tracker.add_allocation(address, 16, cs1_id);
+        }
+        for address in 1..=8 {
+            tracker.free_allocation(address);
+        }
+        // One big buffer, live at peak:
+        tracker.add_allocation(100, 5000, cs2_id);
+        tracker.free_allocation(100);
+        tracker.add_allocation(11, 20, cs1_id);
+        tracker.check_if_new_peak();
+        tracker.validate();
+
+        let top = tracker.top_callstacks(true, 2);
+        assert_eq!(top[0].bytes, 5000);
+        assert_eq!(top[0].live_sizes.to_string(), "1 x 4096-8191");
+        assert_eq!(top[1].bytes, 32);
+        assert_eq!(top[1].live_sizes.to_string(), "2 x 16-31");
+        assert_eq!(top[1].all_sizes.to_string(), "11 x 16-31");
+        let top = tracker.top_callstacks(false, 2);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].live_sizes.to_string(), "3 x 16-31");
+
+        assert_eq!(
+            tracker.size_histograms_json(),
+            "{\"callstacks\": [\n\
+             {\"callstack\": \"b\\\"c:2 (bf)\", \"peak_bytes\": 5000, \
+             \"allocations\": [{\"min_size\": 4096, \"max_size\": 8191, \"count\": 1}], \
+             \"live_at_peak\": [{\"min_size\": 4096, \"max_size\": 8191, \"count\": 1}]},\n\
+             {\"callstack\": \"a:1 (af)\", \"peak_bytes\": 32, \
+             \"allocations\": [{\"min_size\": 16, \"max_size\": 31, \"count\": 11}], \
+             \"live_at_peak\": [{\"min_size\": 16, \"max_size\": 31, \"count\": 2}]}\n\
+             ]}\n"
+        );
+
+        // Resetting the peak makes the current allocations the peak ones:
+        tracker.reset_peak();
+        let top = tracker.top_callstacks(true, 2);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].live_sizes.to_string(), "3 x 16-31");
+    }
+
+    #[test]
+    fn overhead_grows_with_tracked_state() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let empty = tracker.overhead();
+
+        let fid = tracker
+            .functions
+            .add_function("a".repeat(1000), "af".to_string());
+        let mut cs = Callstack::new();
+        for line in 0..100 {
+            cs.start_call(0, CallSiteId::new(fid, line));
+        }
+        let cs_id = tracker.get_callstack_id(&cs);
+        for address in 1..1000 {
+            tracker.add_allocation(address, 10, cs_id);
+        }
+        tracker.add_anon_mmap(1 << 20, 4096, cs_id);
+        let overhead = tracker.overhead();
+        assert!(overhead.functions >= empty.functions + 1000);
+        assert!(overhead.callstacks > empty.callstacks);
+        assert!(overhead.usage >= 100 * std::mem::size_of::<usize>());
+        assert!(overhead.allocations >= 999 * std::mem::size_of::<usize>());
+        assert!(overhead.mmaps > empty.mmaps);
+    }
+
     #[test]
     fn test_unknown_function_id() {
         let func_locations = FunctionLocations::new();
-        let (function, filename) = func_locations.get_function_and_filename(FunctionId::UNKNOWN);
+        let (function, filename, _) = func_locations.get_location(FunctionId::UNKNOWN);
         assert_eq!(filename, "UNKNOWN DUE TO BUG");
         assert_eq!(function, "UNKNOWN");
     }
 
+    #[test]
+    fn function_locations_are_deduplicated() {
+        let mut func_locations = FunctionLocations::new();
+        let id1 = func_locations.add_function("a.py".to_string(), "f".to_string());
+        let id2 = func_locations.add_function("a.py".to_string(), "g".to_string());
+        let id3 = func_locations.add_function("b.py".to_string(), "f".to_string());
+        assert_ne!(id1, id2);
+        assert_ne!(id1, id3);
+        assert_ne!(id2, id3);
+        // Same location again gets the same id:
+        assert_eq!(
+            func_locations.add_function("a.py".to_string(), "f".to_string()),
+            id1
+        );
+        assert_eq!(
+            func_locations.add_function("b.py".to_string(), "f".to_string()),
+            id3
+        );
+        assert_eq!(func_locations.functions.len(), 3);
+        assert_eq!(func_locations.get_location(id2), ("g", "a.py", 0));
+        // Filenames are shared:
+        assert_eq!(func_locations.filenames.len(), 2);
+        assert!(Arc::ptr_eq(
+            &func_locations.functions[id1.0 as usize].filename,
+            &func_locations.functions[id2.0 as usize].filename
+        ));
+    }
+
     // TODO test to_lines(false)
 }
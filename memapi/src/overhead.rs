@@ -0,0 +1,81 @@
+// Accounting for the memory Fil itself uses, so users can tell the profiler's
+// overhead apart from their own program's memory usage.
+//
+// The estimates are computed from the sizes and capacities of the tracker's
+// data structures, so they undercount allocator bookkeeping and
+// fragmentation. The allocator's own statistics can't be used instead: in the
+// preloaded library the profiled program's allocations go to the same
+// jemalloc as ours.
+use std::fmt;
+
+/// Estimated memory used by the tracker's own data structures, in bytes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overhead {
+    /// The map of currently tracked allocations.
+    pub allocations: usize,
+    /// Interned callstacks.
+    pub callstacks: usize,
+    /// Function names and filenames.
+    pub functions: usize,
+    /// Tracked mmap() ranges.
+    pub mmaps: usize,
+    /// Per-callstack current and peak memory usage.
+    pub usage: usize,
+}
+
+impl Overhead {
+    /// The sum of the estimates.
+    pub fn estimated_total(&self) -> usize {
+        self.allocations + self.callstacks + self.functions + self.mmaps + self.usage
+    }
+}
+
+/// Format bytes as MiB.
+fn mib(bytes: usize) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+impl fmt::Display for Overhead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Fil's own memory usage (not included in the profiling results): {}",
+            mib(self.estimated_total())
+        )?;
+        for (name, bytes) in [
+            ("Tracked allocations", self.allocations),
+            ("Callstacks", self.callstacks),
+            ("Functions", self.functions),
+            ("mmap() ranges", self.mmaps),
+            ("Per-callstack usage", self.usage),
+        ]
+        .iter()
+        {
+            writeln!(f, "  {}: {}", name, mib(*bytes))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Overhead;
+
+    #[test]
+    fn totals() {
+        let overhead = Overhead {
+            allocations: 1024 * 1024,
+            callstacks: 2 * 1024 * 1024,
+            functions: 0,
+            mmaps: 100,
+            usage: 1000,
+        };
+        assert_eq!(overhead.estimated_total(), 3 * 1024 * 1024 + 1100);
+        let report = overhead.to_string();
+        assert!(report.starts_with(
+            "Fil's own memory usage (not included in the profiling results): 3.0 MiB\n"
+        ));
+        assert!(report.contains("  Callstacks: 2.0 MiB\n"));
+        assert!(report.ends_with("  Per-callstack usage: 0.0 MiB\n"));
+    }
+}
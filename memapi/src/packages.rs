@@ -0,0 +1,395 @@
+// Attributing memory to packages, to answer "is this our code, pandas, or
+// pyarrow?". Each frame's filename is mapped to the top-level package it's
+// part of, using the site-packages layout and sys.path, and the bytes of each
+// callstack go to its innermost package that isn't in the standard library.
+use crate::framefilter::package_relative;
+use crate::util::new_hashmap;
+use ahash::RandomState as ARandomState;
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+
+// For Rust programs profiling themselves, see allocator.rs. Dependencies are
+// in e.g. "~/.cargo/registry/src/<registry>/serde-1.0.130/src/de.rs", and the
+// standard library in e.g. "/rustc/<commit>/library/alloc/src/vec/mod.rs".
+const CARGO_REGISTRY: &str = "/.cargo/registry/src/";
+const RUST_STDLIB: &str = "/rustc/";
+
+/// Where a frame's code comes from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Package {
+    /// The program's own code, by top-level module or package.
+    FirstParty(String),
+    /// Installed libraries, by top-level module or package.
+    ThirdParty(String),
+    StandardLibrary,
+    /// Allocations with no Python callstack, or code without debug info in
+    /// Rust programs.
+    Unknown,
+}
+
+impl Package {
+    /// "first-party", "third-party", etc..
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Package::FirstParty(_) => "first-party",
+            Package::ThirdParty(_) => "third-party",
+            Package::StandardLibrary => "stdlib",
+            Package::Unknown => "unknown",
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Package::FirstParty(name) | Package::ThirdParty(name) => name,
+            Package::StandardLibrary => "[standard library]",
+            Package::Unknown => "[No Python stack]",
+        }
+    }
+}
+
+impl fmt::Display for Package {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Maps filenames to packages.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackageMapper {
+    /// Standard library directories, with a trailing slash.
+    stdlib: Vec<String>,
+    /// sys.path entries, with a trailing slash, longest first.
+    roots: Vec<String>,
+}
+
+impl PackageMapper {
+    pub fn new(stdlib: Vec<String>, roots: Vec<String>) -> Self {
+        let with_slash = |path: String| format!("{}/", path.trim_end_matches('/'));
+        PackageMapper {
+            stdlib: stdlib.into_iter().map(with_slash).collect(),
+            roots: roots
+                .into_iter()
+                .map(with_slash)
+                .sorted_by_key(|root| std::cmp::Reverse(root.len()))
+                .collect(),
+        }
+    }
+
+    /// The package a file is part of.
+    pub fn package(&self, filename: &str) -> Package {
+        if filename.starts_with("<frozen ") || filename.starts_with(RUST_STDLIB) {
+            return Package::StandardLibrary;
+        }
+        if filename == "<unknown>" {
+            return Package::Unknown;
+        }
+        if let Some(crate_directory) = filename
+            .find(CARGO_REGISTRY)
+            .and_then(|index| filename[index + CARGO_REGISTRY.len()..].split('/').nth(1))
+        {
+            return Package::ThirdParty(crate_name(crate_directory));
+        }
+        if let Some(relative) = package_relative(filename) {
+            return Package::ThirdParty(top_level(relative));
+        }
+        if self
+            .stdlib
+            .iter()
+            .any(|stdlib| filename.starts_with(stdlib))
+        {
+            return Package::StandardLibrary;
+        }
+        let relative = self
+            .roots
+            .iter()
+            .find_map(|root| filename.strip_prefix(root.as_str()))
+            .unwrap_or_else(|| filename.rsplit('/').next().unwrap_or(filename));
+        Package::FirstParty(top_level(relative))
+    }
+}
+
+/// The top-level module or package of a path relative to an import root,
+/// e.g. "pandas" for "pandas/core/frame.py".
+fn top_level(relative: &str) -> String {
+    let first = relative.split('/').next().unwrap_or(relative);
+    // Strip ".py", or e.g. ".cpython-39-x86_64-linux-gnu.so":
+    first.split('.').next().unwrap_or(first).to_string()
+}
+
+/// The name of a crate given its directory in the cargo registry, e.g.
+/// "serde" for "serde-1.0.130".
+fn crate_name(directory: &str) -> String {
+    match directory.rsplit_once('-') {
+        Some((name, version)) if version.starts_with(|c: char| c.is_ascii_digit()) => {
+            name.to_string()
+        }
+        _ => directory.to_string(),
+    }
+}
+
+static MAPPER: Lazy<PackageMapper> = Lazy::new(|| {
+    #[cfg(feature = "python")]
+    let (stdlib, roots) = crate::python::get_import_paths();
+    // Without Python, only Rust code is told apart:
+    #[cfg(not(feature = "python"))]
+    let (stdlib, roots) = (vec![], vec![]);
+    PackageMapper::new(stdlib, roots)
+});
+
+/// The mapper for this process.
+pub fn mapper() -> &'static PackageMapper {
+    &MAPPER
+}
+
+/// Load the mapper ahead of time, while it's safe to use Python.
+pub fn initialize() {
+    Lazy::force(&MAPPER);
+}
+
+/// Memory usage attributed to packages.
+#[derive(Debug, Default)]
+pub struct PackageReport {
+    total_bytes: usize,
+    by_package: HashMap<Package, usize, ARandomState>,
+    // Callstacks with unknown frames dropped, and consecutive frames from the
+    // same package merged:
+    by_package_stack: HashMap<Vec<Package>, usize, ARandomState>,
+}
+
+impl PackageReport {
+    pub fn new() -> Self {
+        PackageReport {
+            total_bytes: 0,
+            by_package: new_hashmap(),
+            by_package_stack: new_hashmap(),
+        }
+    }
+
+    /// Add the bytes of a callstack, given the packages of its frames,
+    /// outermost first. They're attributed to the innermost package that isn't
+    /// the standard library or unknown.
+    pub fn add(&mut self, packages: Vec<Package>, bytes: usize) {
+        self.total_bytes += bytes;
+        let innermost = packages
+            .iter()
+            .rev()
+            .find(|package| !matches!(package, Package::StandardLibrary | Package::Unknown))
+            .or_else(|| packages.last())
+            .cloned()
+            .unwrap_or(Package::Unknown);
+        *self.by_package.entry(innermost).or_insert(0) += bytes;
+        let mut stack: Vec<Package> = packages
+            .into_iter()
+            .filter(|package| *package != Package::Unknown)
+            .dedup()
+            .collect();
+        if stack.is_empty() {
+            stack.push(Package::Unknown);
+        }
+        *self.by_package_stack.entry(stack).or_insert(0) += bytes;
+    }
+
+    /// Bytes by package, largest first.
+    pub fn by_package(&self) -> Vec<(&Package, usize)> {
+        self.by_package
+            .iter()
+            .map(|(package, bytes)| (package, *bytes))
+            .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)))
+            .collect()
+    }
+
+    /// Bytes by kind of package, e.g. "first-party", largest first.
+    pub fn by_kind(&self) -> Vec<(&'static str, usize)> {
+        let mut by_kind: HashMap<&'static str, usize, ARandomState> = new_hashmap();
+        for (package, bytes) in self.by_package.iter() {
+            *by_kind.entry(package.kind()).or_insert(0) += bytes;
+        }
+        by_kind
+            .into_iter()
+            .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)))
+            .collect()
+    }
+
+    /// Render as a human-readable table.
+    pub fn table(&self) -> String {
+        let percent = |bytes: usize| {
+            if self.total_bytes == 0 {
+                0.0
+            } else {
+                bytes as f64 * 100.0 / self.total_bytes as f64
+            }
+        };
+        let mut result = String::new();
+        result.push_str(
+            "Peak memory usage by package, attributed to the innermost frame outside the standard library.\n\n",
+        );
+        result.push_str(&format!(
+            "{:>15}  {:>6}  {:<11}  {}\n",
+            "Bytes", "Share", "Kind", "Package"
+        ));
+        for (package, bytes) in self.by_package() {
+            result.push_str(&format!(
+                "{:>15}  {:>5.1}%  {:<11}  {}\n",
+                bytes,
+                percent(bytes),
+                package.kind(),
+                package
+            ));
+        }
+        result.push('\n');
+        for (kind, bytes) in self.by_kind() {
+            result.push_str(&format!(
+                "{:>15}  {:>5.1}%  {}\n",
+                bytes,
+                percent(bytes),
+                kind
+            ));
+        }
+        result
+    }
+
+    /// Lines for a package-level flamegraph.
+    pub fn flamegraph_lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.by_package_stack.iter().map(|(stack, bytes)| {
+            format!(
+                "{} {}",
+                stack
+                    .iter()
+                    .map(|package| format!("{} ({})", package, package.kind()))
+                    .join(";"),
+                bytes
+            )
+        })
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Package, PackageMapper, PackageReport};
+
+    fn mapper() -> PackageMapper {
+        PackageMapper::new(
+            vec!["/usr/lib/python3.9".to_string()],
+            vec![
+                "/home/user/project".to_string(),
+                "/usr/lib/python3.9".to_string(),
+                "/usr/lib/python3.9/lib-dynload/".to_string(),
+                "/home/user/venv/lib/python3.9/site-packages".to_string(),
+                "/home/user/project/src".to_string(),
+            ],
+        )
+    }
+
+    #[test]
+    fn packages() {
+        let mapper = mapper();
+        let third_party = |name: &str| Package::ThirdParty(name.to_string());
+        let first_party = |name: &str| Package::FirstParty(name.to_string());
+        assert_eq!(
+            mapper.package("/home/user/venv/lib/python3.9/site-packages/pandas/core/frame.py"),
+            third_party("pandas")
+        );
+        assert_eq!(
+            mapper.package("/usr/lib/python3/dist-packages/six.py"),
+            third_party("six")
+        );
+        assert_eq!(
+            mapper.package(
+                "/home/user/venv/lib/python3.9/site-packages/_cffi_backend.cpython-39-x86_64-linux-gnu.so"
+            ),
+            third_party("_cffi_backend")
+        );
+        assert_eq!(
+            mapper.package("/usr/lib/python3.9/json/decoder.py"),
+            Package::StandardLibrary
+        );
+        assert_eq!(
+            mapper.package("<frozen importlib._bootstrap>"),
+            Package::StandardLibrary
+        );
+        assert_eq!(
+            mapper.package("/home/user/project/script.py"),
+            first_party("script")
+        );
+        // The longest matching sys.path entry wins:
+        assert_eq!(
+            mapper.package("/home/user/project/src/myapp/models.py"),
+            first_party("myapp")
+        );
+        assert_eq!(mapper.package("/elsewhere/tool.py"), first_party("tool"));
+        // Rust code:
+        assert_eq!(
+            mapper.package(
+                "/home/user/.cargo/registry/src/github.com-1ecc6299db9ec823/serde_json-1.0.68/src/de.rs"
+            ),
+            third_party("serde_json")
+        );
+        assert_eq!(
+            mapper.package("/rustc/59807616e/library/alloc/src/raw_vec.rs"),
+            Package::StandardLibrary
+        );
+        assert_eq!(mapper.package("src/main.rs"), first_party("main"));
+        assert_eq!(mapper.package("<unknown>"), Package::Unknown);
+    }
+
+    #[test]
+    fn report() {
+        let pandas = Package::ThirdParty("pandas".to_string());
+        let script = Package::FirstParty("script".to_string());
+        let mut report = PackageReport::new();
+        report.add(
+            vec![
+                script.clone(),
+                pandas.clone(),
+                pandas.clone(),
+                Package::StandardLibrary,
+            ],
+            600,
+        );
+        report.add(
+            vec![script.clone(), Package::StandardLibrary, Package::Unknown],
+            300,
+        );
+        report.add(vec![Package::StandardLibrary], 50);
+        report.add(vec![], 50);
+        assert_eq!(report.total_bytes(), 1000);
+        assert_eq!(
+            report.by_package(),
+            vec![
+                (&pandas, 600),
+                (&script, 300),
+                (&Package::StandardLibrary, 50),
+                (&Package::Unknown, 50)
+            ]
+        );
+        assert_eq!(
+            report.by_kind(),
+            vec![
+                ("third-party", 600),
+                ("first-party", 300),
+                ("stdlib", 50),
+                ("unknown", 50)
+            ]
+        );
+        let mut lines: Vec<String> = report.flamegraph_lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "[No Python stack] (unknown) 50",
+                "[standard library] (stdlib) 50",
+                "script (first-party);[standard library] (stdlib) 300",
+                "script (first-party);pandas (third-party);[standard library] (stdlib) 600",
+            ]
+        );
+        let table = report.table();
+        assert!(table.contains("            600   60.0%  third-party  pandas\n"));
+        assert!(table.contains("            300   30.0%  first-party\n"));
+    }
+}
@@ -0,0 +1,231 @@
+// Expose tracker metrics in the Prometheus text exposition format, either over
+// a minimal HTTP endpoint or by rewriting a file for node_exporter's textfile
+// collector.
+use crate::memorytracking::AllocationTracker;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// How long a client gets to send its request and read the response, so one
+/// that never does can't block other scrapes.
+pub const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Render the tracker's metrics, including gauges for the `top_functions`
+/// functions with the most memory currently allocated.
+pub fn render(tracker: &mut AllocationTracker, top_functions: usize) -> String {
+    let mut result = String::new();
+    let mut metric = |kind: &str, name: &str, help: &str, value: usize| {
+        result.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+            name = name,
+            help = help,
+            kind = kind,
+            value = value
+        ));
+    };
+    metric(
+        "gauge",
+        "fil_current_allocated_bytes",
+        "Currently allocated bytes.",
+        tracker.get_current_allocated_bytes(),
+    );
+    metric(
+        "gauge",
+        "fil_peak_allocated_bytes",
+        "Peak allocated bytes.",
+        tracker.get_peak_allocated_bytes(),
+    );
+    // These only go up, until tracking is reset:
+    metric(
+        "counter",
+        "fil_missing_allocated_bytes_total",
+        "Tracked bytes that disappeared without being freed.",
+        tracker.get_missing_allocated_bytes(),
+    );
+    metric(
+        "counter",
+        "fil_failed_deallocations_total",
+        "Deallocations of untracked allocations.",
+        tracker.get_failed_deallocations(),
+    );
+    result.push_str(
+        "# HELP fil_function_current_allocated_bytes Currently allocated bytes, by innermost Python function.\n\
+         # TYPE fil_function_current_allocated_bytes gauge\n",
+    );
+    for (filename, function, first_line, size) in tracker.top_functions(top_functions) {
+        result.push_str(&format!(
+            "fil_function_current_allocated_bytes{{filename=\"{}\",function=\"{}\",line=\"{}\"}} {}\n",
+            escape_label(filename),
+            escape_label(function),
+            first_line,
+            size
+        ));
+    }
+    result
+}
+
+/// Escape a label value, per the exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer a single HTTP request. `GET /metrics` gets the output of `render()`,
+/// anything else gets a 404. Requests are answered one at a time, so sockets
+/// should have SCRAPE_TIMEOUT set as their read and write timeouts.
+pub fn answer_scrape<S, F>(stream: S, render: F) -> std::io::Result<()>
+where
+    S: Read + Write,
+    F: FnOnce() -> String,
+{
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, we don't care about them:
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render()),
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Atomically replace the file at the given path with the metrics, so the
+/// textfile collector never sees a partially written file.
+pub fn write_textfile(path: &Path, metrics: &str) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, metrics)?;
+    std::fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{answer_scrape, render, write_textfile};
+    use crate::memorytracking::{AllocationTracker, CallSiteId, Callstack};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::Path;
+
+    fn tracker() -> AllocationTracker {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid1 = tracker.functions.add_function_definition(
+            "a.py".to_string(),
+            "Example.af".to_string(),
+            3,
+        );
+        let fid2 = tracker
+            .functions
+            .add_function("b\"c.py".to_string(), "bf".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(0, CallSiteId::new(fid2, 2));
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, CallSiteId::new(fid2, 7));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        let cs3_id = tracker.get_callstack_id(&cs3);
+        tracker.add_allocation(1, 100, cs1_id);
+        tracker.add_allocation(2, 300, cs2_id);
+        tracker.add_allocation(3, 50, cs3_id);
+        tracker.free_allocation(1);
+        tracker.add_allocation(4, 20, cs1_id);
+        // Never allocated:
+        tracker.free_allocation(5);
+        tracker
+    }
+
+    const EXPECTED: &str = "\
+# HELP fil_current_allocated_bytes Currently allocated bytes.
+# TYPE fil_current_allocated_bytes gauge
+fil_current_allocated_bytes 370
+# HELP fil_peak_allocated_bytes Peak allocated bytes.
+# TYPE fil_peak_allocated_bytes gauge
+fil_peak_allocated_bytes 450
+# HELP fil_missing_allocated_bytes_total Tracked bytes that disappeared without being freed.
+# TYPE fil_missing_allocated_bytes_total counter
+fil_missing_allocated_bytes_total 0
+# HELP fil_failed_deallocations_total Deallocations of untracked allocations.
+# TYPE fil_failed_deallocations_total counter
+fil_failed_deallocations_total 1
+# HELP fil_function_current_allocated_bytes Currently allocated bytes, by innermost Python function.
+# TYPE fil_function_current_allocated_bytes gauge
+fil_function_current_allocated_bytes{filename=\"b\\\"c.py\",function=\"bf\",line=\"0\"} 350
+fil_function_current_allocated_bytes{filename=\"a.py\",function=\"Example.af\",line=\"3\"} 20
+";
+
+    #[test]
+    fn rendering() {
+        let mut tracker = tracker();
+        assert_eq!(render(&mut tracker, 10), EXPECTED);
+        // Only the top function:
+        let rendered = render(&mut tracker, 1);
+        assert!(rendered.contains("function=\"bf\",line=\"0\"} 350\n"));
+        assert!(!rendered.contains("function=\"Example.af\""));
+    }
+
+    #[test]
+    fn local_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut tracker = tracker();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                answer_scrape(stream, || render(&mut tracker, 10)).unwrap();
+            }
+        });
+        let scrape = |path: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n",
+                path
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = scrape("/metrics");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", EXPECTED.len())));
+        assert_eq!(body, EXPECTED);
+
+        assert!(scrape("/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn textfile() {
+        let temp_dir = crate::util::TempDir::new("fil-prometheus-test");
+        let path = temp_dir.path().join("fil.prom");
+        write_textfile(&path, "first\n").unwrap();
+        write_textfile(&path, "second\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        assert!(!Path::new(&temporary).exists());
+    }
+}
@@ -1,10 +1,24 @@
-// Interactions with Python APIs.
+// Interactions with Python APIs, including the SourceProvider and FrameFilter
+// that AllocationTracker should use when profiling Python code.
+use crate::framefilter::FrameRules;
+use crate::sourcecache::{SourceCache, SourceProvider};
 use once_cell::sync::Lazy;
 use pyo3::prelude::*;
 use pyo3::types::PyModule;
 
-// Get the source code line from a given filename.
-pub fn get_source_line(filename: &str, line_number: u16) -> PyResult<String> {
+// Whether there's a Python interpreter. There isn't when a Rust program
+// profiles itself, see allocator.rs, in which case the functions below return
+// empty results.
+pub fn is_initialized() -> bool {
+    unsafe { pyo3::ffi::Py_IsInitialized() != 0 }
+}
+
+// Get the source code line from a given filename, via linecache. Most code
+// should use source_provider() instead.
+pub fn get_source_line(filename: &str, line_number: u32) -> PyResult<String> {
+    if !is_initialized() {
+        return Ok(String::new());
+    }
     Python::with_gil(|py| {
         let linecache = PyModule::import(py, "linecache")?;
         let result: String = linecache
@@ -15,6 +29,27 @@ pub fn get_source_line(filename: &str, line_number: u16) -> PyResult<String> {
     })
 }
 
+// Source code lines via linecache.
+pub struct Linecache;
+
+impl SourceProvider for Linecache {
+    fn get_line(&self, filename: &str, line_number: u32) -> String {
+        get_source_line(filename, line_number).unwrap_or_default()
+    }
+}
+
+// Source code lines for Python code: files are read directly, and only code
+// that isn't in a plain file comes from linecache.
+pub fn source_provider() -> SourceCache {
+    SourceCache::with_fallback(Box::new(Linecache))
+}
+
+// Frame rules for Python code, which hide the runpy frames Fil runs programs
+// with. Call this while the interpreter is running.
+pub fn frame_filter() -> FrameRules {
+    FrameRules::from_env_for_python(get_runpy_path(), &get_prefixes())
+}
+
 // Return the filesystem path of the stdlib's runpy module.
 pub fn get_runpy_path() -> &'static str {
     static PATH: Lazy<String> = Lazy::new(|| {
@@ -25,3 +60,52 @@ pub fn get_runpy_path() -> &'static str {
     });
     PATH.as_str()
 }
+
+// Return sys.prefix, sys.exec_prefix and their base_ equivalents, without
+// duplicates.
+pub fn get_prefixes() -> Vec<String> {
+    if !is_initialized() {
+        return vec![];
+    }
+    Python::with_gil(|py| {
+        let sys = PyModule::import(py, "sys").unwrap();
+        let mut prefixes: Vec<String> = vec![];
+        for name in ["prefix", "exec_prefix", "base_prefix", "base_exec_prefix"].iter() {
+            if let Ok(prefix) = sys.getattr(*name).and_then(|prefix| prefix.extract()) {
+                if !prefixes.contains(&prefix) {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+        prefixes
+    })
+}
+
+// Return the standard library directories, and the sys.path entries, for
+// mapping filenames to packages.
+pub fn get_import_paths() -> (Vec<String>, Vec<String>) {
+    if !is_initialized() {
+        return (vec![], vec![]);
+    }
+    Python::with_gil(|py| {
+        let sysconfig = PyModule::import(py, "sysconfig").unwrap();
+        let paths = sysconfig.getattr("get_paths").unwrap().call0().unwrap();
+        let stdlib: Vec<String> = ["stdlib", "platstdlib"]
+            .iter()
+            .filter_map(|name| paths.get_item(*name).ok()?.extract().ok())
+            .collect();
+        let sys = PyModule::import(py, "sys").unwrap();
+        let cwd = std::env::current_dir()
+            .map(|cwd| cwd.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let roots: Vec<String> = sys
+            .getattr("path")
+            .and_then(|path| path.extract::<Vec<String>>())
+            .unwrap_or_default()
+            .into_iter()
+            // The empty string means the current directory:
+            .map(|root: String| if root.is_empty() { cwd.clone() } else { root })
+            .collect();
+        (stdlib, roots)
+    })
+}
@@ -0,0 +1,226 @@
+// Line-based protocol for querying a running process about its memory usage.
+//
+// Each request is a single line containing one of the following commands:
+//
+// * `current`: currently allocated bytes.
+// * `peak`: peak allocated bytes.
+// * `top N`: the N callstacks currently using the most memory.
+// * `top-peak N`: the N callstacks using the most memory at peak.
+// * `dump PATH`: write out peak and current memory usage to directory PATH.
+// * `reset-peak`: forget the previous peak, current usage becomes the peak.
+// * `compact`: forget callstacks that use no memory, responds with the bytes
+//   of profiler memory freed.
+//
+// Each response is zero or more lines of data, followed by a final line that
+// is either `OK` or `ERROR <message>`. Callstacks are written as `<bytes>
+// <callstack>`, with the callstack in the same format as .prof files,
+// followed by two indented lines with histograms of malloc() sizes: of the
+// allocations using the memory, and of all allocations so far.
+use crate::memorytracking::AllocationTracker;
+use std::path::PathBuf;
+
+/// How many clients can be connected at once. Each one uses a thread in the
+/// profiled process, so idle or leaked clients mustn't pile up.
+pub const MAX_CLIENTS: usize = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    CurrentBytes,
+    PeakBytes,
+    Top { count: usize, peak: bool },
+    Dump(PathBuf),
+    ResetPeak,
+    Compact,
+}
+
+impl Command {
+    /// Parse a request line.
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        let (name, argument) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+        let no_argument = |command| {
+            if argument.is_empty() {
+                Ok(command)
+            } else {
+                Err(format!("{} takes no arguments", name))
+            }
+        };
+        let count = || {
+            argument
+                .parse::<usize>()
+                .map_err(|_| format!("{} requires a number of callstacks", name))
+        };
+        match name {
+            "current" => no_argument(Command::CurrentBytes),
+            "peak" => no_argument(Command::PeakBytes),
+            "top" => Ok(Command::Top {
+                count: count()?,
+                peak: false,
+            }),
+            "top-peak" => Ok(Command::Top {
+                count: count()?,
+                peak: true,
+            }),
+            "dump" if !argument.is_empty() => Ok(Command::Dump(PathBuf::from(argument))),
+            "dump" => Err("dump requires a path".to_string()),
+            "reset-peak" => no_argument(Command::ResetPeak),
+            "compact" => no_argument(Command::Compact),
+            "" => Err("empty command".to_string()),
+            _ => Err(format!("unknown command {}", name)),
+        }
+    }
+
+    /// Whether running this command requires the Python interpreter, e.g. to
+    /// render callstacks or read source code.
+    pub fn needs_python(&self) -> bool {
+        matches!(self, Command::Top { .. } | Command::Dump(_))
+    }
+
+    /// Run the command, returning the full response.
+    ///
+    /// Compacting invalidates callstack IDs cached outside the tracker, so
+    /// it's done by calling `compact`, which should call
+    /// AllocationTracker::compact() and arrange for those IDs to be
+    /// recalculated, returning the bytes freed.
+    pub fn run(
+        &self,
+        tracker: &mut AllocationTracker,
+        compact: impl FnOnce(&mut AllocationTracker) -> usize,
+    ) -> String {
+        match self {
+            Command::CurrentBytes => format!("{}\nOK\n", tracker.get_current_allocated_bytes()),
+            Command::PeakBytes => format!("{}\nOK\n", tracker.get_peak_allocated_bytes()),
+            Command::Top { count, peak } => {
+                let mut result = String::new();
+                let live = if *peak { "at peak" } else { "now" };
+                for top in tracker.top_callstacks(*peak, *count) {
+                    result.push_str(&format!("{} {}\n", top.bytes, top.callstack));
+                    result.push_str(&format!("  sizes {}: {}\n", live, top.live_sizes));
+                    result.push_str(&format!("  sizes over the run: {}\n", top.all_sizes));
+                }
+                result.push_str("OK\n");
+                result
+            }
+            Command::Dump(path) => {
+                tracker.dump_snapshot_to(path);
+                "OK\n".to_string()
+            }
+            Command::ResetPeak => {
+                tracker.reset_peak();
+                "OK\n".to_string()
+            }
+            Command::Compact => format!("{}\nOK\n", compact(tracker)),
+        }
+    }
+}
+
+/// Format an error response.
+pub fn error_response(message: &str) -> String {
+    format!("ERROR {}\n", message.replace('\n', " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+    use crate::memorytracking::{AllocationTracker, CallSiteId, Callstack};
+    use std::path::PathBuf;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(Command::parse("current\n"), Ok(Command::CurrentBytes));
+        assert_eq!(Command::parse(" peak "), Ok(Command::PeakBytes));
+        assert_eq!(
+            Command::parse("top 5"),
+            Ok(Command::Top {
+                count: 5,
+                peak: false
+            })
+        );
+        assert_eq!(
+            Command::parse("top-peak  12"),
+            Ok(Command::Top {
+                count: 12,
+                peak: true
+            })
+        );
+        assert_eq!(
+            Command::parse("dump /tmp/a dir"),
+            Ok(Command::Dump(PathBuf::from("/tmp/a dir")))
+        );
+        assert_eq!(Command::parse("reset-peak"), Ok(Command::ResetPeak));
+        assert_eq!(Command::parse("compact"), Ok(Command::Compact));
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("top").is_err());
+        assert!(Command::parse("top x").is_err());
+        assert!(Command::parse("dump").is_err());
+        assert!(Command::parse("current 1").is_err());
+        assert!(Command::parse("compact now").is_err());
+        assert!(Command::parse("bottom 3").is_err());
+    }
+
+    #[test]
+    fn running() {
+        let mut tracker = AllocationTracker::new(".".to_string());
+        let fid1 = tracker
+            .functions
+            .add_function("a".to_string(), "af".to_string());
+        let fid2 = tracker
+            .functions
+            .add_function("b".to_string(), "bf".to_string());
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(0, CallSiteId::new(fid2, 2));
+        let cs1_id = tracker.get_callstack_id(&cs1);
+        let cs2_id = tracker.get_callstack_id(&cs2);
+        tracker.add_allocation(1, 100, cs1_id);
+        tracker.add_allocation(2, 300, cs2_id);
+        tracker.free_allocation(2);
+        tracker.add_allocation(3, 50, cs2_id);
+
+        let run = |tracker: &mut AllocationTracker, line| {
+            Command::parse(line)
+                .unwrap()
+                .run(tracker, |tracker| tracker.compact())
+        };
+        assert_eq!(run(&mut tracker, "current"), "150\nOK\n");
+        assert_eq!(run(&mut tracker, "peak"), "400\nOK\n");
+        assert_eq!(
+            run(&mut tracker, "top 5"),
+            "100 a:1 (af)\n  sizes now: 1 x 64-127\n  sizes over the run: 1 x 64-127\n\
+             50 a:1 (af);b:2 (bf)\n  sizes now: 1 x 32-63\n  sizes over the run: 1 x 32-63, 1 x 256-511\n\
+             OK\n"
+        );
+        assert_eq!(
+            run(&mut tracker, "top-peak 1"),
+            "300 a:1 (af);b:2 (bf)\n  sizes at peak: 1 x 256-511\n  sizes over the run: 1 x 32-63, 1 x 256-511\nOK\n"
+        );
+        assert_eq!(run(&mut tracker, "reset-peak"), "OK\n");
+        assert_eq!(run(&mut tracker, "peak"), "150\nOK\n");
+        assert_eq!(
+            run(&mut tracker, "top-peak 5"),
+            "100 a:1 (af)\n  sizes at peak: 1 x 64-127\n  sizes over the run: 1 x 64-127\n\
+             50 a:1 (af);b:2 (bf)\n  sizes at peak: 1 x 32-63\n  sizes over the run: 1 x 32-63, 1 x 256-511\n\
+             OK\n"
+        );
+        tracker.free_allocation(3);
+        run(&mut tracker, "reset-peak");
+        let freed = run(&mut tracker, "compact");
+        assert!(
+            freed
+                .strip_suffix("\nOK\n")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+                > 0
+        );
+        // cs2 was dropped, and its histogram of allocations with it:
+        assert_eq!(
+            run(&mut tracker, "top 5"),
+            "100 a:1 (af)\n  sizes now: 1 x 64-127\n  sizes over the run: 1 x 64-127\nOK\n"
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use std::cmp::{max, min};
+use std::collections::BTreeMap;
 #[cfg(test)]
 use std::collections::HashMap;
+use std::mem::size_of;
 
 /// Open-ended range in memory, [A...B).
 #[derive(Clone, Debug, PartialEq)]
@@ -36,96 +38,171 @@ impl Range {
     }
 }
 
+/// The sizes removed and added by RangeMap::remap().
+pub type Remapped<V> = (Vec<(V, usize)>, Vec<(V, usize)>);
+
 /// Map from memory address range to some other object, typically a CallStack.
 ///
 /// The intended use case is tracking anonymous mmap(), where munmap() can
 /// deallocate chunks of an allocation, or even multiple allocations.
+///
+/// Ranges never overlap, so they're stored ordered by start address, and
+/// adding or removing a range is O(log n) plus the number of ranges touched.
 #[derive(Clone, Debug, PartialEq)]
 pub struct RangeMap<V: Clone> {
-    ranges: Vec<(Range, V)>,
+    // Map start address to (end address, value):
+    ranges: BTreeMap<usize, (usize, V)>,
+    // Sum of the sizes of all ranges:
+    size: usize,
 }
 
 impl<V: Clone> RangeMap<V> {
     pub fn new() -> Self {
-        RangeMap { ranges: vec![] }
+        RangeMap {
+            ranges: BTreeMap::new(),
+            size: 0,
+        }
+    }
+
+    fn insert(&mut self, range: Range, value: V) {
+        self.size += range.size();
+        self.ranges.insert(range.start, (range.end, value));
     }
 
-    pub fn add(&mut self, start: usize, length: usize, value: V) {
-        if length <= 0 {
-            return;
+    /// Add a new range. Like mmap() with MAP_FIXED, any existing ranges that
+    /// overlap are replaced; returns how many bytes were replaced.
+    pub fn add(&mut self, start: usize, length: usize, value: V) -> Vec<(V, usize)> {
+        if length == 0 {
+            return vec![];
         }
-        self.ranges.push((Range::new(start, length), value));
+        let removed = self.remove(start, length);
+        self.insert(Range::new(start, length), value);
+        removed
     }
 
     /// Return how many bytes were removed.
     pub fn remove(&mut self, start: usize, length: usize) -> Vec<(V, usize)> {
-        if length <= 0 {
+        self.remove_pieces(start, length)
+            .into_iter()
+            .map(|(range, value)| (value, range.size()))
+            .collect()
+    }
+
+    /// Remove the given range, returning the removed pieces ordered by
+    /// address.
+    fn remove_pieces(&mut self, start: usize, length: usize) -> Vec<(Range, V)> {
+        if length == 0 {
             return vec![];
         }
-        let mut new_ranges = vec![];
-        let mut removed = vec![];
         let remove = Range::new(start, length);
-        for (range, value) in self.ranges.iter() {
-            match range.intersection(&remove) {
-                // Total overlap, remove it all:
-                Some(i) if (i.start == range.start) && (i.end == range.end) => {
-                    removed.push((value.clone(), range.size()));
-                }
-                // Remove chunk from start:
-                Some(i) if (i.start == range.start) && (i.end < range.end) => {
-                    let new_range = Range {
-                        start: i.end,
-                        end: range.end,
-                    };
-                    removed.push((value.clone(), range.size() - new_range.size()));
-                    new_ranges.push((new_range, value.clone()));
-                }
-                // Remove chunk from end:
-                Some(i) if (i.start > range.start) && (i.end == range.end) => {
-                    let new_range = Range {
+        // The only range starting before the removed range that can overlap
+        // it is the one immediately before it; the rest start inside it:
+        let mut overlapping: Vec<usize> = self
+            .ranges
+            .range(..remove.start)
+            .next_back()
+            .filter(|(_, (end, _))| *end > remove.start)
+            .map(|(start, _)| *start)
+            .into_iter()
+            .collect();
+        overlapping.extend(
+            self.ranges
+                .range(remove.start..remove.end)
+                .map(|(start, _)| *start),
+        );
+
+        let mut removed = vec![];
+        for range_start in overlapping {
+            let (range_end, value) = self.ranges.remove(&range_start).unwrap();
+            let range = Range {
+                start: range_start,
+                end: range_end,
+            };
+            self.size -= range.size();
+            let i = range.intersection(&remove).unwrap();
+            // Keep whatever is left at the start and end:
+            if range.start < i.start {
+                self.insert(
+                    Range {
                         start: range.start,
                         end: i.start,
-                    };
-                    removed.push((value.clone(), range.size() - new_range.size()));
-                    new_ranges.push((new_range, value.clone()));
-                }
-                // Remove chunk from the middle:
-                Some(i) => {
-                    new_ranges.push((
-                        Range {
-                            start: range.start,
-                            end: i.start,
-                        },
-                        value.clone(),
-                    ));
-                    new_ranges.push((
-                        Range {
-                            start: i.end,
-                            end: range.end,
-                        },
-                        value.clone(),
-                    ));
-                    removed.push((value.clone(), length));
-                }
-                // No overlap, remove nothing:
-                None => {
-                    new_ranges.push((range.clone(), value.clone()));
-                }
+                    },
+                    value.clone(),
+                );
             }
+            if i.end < range.end {
+                self.insert(
+                    Range {
+                        start: i.end,
+                        end: range.end,
+                    },
+                    value.clone(),
+                );
+            }
+            removed.push((i, value));
         }
-        self.ranges = new_ranges;
         removed
     }
 
+    /// Move and/or resize ranges, like mremap(): whatever is in the old range
+    /// moves to the new address, keeping its value, and is truncated or
+    /// extended to the new length. An extension gets the value of the last
+    /// piece of the old range. Anything that was already at the new range is
+    /// replaced.
+    ///
+    /// Returns the bytes that were removed and the bytes that were added.
+    pub fn remap(
+        &mut self,
+        old_start: usize,
+        old_length: usize,
+        new_start: usize,
+        new_length: usize,
+    ) -> Remapped<V> {
+        let pieces = self.remove_pieces(old_start, old_length);
+        let mut removed: Vec<(V, usize)> = pieces
+            .iter()
+            .map(|(range, value)| (value.clone(), range.size()))
+            .collect();
+        removed.extend(self.remove(new_start, new_length));
+
+        let new_end = new_start + new_length;
+        let mut added = vec![];
+        let mut add = |map: &mut Self, start: usize, end: usize, value: &V| {
+            let end = min(end, new_end);
+            if start < end {
+                map.insert(Range { start, end }, value.clone());
+                added.push((value.clone(), end - start));
+            }
+        };
+        for (range, value) in pieces.iter() {
+            let start = range.start - old_start + new_start;
+            add(self, start, start + range.size(), value);
+        }
+        if let Some((_, value)) = pieces.last() {
+            add(self, new_start + old_length, new_end, value);
+        }
+        (removed, added)
+    }
+
     pub fn size(&self) -> usize {
-        self.ranges.iter().map(|(r, _)| r.size()).sum()
+        self.size
+    }
+
+    /// Approximate bytes of memory used to store the ranges.
+    pub fn memory_usage(&self) -> usize {
+        self.ranges.len() * size_of::<(usize, (usize, V))>()
+    }
+
+    /// Iterate over the values of all ranges, so they can be changed.
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.ranges.values_mut().map(|(_, value)| value)
     }
 
     #[cfg(test)]
     pub fn as_hashmap(&self) -> HashMap<usize, (usize, &V)> {
         self.ranges
             .iter()
-            .map(|(range, v)| (range.start, (range.size(), v)))
+            .map(|(start, (end, v))| (*start, (end - start, v)))
             .collect()
     }
 }
@@ -150,11 +227,43 @@ mod tests {
             }
         }
 
-        fn add(&mut self, start: usize, length: usize, value: V) {
+        fn add(&mut self, start: usize, length: usize, value: V) -> Vec<(V, usize)> {
             assert!(length > 0);
+            let removed = self.remove(start, length);
             for i in start..(start + length) {
                 self.items.insert(i, value.clone());
             }
+            removed
+        }
+
+        fn remap(
+            &mut self,
+            old_start: usize,
+            old_length: usize,
+            new_start: usize,
+            new_length: usize,
+        ) -> (Vec<(V, usize)>, Vec<(V, usize)>) {
+            let moved: Vec<(usize, V)> = (old_start..(old_start + old_length))
+                .filter_map(|i| self.items.remove(&i).map(|value| (i, value)))
+                .collect();
+            let mut removed: Vec<(V, usize)> = moved.iter().map(|(_, value)| (*value, 1)).collect();
+            removed.extend(self.remove(new_start, new_length));
+            let mut added = vec![];
+            let new_end = new_start + new_length;
+            for (i, value) in moved.iter() {
+                let i = i - old_start + new_start;
+                if i < new_end {
+                    self.items.insert(i, *value);
+                    added.push((*value, 1));
+                }
+            }
+            if let Some((_, value)) = moved.last() {
+                for i in (new_start + old_length)..new_end {
+                    self.items.insert(i, *value);
+                    added.push((*value, 1));
+                }
+            }
+            (removed, added)
         }
 
         fn remove(&mut self, start: usize, length: usize) -> Vec<(V, usize)> {
@@ -215,7 +324,75 @@ mod tests {
             .boxed()
     }
 
+    #[test]
+    fn many_ranges() {
+        // Lots of adjacent mappings, as with an arena allocator:
+        let mut rangemap: RangeMap<usize> = RangeMap::new();
+        for i in 0..100_000 {
+            rangemap.add(i * 4096, 4096, i);
+        }
+        assert_eq!(rangemap.size(), 100_000 * 4096);
+        // Remove the middle of one mapping, and a range spanning several:
+        assert_eq!(rangemap.remove(10 * 4096 + 100, 100), vec![(10, 100)]);
+        assert_eq!(
+            rangemap.remove(20 * 4096 + 4000, 2 * 4096),
+            vec![(20, 96), (21, 4096), (22, 4000)]
+        );
+        for i in 0..100_000 {
+            rangemap.remove(i * 4096, 4096);
+        }
+        assert_eq!(rangemap.size(), 0);
+        assert!(rangemap.as_hashmap().is_empty());
+    }
+
+    /// Ranges that may well overlap each other.
+    fn overlapping_ranges() -> impl Strategy<Value = Vec<(usize, usize)>> {
+        proptest::collection::vec((0..200usize, 1..30usize), 1..20)
+    }
+
+    fn remaps() -> impl Strategy<Value = Vec<(usize, usize, usize, usize)>> {
+        proptest::collection::vec((0..200usize, 1..30usize, 0..200usize, 1..40usize), 1..10)
+    }
+
+    /// Sum up sizes by value.
+    fn summed(removed: Vec<(usize, usize)>) -> HashMap<usize, usize> {
+        let mut result = HashMap::new();
+        for (k, v) in removed {
+            *result.entry(k).or_insert(0) += v;
+        }
+        result
+    }
+
     proptest! {
+        /// Adding ranges that overlap existing ones replaces them, and moving
+        /// and resizing ranges keeps their values, with the same result in the
+        /// real and stupid range maps.
+        #[test]
+        fn overlapping_adds_and_remaps(add_ranges in overlapping_ranges(), remaps in remaps()) {
+            let mut real_rangemap : RangeMap<usize> = RangeMap::new();
+            let mut stupid_rangemap: StupidRangeMap<usize> = StupidRangeMap::new();
+            for (i, (start, length)) in add_ranges.into_iter().enumerate() {
+                let removed1 = real_rangemap.add(start, length, i);
+                let removed2 = stupid_rangemap.add(start, length, i);
+                prop_assert_eq!(summed(removed1), summed(removed2));
+                prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
+            }
+            for (old_start, old_length, new_start, new_length) in remaps {
+                let (removed1, added1) = real_rangemap.remap(old_start, old_length, new_start, new_length);
+                let (removed2, added2) = stupid_rangemap.remap(old_start, old_length, new_start, new_length);
+                prop_assert_eq!(summed(removed1), summed(removed2));
+                prop_assert_eq!(summed(added1), summed(added2));
+                prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
+                // Adjacent ranges with the same value may or may not get
+                // merged, so compare address by address:
+                let mut real_stupid = StupidRangeMap::new();
+                for (start, (length, value)) in real_rangemap.as_hashmap() {
+                    real_stupid.add(start, length, *value);
+                }
+                prop_assert_eq!(real_stupid.items, stupid_rangemap.items.clone());
+            }
+        }
+
         /// We can add and remove ranges and get the same result in the real and
         /// stupid range maps.
         #[test]
@@ -0,0 +1,237 @@
+// Automatic snapshots of memory usage for long-running processes.
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const MIB: usize = 1024 * 1024;
+
+/// When to take automatic snapshots, and how many to keep around.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotPolicy {
+    /// Take a snapshot this often.
+    pub interval: Option<Duration>,
+    /// Take a snapshot when memory usage has grown by this percentage since
+    /// the last snapshot.
+    pub growth_percent: Option<usize>,
+    /// How many automatic snapshot directories to keep; older ones get
+    /// deleted.
+    pub keep: usize,
+}
+
+impl SnapshotPolicy {
+    /// Load the policy from environment variables:
+    ///
+    /// * FIL_SNAPSHOT_EVERY_MINUTES: take a snapshot every N minutes.
+    /// * FIL_SNAPSHOT_ON_GROWTH_PERCENT: take a snapshot when memory has grown
+    ///   by X% since the last one.
+    /// * FIL_SNAPSHOT_KEEP: how many snapshots to keep, 10 by default.
+    ///
+    /// Returns None if automatic snapshots aren't enabled.
+    pub fn from_env() -> Option<Self> {
+        let get = |name| std::env::var(name).ok();
+        match Self::parse(
+            get("FIL_SNAPSHOT_EVERY_MINUTES").as_deref(),
+            get("FIL_SNAPSHOT_ON_GROWTH_PERCENT").as_deref(),
+            get("FIL_SNAPSHOT_KEEP").as_deref(),
+        ) {
+            Ok(policy) => policy,
+            Err(message) => {
+                eprintln!(
+                    "=fil-profile= WARNING: {}, automatic snapshots are disabled.",
+                    message
+                );
+                None
+            }
+        }
+    }
+
+    fn parse(
+        every_minutes: Option<&str>,
+        growth_percent: Option<&str>,
+        keep: Option<&str>,
+    ) -> Result<Option<Self>, String> {
+        let interval = match every_minutes {
+            Some(minutes) => match minutes.parse::<f64>() {
+                Ok(minutes) if minutes > 0.0 => Some(Duration::from_secs_f64(minutes * 60.0)),
+                _ => return Err(format!("Bad FIL_SNAPSHOT_EVERY_MINUTES={}", minutes)),
+            },
+            None => None,
+        };
+        let growth_percent = match growth_percent {
+            Some(percent) => match percent.parse::<usize>() {
+                Ok(percent) if percent > 0 => Some(percent),
+                _ => return Err(format!("Bad FIL_SNAPSHOT_ON_GROWTH_PERCENT={}", percent)),
+            },
+            None => None,
+        };
+        let keep = match keep {
+            Some(keep) => match keep.parse::<usize>() {
+                Ok(keep) if keep > 0 => keep,
+                _ => return Err(format!("Bad FIL_SNAPSHOT_KEEP={}", keep)),
+            },
+            None => 10,
+        };
+        if interval.is_none() && growth_percent.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(SnapshotPolicy {
+            interval,
+            growth_percent,
+            keep,
+        }))
+    }
+}
+
+/// Keeps track of when the next growth-based snapshot is due, and of the
+/// rolling window of snapshot directories.
+///
+/// Checking whether a snapshot is due is cheap, so it can be done on every
+/// allocation; actually writing the snapshot should happen elsewhere.
+pub struct SnapshotSchedule {
+    policy: SnapshotPolicy,
+    // Once current allocated bytes reach this, a snapshot is due:
+    growth_threshold_bytes: usize,
+    // Automatic snapshot directories written so far, oldest first:
+    written: VecDeque<PathBuf>,
+}
+
+impl SnapshotSchedule {
+    pub fn new(policy: SnapshotPolicy) -> Self {
+        let mut result = SnapshotSchedule {
+            policy,
+            growth_threshold_bytes: 0,
+            written: VecDeque::new(),
+        };
+        result.set_growth_threshold(0);
+        result
+    }
+
+    pub fn policy(&self) -> &SnapshotPolicy {
+        &self.policy
+    }
+
+    fn set_growth_threshold(&mut self, current_allocated_bytes: usize) {
+        self.growth_threshold_bytes = match self.policy.growth_percent {
+            // Require at least 1MiB of growth, so tiny amounts of memory
+            // don't result in lots of snapshots. Huge percentages mean never:
+            Some(percent) => current_allocated_bytes.saturating_add(std::cmp::max(
+                (current_allocated_bytes / 100).saturating_mul(percent),
+                MIB,
+            )),
+            None => usize::MAX,
+        };
+    }
+
+    /// Return whether memory has grown enough since the last snapshot that a
+    /// new one is due.
+    #[inline]
+    pub fn grew_enough(&self, current_allocated_bytes: usize) -> bool {
+        current_allocated_bytes >= self.growth_threshold_bytes
+    }
+
+    /// Record that a new automatic snapshot was written, deleting the oldest
+    /// ones beyond the number we want to keep.
+    pub fn snapshot_written(&mut self, directory: PathBuf, current_allocated_bytes: usize) {
+        self.set_growth_threshold(current_allocated_bytes);
+        self.written.push_back(directory);
+        while self.written.len() > self.policy.keep {
+            let oldest = self.written.pop_front().unwrap();
+            if let Err(e) = std::fs::remove_dir_all(&oldest) {
+                eprintln!(
+                    "=fil-profile= Error removing old snapshot {}: {}",
+                    oldest.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Start over, e.g. when tracking restarts with a new output directory.
+    pub fn reset(&mut self) {
+        self.set_growth_threshold(0);
+        self.written.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SnapshotPolicy, SnapshotSchedule, MIB};
+    use std::time::Duration;
+
+    #[test]
+    fn policy_parsing() {
+        assert_eq!(SnapshotPolicy::parse(None, None, None), Ok(None));
+        assert_eq!(SnapshotPolicy::parse(None, None, Some("3")), Ok(None));
+        assert_eq!(
+            SnapshotPolicy::parse(Some("0.5"), None, None),
+            Ok(Some(SnapshotPolicy {
+                interval: Some(Duration::from_secs(30)),
+                growth_percent: None,
+                keep: 10
+            }))
+        );
+        assert_eq!(
+            SnapshotPolicy::parse(None, Some("20"), Some("3")),
+            Ok(Some(SnapshotPolicy {
+                interval: None,
+                growth_percent: Some(20),
+                keep: 3
+            }))
+        );
+        assert!(SnapshotPolicy::parse(Some("x"), None, None).is_err());
+        assert!(SnapshotPolicy::parse(Some("-1"), None, None).is_err());
+        assert!(SnapshotPolicy::parse(None, Some("0"), None).is_err());
+        assert!(SnapshotPolicy::parse(None, Some("10"), Some("0")).is_err());
+    }
+
+    #[test]
+    fn growth_threshold() {
+        let mut schedule = SnapshotSchedule::new(SnapshotPolicy {
+            interval: None,
+            growth_percent: Some(50),
+            keep: 10,
+        });
+        // At least 1MiB of growth is needed:
+        assert!(!schedule.grew_enough(MIB - 1));
+        assert!(schedule.grew_enough(MIB));
+        schedule.snapshot_written("/nonexistent".into(), 100 * MIB);
+        assert!(!schedule.grew_enough(149 * MIB));
+        assert!(schedule.grew_enough(150 * MIB));
+        schedule.reset();
+        assert!(schedule.grew_enough(MIB));
+
+        // Huge percentages don't overflow:
+        let mut schedule = SnapshotSchedule::new(SnapshotPolicy {
+            interval: None,
+            growth_percent: Some(usize::MAX / 2),
+            keep: 10,
+        });
+        schedule.snapshot_written("/nonexistent".into(), 100 * MIB);
+        assert!(!schedule.grew_enough(usize::MAX - 1));
+
+        // No growth policy, never due:
+        let schedule = SnapshotSchedule::new(SnapshotPolicy {
+            interval: Some(Duration::from_secs(1)),
+            growth_percent: None,
+            keep: 10,
+        });
+        assert!(!schedule.grew_enough(usize::MAX - 1));
+    }
+
+    #[test]
+    fn old_snapshots_are_deleted() {
+        let base = crate::util::TempDir::new("fil-snapshots-test");
+        let mut schedule = SnapshotSchedule::new(SnapshotPolicy {
+            interval: Some(Duration::from_secs(60)),
+            growth_percent: None,
+            keep: 2,
+        });
+        let directories: Vec<_> = (0..4).map(|i| base.path().join(i.to_string())).collect();
+        for directory in directories.iter() {
+            std::fs::create_dir_all(directory).unwrap();
+            schedule.snapshot_written(directory.clone(), 0);
+        }
+        let exists: Vec<bool> = directories.iter().map(|d| d.exists()).collect();
+        assert_eq!(exists, vec![false, false, true, true]);
+    }
+}
@@ -0,0 +1,316 @@
+// Source code lines for rendering callstacks. Reading files directly from
+// Rust, rather than via Python's linecache, avoids taking the GIL for every
+// frame of every callstack, which is slow for big profiles and risky when
+// e.g. out of memory. Python is only used, via a fallback SourceProvider, for
+// code that doesn't live in a plain file, like frozen modules or modules
+// imported from zip files.
+use crate::util::new_hashmap;
+use ahash::RandomState as ARandomState;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// How many files to keep cached. Long-running processes that dump
+/// repeatedly would otherwise keep every file they ever rendered.
+const MAX_FILES: usize = 200;
+
+/// Where the source code lines shown in callstacks come from.
+pub trait SourceProvider: Send + Sync {
+    /// Get a line of source code, with the trailing newline, like Python's
+    /// linecache.getline(). Returns an empty string if it's not available.
+    fn get_line(&self, filename: &str, line_number: u32) -> String;
+}
+
+/// The source code of a single file.
+enum Source {
+    Lines(Vec<String>),
+    /// No such file, or it couldn't be read or decoded.
+    Missing,
+    /// Only the fallback, i.e. Python, knows how to load it.
+    Fallback,
+}
+
+/// Cached files, evicting the least recently used beyond `max_files`.
+struct Files {
+    /// Each file's source, and when it was last used.
+    sources: HashMap<String, (Source, u64), ARandomState>,
+    /// Incremented on every use.
+    clock: u64,
+    max_files: usize,
+}
+
+impl Files {
+    fn get(&mut self, filename: &str) -> &Source {
+        self.clock += 1;
+        if !self.sources.contains_key(filename) {
+            if self.sources.len() >= self.max_files {
+                let oldest = self
+                    .sources
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(filename, _)| filename.clone());
+                if let Some(oldest) = oldest {
+                    self.sources.remove(&oldest);
+                }
+            }
+            self.sources
+                .insert(filename.to_string(), (load(filename), self.clock));
+        }
+        let (source, last_used) = self.sources.get_mut(filename).unwrap();
+        *last_used = self.clock;
+        source
+    }
+}
+
+/// Cache of source files, by filename.
+pub struct SourceCache {
+    files: Mutex<Files>,
+    /// For code that doesn't live in a plain file.
+    fallback: Option<Box<dyn SourceProvider>>,
+}
+
+impl SourceCache {
+    /// Read source files only; code that isn't in a plain file has no source.
+    pub fn new() -> Self {
+        Self::with_max_files(MAX_FILES)
+    }
+
+    fn with_max_files(max_files: usize) -> Self {
+        SourceCache {
+            files: Mutex::new(Files {
+                sources: new_hashmap(),
+                clock: 0,
+                max_files,
+            }),
+            fallback: None,
+        }
+    }
+
+    /// Get code that isn't in a plain file, e.g. frozen modules, from the
+    /// fallback.
+    pub fn with_fallback(fallback: Box<dyn SourceProvider>) -> Self {
+        SourceCache {
+            fallback: Some(fallback),
+            ..Self::new()
+        }
+    }
+}
+
+impl SourceProvider for SourceCache {
+    fn get_line(&self, filename: &str, line_number: u32) -> String {
+        {
+            let mut files = self.files.lock().unwrap();
+            match files.get(filename) {
+                Source::Lines(lines) => {
+                    return line_number
+                        .checked_sub(1)
+                        .and_then(|index| lines.get(index as usize))
+                        .map(|line| format!("{}\n", line))
+                        .unwrap_or_default()
+                }
+                Source::Missing => return String::new(),
+                Source::Fallback => (),
+            }
+        }
+        // The lock is released first, since the fallback may take the GIL:
+        match &self.fallback {
+            Some(fallback) => fallback.get_line(filename, line_number),
+            None => String::new(),
+        }
+    }
+}
+
+impl Default for SourceCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Load a file's source code.
+fn load(filename: &str) -> Source {
+    // E.g. "<frozen importlib._bootstrap>" or "<string>":
+    if filename.starts_with('<') && filename.ends_with('>') {
+        return Source::Fallback;
+    }
+    match std::fs::read(filename) {
+        Ok(data) => match decode(&data) {
+            Some(text) => Source::Lines(split_lines(&text)),
+            // An encoding we don't support:
+            None => Source::Fallback,
+        },
+        Err(_) if in_archive(Path::new(filename)) => Source::Fallback,
+        Err(_) => Source::Missing,
+    }
+}
+
+/// Whether the path is inside a zip file or similar, e.g.
+/// "/app/deps.zip/package/module.py".
+fn in_archive(path: &Path) -> bool {
+    path.ancestors().skip(1).any(|ancestor| ancestor.is_file())
+}
+
+/// Decode Python source code, following PEP 263: UTF-8 by default, unless
+/// there's an encoding declaration in the first two lines. Returns None for
+/// encodings other than UTF-8 and Latin-1.
+fn decode(data: &[u8]) -> Option<String> {
+    const BOM: &[u8] = b"\xef\xbb\xbf";
+    if let Some(data) = data.strip_prefix(BOM) {
+        return Some(String::from_utf8_lossy(data).into_owned());
+    }
+    let encoding = data
+        .split(|byte| *byte == b'\n')
+        .take(2)
+        .find_map(encoding_declaration);
+    match encoding.as_deref() {
+        None | Some("utf-8") | Some("utf8") | Some("utf-8-sig") => {
+            Some(String::from_utf8_lossy(data).into_owned())
+        }
+        Some("latin-1") | Some("latin1") | Some("iso-8859-1") | Some("iso8859-1") | Some("l1") => {
+            Some(data.iter().map(|byte| *byte as char).collect())
+        }
+        Some(_) => None,
+    }
+}
+
+/// Parse a "# -*- coding: <encoding> -*-" style declaration, returning the
+/// encoding normalized to lowercase with dashes.
+fn encoding_declaration(line: &[u8]) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?;
+    let comment = line.trim_start_matches(&[' ', '\t', '\x0c'][..]);
+    if !comment.starts_with('#') {
+        return None;
+    }
+    let start = comment.find("coding")? + "coding".len();
+    let rest = comment[start..].strip_prefix(&[':', '='][..])?;
+    let encoding: String = rest
+        .trim_start_matches(&[' ', '\t'][..])
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '-' || *c == '_' || *c == '.')
+        .collect();
+    if encoding.is_empty() {
+        None
+    } else {
+        Some(encoding.to_lowercase().replace('_', "-"))
+    }
+}
+
+/// Split into lines, handling all the newline conventions Python does.
+fn split_lines(text: &str) -> Vec<String> {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let text = text.strip_suffix('\n').unwrap_or(&text);
+    text.split('\n').map(|line| line.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encoding_declaration, SourceCache, SourceProvider};
+    use crate::util::TempDir;
+    use std::path::PathBuf;
+
+    fn write_temp_file(directory: &TempDir, name: &str, data: &[u8]) -> PathBuf {
+        let path = directory.path().join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn encoding_declarations() {
+        assert_eq!(
+            encoding_declaration(b"# -*- coding: Latin_1 -*-"),
+            Some("latin-1".to_string())
+        );
+        assert_eq!(
+            encoding_declaration(b"  # vim: set fileencoding=utf-8 :"),
+            Some("utf-8".to_string())
+        );
+        assert_eq!(encoding_declaration(b"x = 1  # coding: latin-1"), None);
+        assert_eq!(encoding_declaration(b"# no declaration"), None);
+        assert_eq!(
+            decode(b"\xef\xbb\xbfx = '\xc3\xa9'"),
+            Some("x = 'é'".to_string())
+        );
+        assert_eq!(
+            decode(b"#!/usr/bin/python\n# coding=latin-1\nx = '\xe9'"),
+            Some("#!/usr/bin/python\n# coding=latin-1\nx = 'é'".to_string())
+        );
+        // Only the first two lines count:
+        assert_eq!(
+            decode(b"\n\n# coding: latin-1\nx = '\xc3\xa9'"),
+            Some("\n\n# coding: latin-1\nx = 'é'".to_string())
+        );
+        assert_eq!(decode(b"# coding: shift_jis\n"), None);
+    }
+
+    #[test]
+    fn lines() {
+        let directory = TempDir::new("fil-source-test");
+        let path = write_temp_file(&directory, "lines.py", b"a = 1\r\nb = 2\rc = 3\n\nd = 4");
+        let filename = path.to_str().unwrap();
+        let cache = SourceCache::new();
+        assert_eq!(cache.get_line(filename, 1), "a = 1\n");
+        assert_eq!(cache.get_line(filename, 2), "b = 2\n");
+        assert_eq!(cache.get_line(filename, 3), "c = 3\n");
+        assert_eq!(cache.get_line(filename, 4), "\n");
+        assert_eq!(cache.get_line(filename, 5), "d = 4\n");
+        assert_eq!(cache.get_line(filename, 6), "");
+        assert_eq!(cache.get_line(filename, 0), "");
+        // Cached, so deleting the file doesn't matter:
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cache.get_line(filename, 1), "a = 1\n");
+    }
+
+    #[test]
+    fn least_recently_used_files_are_evicted() {
+        let directory = TempDir::new("fil-source-test");
+        let paths: Vec<PathBuf> = (0..3)
+            .map(|i| {
+                write_temp_file(
+                    &directory,
+                    &format!("evict{}.py", i),
+                    format!("x = {}\n", i).as_bytes(),
+                )
+            })
+            .collect();
+        let filenames: Vec<&str> = paths.iter().map(|path| path.to_str().unwrap()).collect();
+        let cache = SourceCache::with_max_files(2);
+        cache.get_line(filenames[0], 1);
+        cache.get_line(filenames[1], 1);
+        cache.get_line(filenames[0], 1);
+        // The second file was used least recently, so it's evicted:
+        cache.get_line(filenames[2], 1);
+        for path in paths.iter() {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(cache.get_line(filenames[0], 1), "x = 0\n");
+        assert_eq!(cache.get_line(filenames[2], 1), "x = 2\n");
+        assert_eq!(cache.get_line(filenames[1], 1), "");
+        assert_eq!(cache.files.lock().unwrap().sources.len(), 2);
+    }
+
+    #[test]
+    fn missing_and_python_only_files() {
+        let cache = SourceCache::new();
+        assert_eq!(cache.get_line("/no/such/file.py", 1), "");
+        assert_eq!(cache.get_line("<string>", 1), "");
+        // Files inside an archive are loaded by Python:
+        let directory = TempDir::new("fil-source-test");
+        let archive = write_temp_file(&directory, "archive.zip", b"not really a zip");
+        let filename = archive.join("module.py");
+        assert!(super::in_archive(&filename));
+        assert_eq!(cache.get_line(filename.to_str().unwrap(), 1), "");
+    }
+
+    #[test]
+    fn fallback() {
+        struct Fake;
+        impl SourceProvider for Fake {
+            fn get_line(&self, filename: &str, line_number: u32) -> String {
+                format!("{} {}\n", filename, line_number)
+            }
+        }
+        let cache = SourceCache::with_fallback(Box::new(Fake));
+        assert_eq!(cache.get_line("<string>", 3), "<string> 3\n");
+        // Only code that isn't in a plain file comes from the fallback:
+        assert_eq!(cache.get_line("/no/such/file.py", 1), "");
+    }
+}
@@ -0,0 +1,271 @@
+// A self-contained HTML page for exploring a profile as a call tree, top-down
+// or bottom-up. Unlike the SVG flamegraphs it doesn't have to drop small
+// callstacks to stay usable, so it works for big profiles too. The data is
+// embedded as JSON, so viewing it doesn't need a server or network access.
+//
+// The JSON is compact: the tree is a list of nodes, each of which refers to
+// its parent, with functions, filenames and source lines stored once each.
+use crate::memorytracking::FunctionId;
+use crate::util::{json_string, new_hashmap};
+use ahash::RandomState as ARandomState;
+use itertools::Itertools;
+use std::collections::HashMap;
+
+const TEMPLATE: &str = include_str!("viewer.html");
+
+/// What the viewer can show, in the same order as the values passed to
+/// CallTree::add().
+pub const METRICS: [&str; 3] = ["peak", "current", "allocations"];
+
+/// A frame of a callstack, after hiding frames and folding recursion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Frame {
+    pub function: FunctionId,
+    pub line_number: u32,
+    pub recursion_depth: Option<usize>,
+}
+
+/// A node in the tree, i.e. a callstack.
+struct Node {
+    /// None for outermost frames.
+    parent: Option<usize>,
+    /// None for allocations with no Python callstack.
+    frame: Option<Frame>,
+    /// The values of each metric for this exact callstack, not including its
+    /// callees.
+    values: [usize; 3],
+}
+
+/// Callstacks merged into a tree, with the value of each metric.
+pub struct CallTree {
+    nodes: Vec<Node>,
+    children: HashMap<(Option<usize>, Option<Frame>), usize, ARandomState>,
+}
+
+impl CallTree {
+    pub fn new() -> Self {
+        CallTree {
+            nodes: Vec::new(),
+            children: new_hashmap(),
+        }
+    }
+
+    fn get_or_insert_child(&mut self, parent: Option<usize>, frame: Option<Frame>) -> usize {
+        let nodes = &mut self.nodes;
+        *self.children.entry((parent, frame)).or_insert_with(|| {
+            nodes.push(Node {
+                parent,
+                frame,
+                values: [0; 3],
+            });
+            nodes.len() - 1
+        })
+    }
+
+    /// Add the values of a callstack, given its frames, outermost first.
+    /// Callstacks that end up with the same frames are combined.
+    pub fn add(&mut self, frames: Vec<Frame>, values: [usize; 3]) {
+        let node = if frames.is_empty() {
+            self.get_or_insert_child(None, None)
+        } else {
+            frames
+                .into_iter()
+                .fold(None, |parent, frame| {
+                    Some(self.get_or_insert_child(parent, Some(frame)))
+                })
+                .unwrap()
+        };
+        for (total, value) in self.nodes[node].values.iter_mut().zip(values.iter()) {
+            *total += value;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Render as JSON. `describe` gives a function's displayed filename and
+    /// name, and `source_line` the source code of a line of a function.
+    pub fn to_json(
+        &self,
+        describe: impl Fn(FunctionId) -> (String, String),
+        source_line: impl Fn(FunctionId, u32) -> String,
+    ) -> String {
+        let mut files = Interned::new();
+        let mut sources = Interned::new();
+        let mut functions: HashMap<FunctionId, usize, ARandomState> = new_hashmap();
+        let mut function_list = vec![];
+        let nodes: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let parent = node.parent.map_or(-1, |parent| parent as i64);
+                let frame = match node.frame {
+                    Some(frame) => frame,
+                    None => return format!("[{},-1,0,-1,0]", parent),
+                };
+                let function = *functions.entry(frame.function).or_insert_with(|| {
+                    let (filename, name) = describe(frame.function);
+                    function_list.push(format!("[{},{}]", files.get(filename), json_string(&name)));
+                    function_list.len() - 1
+                });
+                let source = sources.get(
+                    source_line(frame.function, frame.line_number)
+                        .trim_end()
+                        .to_string(),
+                );
+                format!(
+                    "[{},{},{},{},{}]",
+                    parent,
+                    function,
+                    frame.line_number,
+                    source,
+                    frame.recursion_depth.unwrap_or(0)
+                )
+            })
+            .collect();
+        let values = METRICS
+            .iter()
+            .enumerate()
+            .map(|(index, metric)| {
+                format!(
+                    "{}:[{}]",
+                    json_string(metric),
+                    self.nodes.iter().map(|node| node.values[index]).join(",")
+                )
+            })
+            .join(",\n");
+        format!(
+            "{{\"files\":[{}],\n\"functions\":[{}],\n\"sources\":[{}],\n\"nodes\":[{}],\n{}}}",
+            files.to_json(),
+            function_list.join(","),
+            sources.to_json(),
+            nodes.join(","),
+            values
+        )
+    }
+}
+
+impl Default for CallTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Strings stored once each, referred to by index.
+struct Interned {
+    strings: Vec<String>,
+    indexes: HashMap<String, usize, ARandomState>,
+}
+
+impl Interned {
+    fn new() -> Self {
+        Interned {
+            strings: vec![],
+            indexes: new_hashmap(),
+        }
+    }
+
+    fn get(&mut self, string: String) -> usize {
+        let strings = &mut self.strings;
+        *self.indexes.entry(string).or_insert_with_key(|string| {
+            strings.push(string.clone());
+            strings.len() - 1
+        })
+    }
+
+    fn to_json(&self) -> String {
+        self.strings
+            .iter()
+            .map(|string| json_string(string))
+            .join(",")
+    }
+}
+
+/// Render the viewer page with the given title, for data from
+/// CallTree::to_json().
+pub fn render_html(title: &str, json: &str) -> String {
+    let title = title
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    // "</script>" or "<!--" in a string would confuse the HTML parser inside
+    // the script element; "<" only appears inside JSON strings, where
+    // "\u003c" means the same thing:
+    let json = json.replace('<', "\\u003c");
+    TEMPLATE
+        .replace("{{TITLE}}", &title)
+        .replace("{{DATA}}", &json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_html, CallTree, Frame};
+    use crate::memorytracking::FunctionId;
+
+    fn frame(function: u32, line_number: u32) -> Frame {
+        Frame {
+            function: FunctionId::new(function),
+            line_number,
+            recursion_depth: None,
+        }
+    }
+
+    #[test]
+    fn tree() {
+        let mut tree = CallTree::new();
+        tree.add(vec![frame(0, 1), frame(1, 5)], [100, 10, 2]);
+        tree.add(vec![frame(0, 1)], [50, 0, 1]);
+        tree.add(vec![frame(0, 1), frame(1, 5)], [1, 2, 3]);
+        tree.add(
+            vec![
+                frame(0, 2),
+                Frame {
+                    recursion_depth: Some(3),
+                    ..frame(1, 5)
+                },
+            ],
+            [7, 0, 1],
+        );
+        tree.add(vec![], [8, 8, 0]);
+        assert_eq!(tree.len(), 5);
+        let json = tree.to_json(
+            |function| match function.as_u32() {
+                0 => ("main.py".to_string(), "main".to_string()),
+                _ => ("lib.py".to_string(), "load".to_string()),
+            },
+            |function, line| format!("# {} {}\n", function.as_u32(), line),
+        );
+        assert_eq!(
+            json,
+            "{\"files\":[\"main.py\",\"lib.py\"],\n\
+             \"functions\":[[0,\"main\"],[1,\"load\"]],\n\
+             \"sources\":[\"# 0 1\",\"# 1 5\",\"# 0 2\"],\n\
+             \"nodes\":[[-1,0,1,0,0],[0,1,5,1,0],[-1,0,2,2,0],[2,1,5,1,3],[-1,-1,0,-1,0]],\n\
+             \"peak\":[50,101,0,7,8],\n\
+             \"current\":[0,12,0,0,8],\n\
+             \"allocations\":[1,5,0,1,0]}"
+        );
+    }
+
+    #[test]
+    fn html() {
+        let html = render_html(
+            "Profile <1>",
+            "{\"sources\":[\"x = '</script>' # <!--<script>\"]}",
+        );
+        assert!(html.contains("<title>Profile &lt;1&gt;</title>"));
+        assert!(
+            html.contains("{\"sources\":[\"x = '\\u003c/script>' # \\u003c!--\\u003cscript>\"]}")
+        );
+        assert!(!html.contains("{{DATA}}"));
+        // Everything is embedded:
+        assert!(!html.contains("http://"));
+        assert!(!html.contains("https://"));
+        assert!(!html.contains(" src="));
+    }
+}
@@ -0,0 +1,14 @@
+"""Make sure Fil notices file-backed `mmap()`."""
+import mmap
+import sys
+
+
+def main():
+    with open(sys.argv[1], "wb+") as f:
+        f.truncate(1024 * 1024 * 40)
+        f.flush()
+        m = mmap.mmap(f.fileno(), 0)
+    return m
+
+
+main()
@@ -0,0 +1,16 @@
+"""Keep growing memory usage, so automatic snapshots get written."""
+import numpy as np
+
+
+def allocate(arrays):
+    arrays.append(np.ones((1024, 1024, 10), dtype=np.uint8))
+
+
+def main():
+    arrays = []
+    for _ in range(8):
+        allocate(arrays)
+    return arrays
+
+
+main()
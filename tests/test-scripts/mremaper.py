@@ -0,0 +1,19 @@
+"""Make sure Fil notices `mremap()`."""
+import mmap
+from ctypes import CDLL, c_int, c_long, c_size_t, c_void_p
+
+libc = CDLL(None)
+libc.mmap.restype = c_void_p
+libc.mmap.argtypes = [c_void_p, c_size_t, c_int, c_int, c_int, c_long]
+libc.mremap.restype = c_void_p
+libc.mremap.argtypes = [c_void_p, c_size_t, c_size_t, c_int]
+MREMAP_MAYMOVE = 1
+MB = 1024 * 1024
+
+
+def main():
+    address = libc.mmap(None, 10 * MB, mmap.PROT_READ | mmap.PROT_WRITE, mmap.MAP_PRIVATE | mmap.MAP_ANONYMOUS, -1, 0)
+    return libc.mremap(address, 10 * MB, 70 * MB, MREMAP_MAYMOVE)
+
+
+main()
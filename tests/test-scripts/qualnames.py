@@ -0,0 +1,22 @@
+import numpy
+
+
+class Base:
+    def allocate(self):
+        return numpy.ones((1024, 1024, 20), dtype=numpy.uint8)
+
+    @classmethod
+    def make(cls):
+        return numpy.ones((1024, 1024, 10), dtype=numpy.uint8)
+
+
+class Child(Base):
+    pass
+
+
+def main():
+    child = Child()
+    return child.allocate(), Child.make()
+
+
+result = main()
@@ -0,0 +1,20 @@
+"""Send ourselves the dump signal while memory is still allocated."""
+import os
+import signal
+
+import numpy as np
+
+
+def noop():
+    pass
+
+
+def main():
+    arr = np.ones((1024, 1024, 20), dtype=np.uint8)
+    os.kill(os.getpid(), signal.SIGUSR2)
+    # The dump happens at the next safe point, i.e. a Python function call:
+    noop()
+    return arr
+
+
+main()
@@ -0,0 +1,16 @@
+"""Allocate some memory, then wait until the given file exists."""
+import os
+import sys
+import time
+
+import numpy as np
+
+
+def main():
+    arr = np.ones((1024, 1024, 20), dtype=np.uint8)
+    while not os.path.exists(sys.argv[1]):
+        time.sleep(0.1)
+    return arr
+
+
+main()
@@ -1,9 +1,10 @@
 """End-to-end tests."""
 
-from subprocess import check_call, check_output, CalledProcessError, run, PIPE
+from subprocess import check_call, check_output, CalledProcessError, run, PIPE, Popen
 from tempfile import mkdtemp, NamedTemporaryFile
 from pathlib import Path
 import os
+import signal
 import time
 import sys
 from typing import Union
@@ -56,7 +57,7 @@ def test_threaded_allocation_tracking():
 
     import threading
 
-    threading = (threading.__file__, "run", ANY)
+    threading = (threading.__file__, "Thread.run", ANY)
     ones = (numpy.core.numeric.__file__, "ones", ANY)
     script = str(script)
     h = (script, "h", 7)
@@ -89,7 +90,7 @@ def test_thread_allocates_after_main_thread_is_done():
 
     import threading
 
-    threading = (threading.__file__, "run", ANY)
+    threading = (threading.__file__, "Thread.run", ANY)
     ones = (numpy.core.numeric.__file__, "ones", ANY)
     script = str(script)
     thread1_path1 = ((script, "thread1", 9), ones)
@@ -152,7 +153,26 @@ def test_anonymous_mmap():
     """
     script = TEST_SCRIPTS / "mmaper.py"
     output_dir = profile(script)
-    allocations = get_allocations(output_dir)
+    # mmap.mmap(-1, size) uses MAP_SHARED, so shared mmap() reports get
+    # written too:
+    allocations = get_allocations(
+        output_dir,
+        [
+            "peak-memory.svg",
+            "peak-memory-reversed.svg",
+            "index.html",
+            "peak-memory.prof",
+            "fil-overhead.txt",
+            "size-histograms.json",
+            "peak-packages.txt",
+            "peak-packages.prof",
+            "peak-packages.svg",
+            "viewer.html",
+            "peak-mmap-shared.svg",
+            "peak-mmap-shared-reversed.svg",
+            "peak-mmap-shared.prof",
+        ],
+    )
 
     script = str(script)
     path = ((script, "<module>", 8),)
@@ -167,6 +187,181 @@ def test_anonymous_mmap():
         ) == pytest.approx(63, 0.1)
 
 
+@pytest.mark.skipif(sys.platform != "linux", reason="mremap() is Linux-only")
+def test_mremap():
+    """
+    mremap() moves and resizes tracked mappings, keeping the callstack of the
+    original mmap().
+    """
+    script = TEST_SCRIPTS / "mremaper.py"
+    output_dir = profile(script)
+    allocations = get_allocations(output_dir)
+
+    script = str(script)
+    path = ((script, "<module>", 19), (script, "main", 15))
+    assert match(allocations, {path: big}, as_mb) == pytest.approx(70, 0.1)
+
+
+def test_file_backed_mmap(tmpdir):
+    """
+    File-backed mmap() is tracked separately from heap memory, and optionally
+    included in the peak.
+    """
+    script = TEST_SCRIPTS / "file-mmaper.py"
+    report_files = [
+        "peak-memory.svg",
+        "peak-memory-reversed.svg",
+        "index.html",
+        "peak-memory.prof",
+        "fil-overhead.txt",
+        "size-histograms.json",
+        "peak-packages.txt",
+        "peak-packages.prof",
+        "peak-packages.svg",
+        "viewer.html",
+        "peak-mmap-file-backed.svg",
+        "peak-mmap-file-backed-reversed.svg",
+        "peak-mmap-file-backed.prof",
+    ]
+    path = ((str(script), "main", 10),)
+
+    output_dir = profile(script, str(tmpdir / "data.bin"))
+    assert path not in get_allocations(output_dir, report_files)
+    mmaps = get_allocations(
+        output_dir, report_files, prof_file="peak-mmap-file-backed.prof"
+    )
+    assert match(mmaps, {path: big}, as_mb) == pytest.approx(40, 0.1)
+    index = glob(str(output_dir / "*" / "index.html"))[0]
+    assert "peak-mmap-file-backed.svg" in Path(index).read_text()
+
+    output_dir = profile(
+        script,
+        str(tmpdir / "data2.bin"),
+        env={**os.environ, "FIL_MMAP_IN_PEAK": "file-backed"},
+    )
+    allocations = get_allocations(output_dir, report_files)
+    assert match(allocations, {path: big}, as_mb) == pytest.approx(40, 0.1)
+
+
+def test_package_report():
+    """
+    Peak memory is summarized by package, in a table and a flamegraph.
+    """
+    script = TEST_SCRIPTS / "example.py"
+    output_dir = profile(script)
+    [report_dir] = output_dir.glob("*")
+    table = (report_dir / "peak-packages.txt").read_text()
+    # Arrays allocated by numpy's C code are attributed to the script, but
+    # numpy.ones() is implemented in Python so is attributed to numpy:
+    assert "first-party  example\n" in table
+    assert "third-party  numpy\n" in table
+    assert (report_dir / "peak-packages.svg").exists()
+    assert "peak-packages.svg" in (report_dir / "index.html").read_text()
+
+
+def test_viewer():
+    """
+    A self-contained HTML viewer is written, with the callstacks embedded.
+    """
+    script = TEST_SCRIPTS / "example.py"
+    output_dir = profile(script)
+    [report_dir] = output_dir.glob("*")
+    viewer = (report_dir / "viewer.html").read_text()
+    assert str(script) in viewer
+    assert '"return_some_data_that_isnt_freed"' in viewer
+    # Nothing is loaded over the network:
+    assert "https://" not in viewer
+    assert "viewer.html" in (report_dir / "index.html").read_text()
+
+
+def test_line_numbers_above_16_bits(tmpdir):
+    """
+    Allocations in source files longer than 65,535 lines get the right line
+    number.
+    """
+    script = tmpdir / "long.py"
+    lines = ["import numpy"] + [""] * 99_997
+    lines.append("def main():")
+    lines.append("    return numpy.ones((1024, 1024, 30), dtype=numpy.uint8)")
+    lines.append("data = main()")
+    script.write_text("\n".join(lines) + "\n", "utf-8")
+
+    output_dir = profile(str(script))
+    allocations = get_allocations(output_dir)
+    path = ((str(script), "<module>", 100_001), (str(script), "main", 100_000))
+    assert match(allocations, {path: big}, as_mb) == pytest.approx(30, 0.1)
+
+
+def test_large_allocation_alerts(tmpdir):
+    """
+    With FIL_LARGE_ALLOCATION_MIB set, allocations at least that big are
+    reported immediately with a traceback, at most once per interval for each
+    callstack.
+    """
+    script = tmpdir / "large.py"
+    script.write_text(
+        """\
+import numpy
+
+def allocate(mb):
+    return numpy.ones((1024, 1024, mb), dtype=numpy.uint8)
+
+small = allocate(10)
+for _ in range(3):
+    big = allocate(60)
+""",
+        "utf-8",
+    )
+    result = run(
+        ["fil-profile", "-o", str(tmpdir / "out"), "--no-browser", "run", str(script)],
+        env={**os.environ, "FIL_LARGE_ALLOCATION_MIB": "50"},
+        stderr=PIPE,
+        check=True,
+        encoding=sys.getdefaultencoding(),
+    )
+    alerts = [
+        line for line in result.stderr.splitlines() if "Large allocation" in line
+    ]
+    # Rate-limited, so only the first is reported:
+    assert len(alerts) == 1
+    assert "62914560 bytes (60.0 MiB)" in alerts[0]
+    assert f"=| {script}:4 (allocate, defined at line 3)" in result.stderr
+
+    log = tmpdir / "alerts.log"
+    check_call(
+        ["fil-profile", "-o", str(tmpdir / "out"), "--no-browser", "run", str(script)],
+        env={
+            **os.environ,
+            "FIL_LARGE_ALLOCATION_MIB": "50",
+            "FIL_LARGE_ALLOCATION_LOG": str(log),
+            "FIL_LARGE_ALLOCATION_INTERVAL_SECONDS": "0",
+        },
+    )
+    assert log.read_text("utf-8").count("Large allocation") == 3
+
+
+def test_qualified_names(tmpdir):
+    """
+    Methods are reported with their qualified name, and every function with the
+    line where it was defined.
+    """
+    script = TEST_SCRIPTS / "qualnames.py"
+    output_dir = profile(script)
+    allocations = get_allocations(output_dir)
+
+    ones = (numpy.core.numeric.__file__, "ones", ANY)
+    script = str(script)
+    main = ((script, "<module>", 22), (script, "main", 19))
+    allocate = main + ((script, "Base.allocate", 6), ones)
+    make = main + ((script, "Base.make", 10), ones)
+    assert match(allocations, {allocate: big}, as_mb) == pytest.approx(20, 0.1)
+    assert match(allocations, {make: big}, as_mb) == pytest.approx(10, 0.1)
+
+    prof = Path(glob(str(output_dir / "*" / "peak-memory.prof"))[0]).read_text()
+    assert f"{script}:6 (Base.allocate, defined at line 5)" in prof
+    assert f"{script}:19 (main, defined at line 17)" in prof
+
+
 def test_python_objects():
     """
     Python objects gets detected and tracked.
@@ -609,3 +804,186 @@ def test_tabs():
 
         # It's valid XML:
         ElementTree.fromstring(svg)
+
+
+def test_dump_on_signal():
+    """
+    If FIL_DUMP_SIGNAL is set, sending that signal writes out both peak and
+    current memory usage into a timestamped subdirectory, without stopping
+    tracking.
+    """
+    script = TEST_SCRIPTS / "signal-dump.py"
+    output_dir = profile(script, env={**os.environ, "FIL_DUMP_SIGNAL": "SIGUSR2"})
+
+    [snapshot_dir] = glob(str(output_dir / "*" / "*" / ""))
+    snapshot_dir = Path(snapshot_dir)
+    for prefix in ["peak-memory", "current-memory"]:
+        for suffix in [".prof", ".svg", "-reversed.svg"]:
+            assert (snapshot_dir / (prefix + suffix)).stat().st_size > 0
+
+    # The array allocated in main() was alive when the signal was sent:
+    with open(snapshot_dir / "current-memory.prof") as f:
+        in_main = sum(
+            int(line.rsplit(" ", 1)[1])
+            for line in f
+            if str(script) in line and "(main)" in line
+        )
+    assert in_main / (1024 * 1024) == pytest.approx(20, 0.1)
+
+    # Tracking continued, so the final report was still written:
+    assert glob(str(output_dir / "*" / "peak-memory.svg"))
+
+
+def test_dump_signal_must_be_for_applications():
+    """
+    FIL_DUMP_SIGNAL refuses signals that have meanings of their own, like
+    SIGTERM, and doesn't suggest sending them.
+    """
+    script = TEST_SCRIPTS / "printer.py"
+    result = run(
+        ["fil-profile", "-o", mkdtemp(), "--no-browser", "run", str(script)],
+        env={**os.environ, "FIL_DUMP_SIGNAL": str(int(signal.SIGTERM))},
+        stdout=PIPE,
+        stderr=PIPE,
+        check=True,
+        encoding=sys.getdefaultencoding(),
+    )
+    assert "Unsupported signal FIL_DUMP_SIGNAL=" in result.stderr
+    assert "kill -s {} ".format(int(signal.SIGTERM)) not in result.stderr
+
+
+def test_automatic_snapshots():
+    """
+    With FIL_SNAPSHOT_ON_GROWTH_PERCENT set, snapshots are written as memory
+    grows, and only the last FIL_SNAPSHOT_KEEP of them are kept.
+    """
+    script = TEST_SCRIPTS / "growing.py"
+    output_dir = profile(
+        script,
+        env={
+            **os.environ,
+            "FIL_SNAPSHOT_ON_GROWTH_PERCENT": "20",
+            "FIL_SNAPSHOT_KEEP": "2",
+        },
+    )
+
+    snapshot_dirs = sorted(glob(str(output_dir / "*" / "*" / "")))
+    assert len(snapshot_dirs) == 2
+    for snapshot_dir in snapshot_dirs:
+        for name in ["peak-memory.prof", "current-memory.prof"]:
+            assert (Path(snapshot_dir) / name).stat().st_size > 0
+
+    # The final report is still written:
+    assert glob(str(output_dir / "*" / "peak-memory.svg"))
+
+
+def test_query_socket(tmpdir):
+    """
+    With FIL_QUERY_SOCKET set, a running process can be asked about its memory
+    usage over a Unix socket.
+    """
+    import socket
+
+    script = TEST_SCRIPTS / "wait-for-file.py"
+    socket_path = str(tmpdir / "fil.sock")
+    done_path = tmpdir / "done"
+    output = Path(mkdtemp())
+    process = Popen(
+        ["fil-profile", "-o", str(output), "--no-browser", "run", script, done_path],
+        env={**os.environ, "FIL_QUERY_SOCKET": socket_path},
+    )
+
+    def query(command):
+        with socket.socket(socket.AF_UNIX) as s:
+            s.connect(socket_path)
+            s.sendall(command.encode("utf-8") + b"\n")
+            lines = []
+            with s.makefile() as f:
+                for line in f:
+                    line = line.rstrip("\n")
+                    if line == "OK":
+                        return lines
+                    assert not line.startswith("ERROR"), line
+                    lines.append(line)
+
+    try:
+        # Wait until the array has been allocated:
+        for _ in range(300):
+            try:
+                if int(query("current")[0]) > 20 * 1024 * 1024:
+                    break
+            except (OSError, ValueError):
+                pass
+            time.sleep(0.1)
+        else:
+            raise AssertionError("Array never allocated")
+
+        [biggest, live_sizes, all_sizes] = query("top 1")
+        size, callstack = biggest.split(" ", 1)
+        assert live_sizes.startswith("  sizes now: ")
+        assert all_sizes.startswith("  sizes over the run: ")
+        assert int(size) / (1024 * 1024) == pytest.approx(20, 0.1)
+        assert "(main)" in callstack
+        assert int(query("peak")[0]) >= int(size)
+
+        dump_path = tmpdir / "dump"
+        assert query("dump " + str(dump_path)) == []
+        assert (dump_path / "current-memory.svg").exists()
+        assert (dump_path / "peak-memory.svg").exists()
+    finally:
+        done_path.write("")
+        assert process.wait() == 0
+
+
+def test_prometheus_textfile(tmpdir):
+    """
+    With FIL_PROMETHEUS_TEXTFILE set, metrics are periodically written out in
+    the Prometheus text format.
+    """
+    script = TEST_SCRIPTS / "wait-for-file.py"
+    metrics_path = tmpdir / "fil.prom"
+    done_path = tmpdir / "done"
+    output = Path(mkdtemp())
+    process = Popen(
+        ["fil-profile", "-o", str(output), "--no-browser", "run", script, done_path],
+        env={
+            **os.environ,
+            "FIL_PROMETHEUS_TEXTFILE": str(metrics_path),
+            "FIL_PROMETHEUS_INTERVAL_SECONDS": "0.1",
+        },
+    )
+
+    def metrics():
+        result = {}
+        for line in metrics_path.read().splitlines():
+            if not line.startswith("#"):
+                name, value = line.rsplit(" ", 1)
+                result[name] = int(value)
+        return result
+
+    try:
+        # Wait until the array has been allocated:
+        for _ in range(300):
+            if (
+                metrics_path.exists()
+                and metrics()["fil_current_allocated_bytes"] > 20 * 1024 * 1024
+            ):
+                break
+            time.sleep(0.1)
+        else:
+            raise AssertionError("Array never allocated")
+
+        result = metrics()
+        assert result["fil_peak_allocated_bytes"] >= result["fil_current_allocated_bytes"]
+        assert "fil_missing_allocated_bytes_total" in result
+        assert "fil_failed_deallocations_total" in result
+        # The array is allocated inside numpy.ones():
+        biggest = max(
+            value
+            for (name, value) in result.items()
+            if name.startswith("fil_function_current_allocated_bytes{")
+        )
+        assert biggest / (1024 * 1024) == pytest.approx(20, 0.1)
+    finally:
+        done_path.write("")
+        assert process.wait() == 0